@@ -0,0 +1,94 @@
+//! Throughput of the framing and parsing of the recorded depth messages.
+//!
+//! `legacy` is the string based framing that was used in `Socket::recv_loop` before.
+use std::collections::HashMap;
+
+use cfix::{framing::Framer, types::DELIMITER};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::hint::black_box;
+
+const CHUNK_SIZE: usize = 4096;
+
+fn recorded_stream() -> Vec<u8> {
+    let data = include_str!("data/depth.fix");
+    data.lines()
+        .map(|line| line.replace('|', DELIMITER))
+        .collect::<String>()
+        .into_bytes()
+}
+
+// the previous implementation for the comparison
+fn legacy_parse(message: &str) -> (HashMap<u32, usize>, Vec<(u32, String)>) {
+    let message = message.replace(DELIMITER, "|");
+    let mut idx_map = HashMap::new();
+    let fields = message
+        .split('|')
+        .filter(|field| !field.is_empty() && field.contains('='))
+        .enumerate()
+        .map(|(idx, field)| {
+            let parts = field.split('=').collect::<Vec<_>>();
+            let value = &parts[1..].join("=");
+            let field = parts[0].parse::<u32>().unwrap();
+            idx_map.entry(field).or_insert(idx);
+            (field, value.to_string())
+        })
+        .collect::<Vec<_>>();
+    (idx_map, fields)
+}
+
+fn legacy_framing(stream: &[u8]) -> usize {
+    let mut count = 0;
+    let mut msg_buffer = String::new();
+    for chunk in stream.chunks(CHUNK_SIZE) {
+        msg_buffer.push_str(String::from_utf8_lossy(chunk).as_ref());
+        while let Some(pos) = msg_buffer.find(&format!("{}10=", DELIMITER)) {
+            let pos = pos + 8;
+            let rest = if pos >= msg_buffer.len() {
+                None
+            } else {
+                Some(msg_buffer.split_off(pos))
+            };
+            black_box(legacy_parse(&msg_buffer));
+            count += 1;
+            match rest {
+                Some(rest) => msg_buffer = rest,
+                None => {
+                    msg_buffer.clear();
+                    break;
+                }
+            }
+        }
+    }
+    count
+}
+
+fn framing(stream: &[u8]) -> usize {
+    let mut count = 0;
+    let mut framer = Framer::new();
+    for chunk in stream.chunks(CHUNK_SIZE) {
+        framer.extend(chunk);
+        while let Some(res) = framer.next_message() {
+            black_box(res.unwrap());
+            count += 1;
+        }
+    }
+    count
+}
+
+fn bench_framing(c: &mut Criterion) {
+    let stream = recorded_stream();
+    assert_eq!(legacy_framing(&stream), framing(&stream));
+
+    let mut group = c.benchmark_group("depth_stream");
+    group.throughput(Throughput::Bytes(stream.len() as u64));
+    group.bench_with_input(BenchmarkId::new("legacy", stream.len()), &stream, |b, s| {
+        b.iter(|| legacy_framing(s))
+    });
+    group.bench_with_input(BenchmarkId::new("framer", stream.len()), &stream, |b, s| {
+        b.iter(|| framing(s))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_framing);
+criterion_main!(benches);
@@ -1,5 +1,5 @@
 use cfix::{
-    types::{ConnectionHandler, ExecutionReport, Side, TradeDataHandler},
+    types::{ConnectionHandler, ExecutionReport, TradeDataHandler},
     TradeClient,
 };
 use std::{env, error::Error, sync::Arc};
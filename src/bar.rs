@@ -0,0 +1,379 @@
+//! OHLC bars aggregated from the spot prices.
+
+use std::sync::Mutex;
+
+use chrono::{DateTime, NaiveDateTime, TimeDelta};
+use serde::{Deserialize, Serialize};
+
+use crate::types::SpotPrice;
+
+/// Length of the bars in seconds.
+///
+/// The bars start at the multiples of the length from the Unix epoch, so the bars of a day start
+/// at 00:00 UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Timeframe(u32);
+
+impl Timeframe {
+    pub const S1: Self = Self(1);
+    pub const S5: Self = Self(5);
+    pub const M1: Self = Self(60);
+    pub const M5: Self = Self(5 * 60);
+    pub const M15: Self = Self(15 * 60);
+    pub const M30: Self = Self(30 * 60);
+    pub const H1: Self = Self(60 * 60);
+    pub const H4: Self = Self(4 * 60 * 60);
+    pub const D1: Self = Self(24 * 60 * 60);
+
+    /// Returns `None` if `seconds` is 0.
+    pub fn from_seconds(seconds: u32) -> Option<Self> {
+        (seconds > 0).then_some(Self(seconds))
+    }
+
+    pub fn seconds(&self) -> u32 {
+        self.0
+    }
+
+    pub fn duration(&self) -> TimeDelta {
+        TimeDelta::seconds(self.0 as i64)
+    }
+
+    /// Start of the bar containing the time.
+    pub fn start_of(&self, time: NaiveDateTime) -> NaiveDateTime {
+        let timestamp = time.and_utc().timestamp();
+        let start = timestamp - timestamp.rem_euclid(self.0 as i64);
+        DateTime::from_timestamp(start, 0)
+            .map(|v| v.naive_utc())
+            .unwrap_or(time)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Ohlc {
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+}
+
+impl Ohlc {
+    fn new(price: f64) -> Self {
+        Self {
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+        }
+    }
+
+    fn update(&mut self, price: f64) {
+        self.high = self.high.max(price);
+        self.low = self.low.min(price);
+        self.close = price;
+    }
+
+    /// Updates the side with the price, if the price is known.
+    fn update_side(ohlc: &mut Option<Self>, price: Option<f64>) {
+        match (ohlc.as_mut(), price) {
+            (Some(ohlc), Some(price)) => ohlc.update(price),
+            (None, Some(price)) => *ohlc = Some(Self::new(price)),
+            (_, None) => {}
+        }
+    }
+}
+
+/// The price of a side, 0 until the side has arrived.
+fn side_price(price: f64) -> Option<f64> {
+    (price != 0.0).then_some(price)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Bar {
+    pub symbol_id: u32,
+    pub timeframe: Timeframe,
+    /// Open time of the bar. (UTC)
+    pub start: NaiveDateTime,
+    /// `None` if no price of the side is known in the bar.
+    pub bid: Option<Ohlc>,
+    pub ask: Option<Ohlc>,
+    /// `None` until both the sides are known.
+    pub mid: Option<Ohlc>,
+    /// Number of the prices in the bar. 0 for the bar filled in a gap.
+    pub tick_count: u64,
+}
+
+impl Bar {
+    fn new(symbol_id: u32, timeframe: Timeframe, start: NaiveDateTime, price: &SpotPrice) -> Self {
+        let mut bar = Self {
+            symbol_id,
+            timeframe,
+            start,
+            bid: None,
+            ask: None,
+            mid: None,
+            tick_count: 0,
+        };
+        bar.update(price);
+        bar
+    }
+
+    /// Bar without the prices, flat at the close of the previous bar.
+    fn flat(previous: &Bar) -> Self {
+        Self {
+            start: previous.end(),
+            bid: previous.bid.map(|v| Ohlc::new(v.close)),
+            ask: previous.ask.map(|v| Ohlc::new(v.close)),
+            mid: previous.mid.map(|v| Ohlc::new(v.close)),
+            tick_count: 0,
+            ..previous.clone()
+        }
+    }
+
+    fn update(&mut self, price: &SpotPrice) {
+        let (bid, ask) = (side_price(price.bid), side_price(price.ask));
+        Ohlc::update_side(&mut self.bid, bid);
+        Ohlc::update_side(&mut self.ask, ask);
+        let mid = bid.zip(ask).map(|(bid, ask)| (bid + ask) / 2.0);
+        Ohlc::update_side(&mut self.mid, mid);
+        self.tick_count += 1;
+    }
+
+    /// Close time of the bar, the start of the next bar.
+    pub fn end(&self) -> NaiveDateTime {
+        self.start + self.timeframe.duration()
+    }
+}
+
+/// What to do with the periods without the prices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GapPolicy {
+    /// No bar for the period.
+    #[default]
+    Skip,
+    /// Flat bar at the last close with no ticks.
+    Fill,
+}
+
+/// Aggregates the spot prices of a symbol into the bars of a timeframe.
+///
+/// A bar is completed when a price of the later bar arrives or `close_until` passes its end. The
+/// prices older than the bar being built are ignored.
+#[derive(Debug, Clone)]
+pub struct BarAggregator {
+    symbol_id: u32,
+    timeframe: Timeframe,
+    gap_policy: GapPolicy,
+    current: Option<Bar>,
+    last: Option<Bar>,
+}
+
+impl BarAggregator {
+    pub fn new(symbol_id: u32, timeframe: Timeframe, gap_policy: GapPolicy) -> Self {
+        Self {
+            symbol_id,
+            timeframe,
+            gap_policy,
+            current: None,
+            last: None,
+        }
+    }
+
+    pub fn symbol_id(&self) -> u32 {
+        self.symbol_id
+    }
+
+    pub fn timeframe(&self) -> Timeframe {
+        self.timeframe
+    }
+
+    /// Bar being built.
+    pub fn current(&self) -> Option<&Bar> {
+        self.current.as_ref()
+    }
+
+    /// Adds the price at the time, and returns the completed bars.
+    pub fn update(&mut self, time: NaiveDateTime, price: &SpotPrice) -> Vec<Bar> {
+        let start = self.timeframe.start_of(time);
+        let bars = self.close_until(start);
+        match self.current.as_mut() {
+            Some(bar) if bar.start == start => bar.update(price),
+            Some(_) => log::debug!("Ignore the price of {} before the current bar", time),
+            None if self.last.as_ref().is_some_and(|last| start < last.end()) => {
+                log::debug!("Ignore the price of {} before the last bar", time)
+            }
+            None => self.current = Some(Bar::new(self.symbol_id, self.timeframe, start, price)),
+        }
+        bars
+    }
+
+    /// Completes the bars that end by the time, and returns them.
+    pub fn close_until(&mut self, time: NaiveDateTime) -> Vec<Bar> {
+        let mut bars = Vec::new();
+        if self.current.as_ref().is_some_and(|bar| bar.end() <= time) {
+            self.last = self.current.take();
+            bars.extend(self.last.clone());
+        }
+        if self.gap_policy == GapPolicy::Fill && self.current.is_none() {
+            let duration = self.timeframe.duration();
+            while let Some(last) = self.last.as_ref().filter(|v| v.end() + duration <= time) {
+                let bar = Bar::flat(last);
+                bars.push(bar.clone());
+                self.last = Some(bar);
+            }
+        }
+        bars
+    }
+}
+
+/// Aggregators of the client.
+#[derive(Default)]
+pub(crate) struct BarAggregators {
+    aggregators: Mutex<Vec<BarAggregator>>,
+}
+
+impl BarAggregators {
+    /// Returns `false` if the timeframe of the symbol is already aggregated.
+    pub fn add(&self, symbol_id: u32, timeframe: Timeframe, gap_policy: GapPolicy) -> bool {
+        let mut aggregators = self.aggregators.lock().unwrap();
+        if aggregators
+            .iter()
+            .any(|v| v.symbol_id == symbol_id && v.timeframe == timeframe)
+        {
+            return false;
+        }
+        aggregators.push(BarAggregator::new(symbol_id, timeframe, gap_policy));
+        true
+    }
+
+    pub fn remove(&self, symbol_id: u32, timeframe: Timeframe) {
+        self.aggregators
+            .lock()
+            .unwrap()
+            .retain(|v| v.symbol_id != symbol_id || v.timeframe != timeframe);
+    }
+
+    pub fn update(&self, symbol_id: u32, time: NaiveDateTime, price: &SpotPrice) -> Vec<Bar> {
+        self.aggregators
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .filter(|v| v.symbol_id == symbol_id)
+            .flat_map(|v| v.update(time, price))
+            .collect()
+    }
+
+    pub fn close_until(&self, time: NaiveDateTime) -> Vec<Bar> {
+        self.aggregators
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .flat_map(|v| v.close_until(time))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BarAggregator, GapPolicy, Ohlc, Timeframe};
+    use crate::types::SpotPrice;
+    use chrono::{NaiveDate, NaiveDateTime};
+
+    fn time(h: u32, m: u32, s: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, 2)
+            .unwrap()
+            .and_hms_opt(h, m, s)
+            .unwrap()
+    }
+
+    fn price(bid: f64) -> SpotPrice {
+        SpotPrice {
+            bid,
+            ask: bid + 2.0,
+        }
+    }
+
+    #[test]
+    fn test_start_of() {
+        assert_eq!(Timeframe::M5.start_of(time(3, 7, 59)), time(3, 5, 0));
+        assert_eq!(Timeframe::H1.start_of(time(3, 0, 0)), time(3, 0, 0));
+        assert_eq!(Timeframe::D1.start_of(time(3, 7, 59)), time(0, 0, 0));
+        assert_eq!(Timeframe::from_seconds(0), None);
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let mut aggregator = BarAggregator::new(1, Timeframe::M1, GapPolicy::Skip);
+        for (t, bid) in [
+            (time(3, 0, 1), 3.0),
+            (time(3, 0, 20), 5.0),
+            (time(3, 0, 59), 1.0),
+        ] {
+            assert!(aggregator.update(t, &price(bid)).is_empty());
+        }
+        // the price before the current bar
+        assert!(aggregator.update(time(2, 59, 59), &price(9.0)).is_empty());
+
+        let bars = aggregator.update(time(3, 1, 0), &price(2.0));
+        assert_eq!(bars.len(), 1);
+        assert_eq!(bars[0].start, time(3, 0, 0));
+        assert_eq!(bars[0].end(), time(3, 1, 0));
+        assert_eq!(bars[0].tick_count, 3);
+        assert_eq!(
+            bars[0].bid,
+            Some(Ohlc {
+                open: 3.0,
+                high: 5.0,
+                low: 1.0,
+                close: 1.0
+            })
+        );
+        assert_eq!(bars[0].mid.unwrap().high, 6.0);
+        assert_eq!(aggregator.current().unwrap().start, time(3, 1, 0));
+
+        // no bar for the gap
+        let bars = aggregator.update(time(3, 5, 30), &price(2.0));
+        assert_eq!(bars.len(), 1);
+        assert_eq!(bars[0].start, time(3, 1, 0));
+    }
+
+    #[test]
+    fn test_fill_gaps() {
+        let mut aggregator = BarAggregator::new(1, Timeframe::M1, GapPolicy::Fill);
+        assert!(aggregator.close_until(time(3, 5, 0)).is_empty());
+        aggregator.update(time(3, 0, 30), &price(3.0));
+        aggregator.update(time(3, 0, 40), &price(4.0));
+
+        let bars = aggregator.close_until(time(3, 2, 30));
+        assert_eq!(bars.len(), 2);
+        assert_eq!(bars[0].tick_count, 2);
+        assert_eq!(bars[1].start, time(3, 1, 0));
+        assert_eq!(bars[1].tick_count, 0);
+        assert_eq!(bars[1].bid, Some(Ohlc::new(4.0)));
+
+        let bars = aggregator.update(time(3, 4, 10), &price(5.0));
+        let starts = bars.iter().map(|bar| bar.start).collect::<Vec<_>>();
+        assert_eq!(starts, vec![time(3, 2, 0), time(3, 3, 0)]);
+        // the price in the filled bar
+        assert!(aggregator.update(time(3, 3, 10), &price(5.0)).is_empty());
+        assert_eq!(aggregator.current().unwrap().tick_count, 1);
+    }
+
+    #[test]
+    fn test_one_side() {
+        let mut aggregator = BarAggregator::new(1, Timeframe::M1, GapPolicy::Skip);
+        let bid_only = SpotPrice { bid: 3.0, ask: 0.0 };
+        aggregator.update(time(3, 0, 1), &bid_only);
+        let bar = aggregator.current().unwrap();
+        assert_eq!(bar.bid, Some(Ohlc::new(3.0)));
+        assert_eq!(bar.ask, None);
+        assert_eq!(bar.mid, None);
+
+        // the mid starts when both the sides are known.
+        aggregator.update(time(3, 0, 2), &price(1.0));
+        let bar = aggregator.current().unwrap();
+        assert_eq!(bar.bid.unwrap().low, 1.0);
+        assert_eq!(bar.ask, Some(Ohlc::new(3.0)));
+        assert_eq!(bar.mid, Some(Ohlc::new(2.0)));
+        assert_eq!(bar.tick_count, 2);
+    }
+}
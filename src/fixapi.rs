@@ -1,99 +1,121 @@
 use std::{
-    collections::VecDeque,
+    future::Future,
+    pin::Pin,
     sync::{
         atomic::{AtomicBool, AtomicU32, Ordering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use async_std::{
-    channel::{bounded, Receiver},
-    io::{BufWriter, WriteExt},
-    net::TcpStream,
-    stream,
-    stream::StreamExt,
-    sync::RwLock,
-    task,
-};
+use async_channel::{bounded, Receiver};
+use async_lock::{Mutex, RwLock};
+use chrono::NaiveDateTime;
+use futures::StreamExt;
 
-use crate::types::{Config, Error, Field, InternalMDResult, SubID, DELIMITER};
+use crate::store::{MemoryStore, SessionStore};
+use crate::types::{Config, Error, Field, InternalMDResult, ReconnectPolicy, SubID, DELIMITER};
 use crate::{
-    messages::{HeartbeatReq, LogonReq, LogoutReq, RequestMessage, ResponseMessage},
+    messages::{
+        HeartbeatReq, LogonReq, LogoutReq, RequestMessage, ResendReq, ResponseMessage, TestReq,
+    },
+    replay::{ReplaySource, ReplaySpeed},
+    runtime,
+    session::{is_admin_message, resend_messages, InboundSequencer, Liveness, LivenessCheck},
     types::ConnectionHandler,
 };
 use crate::{
-    socket::Socket,
+    socket::{Socket, SocketWriter},
     types::{MarketCallback, TradeCallback},
 };
 
+/// Called with the session after every successful logon.
+pub type LogonCallback = Arc<dyn Fn(FixApi) + Send + Sync>;
+
+#[derive(Clone)]
 pub struct FixApi {
     config: Config,
-    stream: Option<Arc<TcpStream>>,
-    seq: Arc<AtomicU32>,
+    stream: Arc<RwLock<Option<SocketWriter>>>,
+    // next sender sequence number. held until the message is sent, so they go out in order.
+    seq: Arc<Mutex<u32>>,
     sub_id: SubID,
 
     is_connected: Arc<AtomicBool>,
+    // set when the session is closed by the user. no reconnection.
+    is_closing: Arc<AtomicBool>,
+    // increased for every new connection to stop the tasks of the previous one.
+    generation: Arc<AtomicU32>,
+    heartbeat: Arc<AtomicBool>,
+
+    reconnect_policy: Option<ReconnectPolicy>,
+    reconnect_attempt: Arc<AtomicU32>,
 
-    res_receiver: Option<Receiver<ResponseMessage>>,
+    res_receiver: Arc<RwLock<Option<Receiver<ResponseMessage>>>>,
     // pub container: Arc<RwLock<HashMap<String, Vec<ResponseMessage>>>>,
 
-    // ReqMessage Container
-    message_buffer: Arc<RwLock<VecDeque<(u32, String)>>>,
+    // sequence numbers and sent messages
+    store: Arc<dyn SessionStore + Send + Sync>,
 
     //callback
     connection_handler: Option<Arc<dyn ConnectionHandler + Send + Sync>>,
     market_callback: Option<MarketCallback>,
     trade_callback: Option<TradeCallback>,
+    logon_callback: Option<LogonCallback>,
 }
 
 impl FixApi {
-    pub fn new(
-        sub_id: SubID,
-        host: String,
-        login: String,
-        password: String,
-        sender_comp_id: String,
-        heartbeat_interval: Option<u32>,
-    ) -> Self {
+    pub fn new(sub_id: SubID, config: Config) -> Self {
         Self {
-            config: Config::new(
-                host,
-                login,
-                password,
-                sender_comp_id,
-                heartbeat_interval.unwrap_or(30),
-            ),
-            stream: None,
-            res_receiver: None,
+            config,
+            stream: Arc::new(RwLock::new(None)),
+            res_receiver: Arc::new(RwLock::new(None)),
             is_connected: Arc::new(AtomicBool::new(false)),
-            seq: Arc::new(AtomicU32::new(1)),
+            is_closing: Arc::new(AtomicBool::new(false)),
+            generation: Arc::new(AtomicU32::new(0)),
+            heartbeat: Arc::new(AtomicBool::new(false)),
+            reconnect_policy: None,
+            reconnect_attempt: Arc::new(AtomicU32::new(0)),
+            seq: Arc::new(Mutex::new(1)),
             // container: Arc::new(RwLock::new(HashMap::new())),
             sub_id,
 
-            message_buffer: Arc::new(RwLock::new(VecDeque::new())),
+            store: Arc::new(MemoryStore::new()),
             connection_handler: None,
             market_callback: None,
             trade_callback: None,
+            logon_callback: None,
         }
     }
 
+    pub fn set_reconnect_policy(&mut self, policy: Option<ReconnectPolicy>) {
+        self.reconnect_policy = policy;
+    }
+
+    pub fn set_session_store<S: SessionStore + Send + Sync + 'static>(&mut self, store: S) {
+        self.store = Arc::new(store);
+    }
+
+    pub fn register_logon_callback<F>(&mut self, callback: F)
+    where
+        F: Fn(FixApi) + Send + Sync + 'static,
+    {
+        self.logon_callback = Some(Arc::new(callback));
+    }
+
     pub fn register_market_callback<F>(&mut self, callback: F)
     where
-        F: Fn(InternalMDResult) -> () + Send + Sync + 'static,
+        F: Fn(InternalMDResult) + Send + Sync + 'static,
     {
-        self.market_callback = Some(Arc::new(move |mdresult: InternalMDResult| -> () {
+        self.market_callback = Some(Arc::new(move |mdresult: InternalMDResult| {
             callback(mdresult)
         }));
     }
 
     pub fn register_trade_callback<F>(&mut self, callback: F)
     where
-        F: Fn(ResponseMessage) -> () + Send + Sync + 'static,
+        F: Fn(ResponseMessage) + Send + Sync + 'static,
     {
-        self.trade_callback = Some(Arc::new(move |res: ResponseMessage| -> () {
-            callback(res)
-        }));
+        self.trade_callback = Some(Arc::new(move |res: ResponseMessage| callback(res)));
     }
 
     pub fn register_connection_handler_arc<T: ConnectionHandler + Send + Sync + 'static>(
@@ -110,74 +132,125 @@ impl FixApi {
         self.connection_handler = Some(Arc::new(handler));
     }
 
-    pub async fn disconnect(&mut self) -> Result<(), Error> {
-        if let Some(stream) = self.stream.clone() {
-            stream.shutdown(std::net::Shutdown::Both)?;
+    pub async fn disconnect(&self) -> Result<(), Error> {
+        self.is_closing.store(true, Ordering::Relaxed);
+        flush_store(&self.store).await;
+        if let Some(stream) = self.stream.write().await.take() {
+            stream.shutdown()?;
         }
-        self.stream = None;
-        self.res_receiver = None;
+        *self.res_receiver.write().await = None;
         self.is_connected.store(false, Ordering::Relaxed);
-        self.message_buffer.write().await.clear();
         Ok(())
     }
 
-    pub async fn connect(&mut self) -> Result<(), Error> {
-        self.message_buffer.write().await.clear();
+    pub async fn connect(&self) -> Result<(), Error> {
+        self.is_closing.store(false, Ordering::Relaxed);
+        self.reconnect_attempt.store(0, Ordering::Relaxed);
+        self.open().await
+    }
+
+    async fn open(&self) -> Result<(), Error> {
         let (sender, receiver) = bounded(1);
         let mut socket = Socket::connect(
             self.config.host.as_str(),
-            if self.sub_id == SubID::QUOTE {
-                5201
-            } else {
-                5202
-            },
+            self.config.port_for(self.sub_id),
+            &self.config.transport,
             sender,
         )
         .await?;
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed) + 1;
         self.is_connected.store(true, Ordering::Relaxed);
         log::debug!("stream connected");
 
         // notify connection
         if let Some(handler) = self.connection_handler.clone() {
-            task::spawn(async move {
+            runtime::spawn(async move {
                 handler.on_connect().await;
             });
         }
 
-        self.res_receiver = Some(receiver);
-        self.stream = Some(socket.stream.clone());
+        *self.res_receiver.write().await = Some(receiver);
+        *self.stream.write().await = Some(socket.writer.clone());
 
         let is_connected = self.is_connected.clone();
 
         let handler = self.connection_handler.clone();
-        let _ = task::spawn(async move {
+        let api = self.clone();
+        runtime::spawn(async move {
             socket.recv_loop(is_connected, handler).await.ok();
+            api.on_connection_lost(generation);
         });
 
         Ok(())
     }
 
+    fn on_connection_lost(&self, generation: u32) {
+        if self.reconnect_policy.is_none()
+            || self.is_closing.load(Ordering::Relaxed)
+            || self.generation.load(Ordering::Relaxed) != generation
+        {
+            return;
+        }
+        let api = self.clone();
+        runtime::spawn(async move {
+            api.reconnect().await;
+        });
+    }
+
+    /// Reconnects with the backoff of the reconnect policy until the session is logged on again.
+    ///
+    /// If the logon fails after the connection is established, the stream is closed and the
+    /// next attempt is scheduled when the receiving task of that connection ends.
+    fn reconnect(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(async move {
+            let Some(policy) = self.reconnect_policy.clone() else {
+                return;
+            };
+            loop {
+                let attempt = self.reconnect_attempt.fetch_add(1, Ordering::Relaxed);
+                if policy.max_attempts.is_some_and(|max| attempt >= max) {
+                    log::error!("Failed to reconnect after {} attempts", attempt);
+                    return;
+                }
+                let delay = policy.delay_for(attempt);
+                log::info!("Reconnecting in {:?} (attempt {})", delay, attempt + 1);
+                runtime::sleep(delay).await;
+
+                if self.is_closing.load(Ordering::Relaxed) {
+                    return;
+                }
+                if let Err(err) = self.open().await {
+                    log::warn!("Failed to reconnect - {:?}", err);
+                    continue;
+                }
+                let heartbeat = self.heartbeat.load(Ordering::Relaxed);
+                match self.logon(heartbeat).await {
+                    Ok(_) => {
+                        log::info!("Reconnected and logged on");
+                    }
+                    Err(err) => {
+                        log::warn!("Failed to logon after reconnection - {:?}", err);
+                        if let Some(stream) = self.stream.read().await.clone() {
+                            stream.shutdown().ok();
+                        }
+                    }
+                }
+                return;
+            }
+        })
+    }
+
     pub async fn send_message<R: RequestMessage>(&self, req: R) -> Result<(), Error> {
-        let no_seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let msg_type = req.get_message_type().to_string();
+        let mut seq = self.seq.lock().await;
+        let no_seq = *seq;
+        *seq += 1;
         let req = req.build(self.sub_id, no_seq, DELIMITER, &self.config);
-        if let Some(stream) = self.stream.clone() {
-            // FIXME
-            self.message_buffer
-                .write()
-                .await
-                .push_back((no_seq, req.clone()));
-            self.message_buffer
-                .write()
-                .await
-                .push_back((no_seq, req.clone()));
-            if self.message_buffer.read().await.len() > 10 {
-                self.message_buffer.write().await.pop_front();
-            }
+        if let Some(stream) = self.stream.read().await.clone() {
+            save_sent_message(&self.store, no_seq, &msg_type, &req).await?;
 
             log::debug!("Send request : {}", req);
-            let mut writer = BufWriter::new(stream.as_ref());
-            writer.write_all(req.as_bytes()).await?;
-            writer.flush().await?;
+            stream.send(&req).await?;
         }
         Ok(())
     }
@@ -187,31 +260,65 @@ impl FixApi {
     }
 
     pub async fn logon(&self, heartbeat: bool) -> Result<(), Error> {
-        // res3et the seq
-        self.seq.store(1, Ordering::Relaxed);
-        self.send_message(LogonReq::new(Some(true))).await?;
+        self.heartbeat.store(heartbeat, Ordering::Relaxed);
+        let generation = self.generation.load(Ordering::Relaxed);
+        // the sequence numbers are kept over the sessions only with a persistent store.
+        let reset_seq = !self.store.is_persistent();
+        if reset_seq {
+            self.store.reset().await?;
+        }
+        *self.seq.lock().await = self.store.next_sender_seq_num().await?;
+        self.send_message(LogonReq::new(reset_seq.then_some(true)))
+            .await?;
 
         // wait to receive the response
-        if let Some(recv) = &self.res_receiver {
+        let recv = self.res_receiver.read().await.clone();
+        if let Some(recv) = recv {
             while let Ok(response) = recv.recv().await {
                 // logon response
                 let msg_type = response.get_message_type();
                 match msg_type {
                     "A" => {
-                        //
+                        let mut sequencer =
+                            InboundSequencer::new(self.store.next_target_seq_num().await?);
+                        let logon_resend = match sequencer.on_logon(&response, Instant::now()) {
+                            Ok(resend) => resend,
+                            Err(err) => {
+                                // the store is ahead of the server. reset it to logon again.
+                                log::error!("Logout - {}", err);
+                                self.send_message(LogoutReq::new(Some(err.to_string())))
+                                    .await
+                                    .ok();
+                                if let Some(stream) = self.stream.read().await.clone() {
+                                    stream.shutdown().ok();
+                                }
+                                return Err(err);
+                            }
+                        };
+                        save_target_seq(&self.store, sequencer.expected()).await;
+                        self.reconnect_attempt.store(0, Ordering::Relaxed);
                         if let Some(handler) = self.connection_handler.clone() {
-                            task::spawn(async move {
+                            runtime::spawn(async move {
                                 handler.on_logon().await;
                             });
                         }
+                        if let Some(callback) = self.logon_callback.clone() {
+                            callback(self.clone());
+                        }
 
-                        let stream = self.stream.clone().unwrap();
-                        let stream_clone = self.stream.clone().unwrap();
+                        let stream = self
+                            .stream
+                            .read()
+                            .await
+                            .clone()
+                            .ok_or(Error::NotConnected)?;
+                        let stream_clone = stream.clone();
                         let sub_id = self.sub_id;
                         let config = self.config.clone();
                         let seq = self.seq.clone();
-                        let msg_buffer = self.message_buffer.clone();
+                        let store = self.store.clone();
                         let is_connected = self.is_connected.clone();
+                        let current_generation = self.generation.clone();
                         let handler = self.connection_handler.clone();
 
                         let send_request = move |req: Box<dyn RequestMessage>| {
@@ -219,42 +326,47 @@ impl FixApi {
                             let sub_id = sub_id;
                             let config = config.clone();
                             let seq = seq.clone();
-                            let msg_buffer = msg_buffer.clone();
+                            let store = store.clone();
                             let is_connected = is_connected.clone();
+                            let current_generation = current_generation.clone();
                             let handler = handler.clone();
                             async move {
                                 let msg_type = req.get_message_type();
-                                let no_seq = seq.fetch_add(1, Ordering::Relaxed);
+                                let mut seq = seq.lock().await;
+                                let no_seq = *seq;
+                                *seq += 1;
                                 let req = req.build(sub_id, no_seq, DELIMITER, &config);
                                 let handler = handler.clone();
 
-                                // FIXME later
-                                msg_buffer.write().await.push_back((no_seq, req.clone()));
-                                if msg_buffer.read().await.len() > 10 {
-                                    msg_buffer.write().await.pop_front();
+                                if let Err(err) =
+                                    save_sent_message(&store, no_seq, msg_type, &req).await
+                                {
+                                    log::error!("Failed to save the message - {:?}", err);
                                 }
 
-                                let mut writer = BufWriter::new(stream.as_ref());
                                 log::debug!(
                                     "[Session:MsgType({msg_type})] Sending request: {}",
                                     req
                                 );
-                                let _ = writer.write_all(req.as_bytes()).await;
 
-                                match writer.flush().await {
+                                match stream.send(&req).await {
                                     Ok(_) => {}
                                     Err(err) => {
                                         log::error!("Failed to send the request - {:?}", err);
-                                        is_connected.store(false, Ordering::Relaxed);
-                                        if let Err(err) = stream.shutdown(std::net::Shutdown::Both)
+                                        if current_generation.load(Ordering::Relaxed) != generation
                                         {
+                                            // the stream was already replaced
+                                            return;
+                                        }
+                                        is_connected.store(false, Ordering::Relaxed);
+                                        if let Err(err) = stream.shutdown() {
                                             log::error!(
                                                 "Failed to shutdown the stream - {:?}",
                                                 err
                                             );
                                         }
                                         if let Some(handler) = handler {
-                                            task::spawn(async move {
+                                            runtime::spawn(async move {
                                                 handler.on_disconnect().await;
                                             });
                                         }
@@ -264,18 +376,68 @@ impl FixApi {
                         };
                         let send_request_clone = send_request.clone();
 
+                        if let Some((begin, end)) = logon_resend {
+                            send_request_clone(Box::new(ResendReq::new(begin, end))).await;
+                        }
+
+                        // send TestRequest when the server is silent and close the dead connection
+                        let liveness = Arc::new(std::sync::Mutex::new(Liveness::new(
+                            self.config.heart_beat,
+                            Instant::now(),
+                        )));
+                        {
+                            let liveness = liveness.clone();
+                            let send_request = send_request.clone();
+                            let writer = stream_clone.clone();
+                            let is_connected = self.is_connected.clone();
+                            let current_generation = self.generation.clone();
+                            let store = self.store.clone();
+                            runtime::spawn(async move {
+                                let mut ticks = runtime::interval(Duration::from_secs(1));
+                                while ticks.next().await.is_some() {
+                                    if !is_connected.load(Ordering::Relaxed)
+                                        || current_generation.load(Ordering::Relaxed) != generation
+                                    {
+                                        break;
+                                    }
+                                    flush_store(&store).await;
+                                    let check = liveness.lock().unwrap().check(Instant::now());
+                                    match check {
+                                        LivenessCheck::Alive => {}
+                                        LivenessCheck::SendTestRequest(test_req_id) => {
+                                            log::debug!(
+                                                "No message received, send TestRequest({})",
+                                                test_req_id
+                                            );
+                                            send_request(Box::new(TestReq::new(test_req_id))).await;
+                                        }
+                                        LivenessCheck::Dead => {
+                                            log::warn!(
+                                                "No response for TestRequest, close the connection"
+                                            );
+                                            writer.shutdown().ok();
+                                            break;
+                                        }
+                                    }
+                                }
+                            });
+                        }
+
                         if heartbeat {
                             let hb_interval = self.config.heart_beat as u64;
 
                             let is_connected = self.is_connected.clone();
+                            let current_generation = self.generation.clone();
 
                             //send heartbeat per hb_interval
-                            task::spawn(async move {
+                            runtime::spawn(async move {
                                 let mut heartbeat_stream =
-                                    stream::interval(Duration::from_secs(hb_interval));
+                                    runtime::interval(Duration::from_secs(hb_interval));
 
-                                while let Some(_) = heartbeat_stream.next().await {
-                                    if !is_connected.load(Ordering::Relaxed) {
+                                while heartbeat_stream.next().await.is_some() {
+                                    if !is_connected.load(Ordering::Relaxed)
+                                        || current_generation.load(Ordering::Relaxed) != generation
+                                    {
                                         break;
                                     }
                                     let req = HeartbeatReq::new(None);
@@ -287,138 +449,120 @@ impl FixApi {
                         //
                         // handle the responses
 
-                        let recv = self.res_receiver.clone().unwrap();
+                        let recv = recv.clone();
                         let market_callback = self.market_callback.clone();
                         let trade_callback = self.trade_callback.clone();
 
                         let is_connected = self.is_connected.clone();
-                        // let seq = self.seq.clone();
-                        let msg_buffer = self.message_buffer.clone();
-                        task::spawn(async move {
+                        let current_generation = self.generation.clone();
+                        let next_seq = self.seq.clone();
+                        let store = self.store.clone();
+                        let sub_id = self.sub_id;
+                        let config = self.config.clone();
+                        runtime::spawn(async move {
                             while let Ok(res) = recv.recv().await {
-                                if !is_connected.load(Ordering::Relaxed) {
+                                if !is_connected.load(Ordering::Relaxed)
+                                    || current_generation.load(Ordering::Relaxed) != generation
+                                {
                                     break;
                                 }
-
-                                let msg_type = res.get_message_type();
-
-                                // notify? or send? via channel?
-                                match msg_type {
-                                    "0" => {
-                                        log::debug!(
-                                            "[Session:MsyType({msg_type})] Received Heartbeat"
-                                        );
+                                liveness.lock().unwrap().on_received(&res, Instant::now());
+                                let sequenced = match sequencer.on_message(res, Instant::now()) {
+                                    Ok(sequenced) => sequenced,
+                                    Err(err) => {
+                                        log::error!("Logout - {}", err);
+                                        send_request_clone(Box::new(LogoutReq::new(Some(
+                                            err.to_string(),
+                                        ))))
+                                        .await;
+                                        stream_clone.shutdown().ok();
+                                        break;
                                     }
-                                    "2" => {
-                                        log::debug!(
-                                            "[Session:MsyType({msg_type})] Received ResendRequest"
-                                        );
-                                        let begin = res
-                                            .get_field_value(Field::BeginSeqNo)
-                                            .map(|v| v.parse::<u32>().unwrap_or(0))
-                                            .unwrap();
-
-                                        let end = res
-                                            .get_field_value(Field::EndSeqNo)
-                                            .map(|v| v.parse::<u32>().unwrap_or(0))
-                                            .unwrap();
+                                };
+                                if let Some((begin, end)) = sequenced.resend {
+                                    send_request_clone(Box::new(ResendReq::new(begin, end))).await;
+                                }
+                                save_target_seq(&store, sequencer.expected()).await;
 
-                                        {
-                                            for msg in msg_buffer
-                                                .read()
-                                                .await
-                                                .iter()
-                                                .filter(|(no, _)| {
-                                                    if end == 0 {
-                                                        *no >= begin
-                                                    } else {
-                                                        *no >= begin && *no <= end
-                                                    }
-                                                })
-                                                .map(|(_, msg)| msg.clone())
+                                for res in sequenced.ready {
+                                    let msg_type = res.get_message_type();
+
+                                    // notify? or send? via channel?
+                                    match msg_type {
+                                        "0" => {
+                                            log::debug!(
+                                                "[Session:MsyType({msg_type})] Received Heartbeat"
+                                            );
+                                        }
+                                        "2" => {
+                                            log::debug!(
+                                                "[Session:MsyType({msg_type})] Received ResendRequest"
+                                            );
+                                            let begin = res
+                                                .get_field_value(Field::BeginSeqNo)
+                                                .and_then(|v| v.parse::<u32>().ok())
+                                                .unwrap_or(1);
+
+                                            let end = res
+                                                .get_field_value(Field::EndSeqNo)
+                                                .and_then(|v| v.parse::<u32>().ok())
+                                                .unwrap_or(0);
+
+                                            // EndSeqNo(0) means the last message sent
+                                            let last_seq = *next_seq.lock().await - 1;
+                                            let end = if end == 0 || end > last_seq {
+                                                last_seq
+                                            } else {
+                                                end
+                                            };
+                                            let stored = match store.get_messages(begin, end).await
                                             {
-                                                let mut writer =
-                                                    BufWriter::new(stream_clone.as_ref());
+                                                Ok(stored) => stored,
+                                                Err(err) => {
+                                                    log::error!("Failed to load the messages to resend - {:?}", err);
+                                                    Vec::new()
+                                                }
+                                            };
+                                            for msg in resend_messages(
+                                                stored, begin, end, sub_id, DELIMITER, &config,
+                                            ) {
                                                 log::debug!(
-                                                 "[Session:MsgType({msg_type})] Send ResendRequest: {}",
-                                                msg
+                                                    "[Session:MsgType({msg_type})] Resend: {}",
+                                                    msg
                                                 );
-                                                let _ = writer.write_all(msg.as_bytes()).await;
-                                                break;
+                                                if let Err(err) = stream_clone.send(&msg).await {
+                                                    log::error!(
+                                                        "Failed to resend the message - {:?}",
+                                                        err
+                                                    );
+                                                    break;
+                                                }
                                             }
                                         }
-                                    }
-                                    "5" => {
-                                        log::debug!(
-                                            "[Session:MsyType({msg_type})] Received Logged out"
-                                        );
-                                        // 5 : logout
-                                        //disconnect
-                                        stream_clone.shutdown(std::net::Shutdown::Both).ok();
-                                    }
-                                    "1" => {
-                                        log::debug!(
-                                            "[Session:MsyType({msg_type})] Received TestRequest"
-                                        );
-                                        // send back with test request id
-                                        if let Some(test_req_id) =
-                                            res.get_field_value(Field::TestReqID)
-                                        {
-                                            send_request_clone(Box::new(HeartbeatReq::new(Some(
-                                                test_req_id,
-                                            ))))
-                                            .await;
-                                            log::debug!("Sent the heartbeat from test_req_id");
-                                        }
-                                    }
-                                    "W" | "X" | "Y" => {
-                                        // For market data
-                                        let symbol_id = res
-                                            .get_field_value(Field::Symbol)
-                                            .unwrap_or("0".into())
-                                            .parse::<u32>()
-                                            .unwrap();
-                                        // notify to callback
-                                        if let Some(market_callback) = market_callback.clone() {
-                                            let mdresult = if msg_type == "Y" {
-                                                let md_req_id = res
-                                                    .get_field_value(Field::MDReqID)
-                                                    .map(|v| v.clone())
-                                                    .unwrap_or("".into());
-                                                let err_msg = res
-                                                    .get_field_value(Field::Text)
-                                                    .map(|v| v.clone())
-                                                    .unwrap_or("".into());
-                                                InternalMDResult::MDReject {
-                                                    symbol_id,
-                                                    md_req_id,
-                                                    err_msg,
-                                                }
-                                            } else {
-                                                let data = res.get_repeating_groups(
-                                                    Field::NoMDEntries,
-                                                    if msg_type == "W" {
-                                                        Field::MDEntryType
-                                                    } else {
-                                                        Field::MDUpdateAction
-                                                    },
-                                                    None,
-                                                );
-                                                InternalMDResult::MD {
-                                                    msg_type: msg_type.chars().next().unwrap(),
-                                                    symbol_id,
-                                                    data,
-                                                }
-                                            };
-
-                                            market_callback(mdresult);
+                                        "5" => {
+                                            log::debug!(
+                                                "[Session:MsyType({msg_type})] Received Logged out"
+                                            );
+                                            // 5 : logout
+                                            //disconnect
+                                            stream_clone.shutdown().ok();
                                         }
-                                    }
-                                    _ => {
-                                        log::debug!("{}", res.get_message());
-                                        if let Some(trade_callback) = trade_callback.clone() {
-                                            trade_callback(res);
+                                        "1" => {
+                                            log::debug!(
+                                                "[Session:MsyType({msg_type})] Received TestRequest"
+                                            );
+                                            // send back with test request id
+                                            if let Some(test_req_id) =
+                                                res.get_field_value(Field::TestReqID)
+                                            {
+                                                send_request_clone(Box::new(HeartbeatReq::new(
+                                                    Some(test_req_id),
+                                                )))
+                                                .await;
+                                                log::debug!("Sent the heartbeat from test_req_id");
+                                            }
                                         }
+                                        _ => route_message(res, &market_callback, &trade_callback),
                                     }
                                 }
                             }
@@ -438,6 +582,117 @@ impl FixApi {
 
     pub async fn logout(&self) -> Result<(), Error> {
         self.send_message(LogoutReq::default()).await?;
+        flush_store(&self.store).await;
         Ok(())
     }
+
+    /// Passes the recorded messages to the callbacks as if they were received.
+    ///
+    /// The logon callback is called for the Logon messages, and the other administrative
+    /// messages are ignored.
+    pub async fn replay(&self, source: &ReplaySource, speed: ReplaySpeed) {
+        source
+            .play(speed, |res| match res.get_message_type() {
+                "A" => {
+                    if let Some(callback) = self.logon_callback.clone() {
+                        callback(self.clone());
+                    }
+                }
+                msg_type if is_admin_message(msg_type) => {}
+                _ => route_message(res, &self.market_callback, &self.trade_callback),
+            })
+            .await;
+    }
+}
+
+async fn flush_store(store: &Arc<dyn SessionStore + Send + Sync>) {
+    if let Err(err) = store.flush().await {
+        log::error!("Failed to flush the session store - {:?}", err);
+    }
+}
+
+async fn save_target_seq(store: &Arc<dyn SessionStore + Send + Sync>, seq: u32) {
+    if let Err(err) = store.set_next_target_seq_num(seq).await {
+        log::error!("Failed to save the target sequence number - {:?}", err);
+    }
+}
+
+// the administrative messages are not stored. they are replaced by SequenceReset-GapFill on resend.
+async fn save_sent_message(
+    store: &Arc<dyn SessionStore + Send + Sync>,
+    no_seq: u32,
+    msg_type: &str,
+    msg: &str,
+) -> Result<(), Error> {
+    if is_admin_message(msg_type) {
+        store.advance_next_sender_seq_num(no_seq + 1).await
+    } else {
+        store.save_message(no_seq, msg).await
+    }
+}
+
+/// Passes the application message to the market or trade callback.
+pub(crate) fn route_message(
+    res: ResponseMessage,
+    market_callback: &Option<MarketCallback>,
+    trade_callback: &Option<TradeCallback>,
+) {
+    let msg_type = res.get_message_type();
+    match msg_type {
+        "W" | "X" | "Y" => {
+            // For market data
+            let symbol_id = res
+                .get_field_value(Field::Symbol)
+                .and_then(|v| v.parse::<u32>().ok())
+                .unwrap_or(0);
+            let seq = res
+                .get_field_str(Field::MsgSeqNum)
+                .and_then(|v| v.parse::<u32>().ok())
+                .unwrap_or(0);
+            // notify to callback
+            if let Some(market_callback) = market_callback.clone() {
+                let mdresult = if msg_type == "Y" {
+                    let md_req_id = res.get_field_value(Field::MDReqID).unwrap_or("".into());
+                    let err_msg = res.get_field_value(Field::Text).unwrap_or("".into());
+                    InternalMDResult::MDReject {
+                        seq,
+                        symbol_id,
+                        md_req_id,
+                        err_msg,
+                    }
+                } else {
+                    let data = res.get_repeating_groups(
+                        Field::NoMDEntries,
+                        if msg_type == "W" {
+                            Field::MDEntryType
+                        } else {
+                            Field::MDUpdateAction
+                        },
+                        None,
+                    );
+                    InternalMDResult::MD {
+                        seq,
+                        sending_time: sending_time_of(&res),
+                        msg_type: msg_type.chars().next().unwrap(),
+                        symbol_id,
+                        data,
+                    }
+                };
+
+                market_callback(mdresult);
+            }
+        }
+        _ => {
+            log::debug!("{}", res.get_message().replace(DELIMITER, "|"));
+            if let Some(trade_callback) = trade_callback.clone() {
+                trade_callback(res);
+            }
+        }
+    }
+}
+
+/// SendingTime(52) of the message.
+pub(crate) fn sending_time_of(res: &ResponseMessage) -> Option<NaiveDateTime> {
+    res.get_field_str(Field::SendingTime)
+        .and_then(|v| NaiveDateTime::parse_from_str(v, "%Y%m%d-%H:%M:%S%.f").ok())
 }
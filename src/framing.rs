@@ -0,0 +1,239 @@
+use crate::{
+    messages::ResponseMessage,
+    types::{Field, ParseError, DELIMITER},
+};
+
+const SOH: u8 = 0x01;
+const BEGIN_STRING: &[u8] = b"8=FIX";
+// "10=" + 3 digits + SOH
+const TRAILER_LEN: usize = 7;
+// "8=FIX.4.4" + SOH + "9=" + digits + SOH
+const MAX_HEADER_LEN: usize = 32;
+/// Largest BodyLength(9) accepted. A larger one is regarded as corrupt, not to buffer the bytes
+/// forever waiting for the end of the message.
+pub const MAX_BODY_LENGTH: usize = 4 * 1024 * 1024;
+
+/// Message that failed to be framed or parsed.
+#[derive(Debug)]
+pub struct InvalidFrame {
+    pub raw: String,
+    pub err: ParseError,
+}
+
+/// Splits the received bytes into the messages using the BeginString(8) and BodyLength(9).
+///
+/// The bytes are kept in one buffer and each message is copied out of it only once.
+#[derive(Debug, Default)]
+pub struct Framer {
+    buffer: Vec<u8>,
+    start: usize,
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+// start and end of the BodyLength field after the BeginString
+fn body_length_field(header: &[u8]) -> Option<(usize, usize)> {
+    let begin_end = find(header, &[SOH])? + 1;
+    Some((begin_end, begin_end + find(&header[begin_end..], &[SOH])?))
+}
+
+impl Framer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        // move the remaining bytes to the front before growing the buffer
+        if self.start > 0 && self.start * 2 >= self.buffer.len() {
+            self.buffer.drain(..self.start);
+            self.start = 0;
+        }
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Number of the bytes waiting for the rest of the message.
+    pub fn pending(&self) -> usize {
+        self.buffer.len() - self.start
+    }
+
+    /// Returns the next complete message, or `None` if more bytes are needed.
+    pub fn next_message(&mut self) -> Option<Result<ResponseMessage, InvalidFrame>> {
+        let frame = match self.next_frame()? {
+            Ok(frame) => frame,
+            Err(invalid) => return Some(Err(invalid)),
+        };
+        let message = match String::from_utf8(frame) {
+            Ok(message) => message,
+            Err(err) => {
+                let raw = String::from_utf8_lossy(err.as_bytes()).to_string();
+                return Some(Err(InvalidFrame {
+                    err: ParseError::InvalidValue(raw.clone()),
+                    raw,
+                }));
+            }
+        };
+        Some(
+            ResponseMessage::parse(message, DELIMITER)
+                .map_err(|(raw, err)| InvalidFrame { raw, err }),
+        )
+    }
+
+    /// Returns the bytes of the next complete message.
+    pub fn next_frame(&mut self) -> Option<Result<Vec<u8>, InvalidFrame>> {
+        let data = &self.buffer[self.start..];
+        if data.len() < BEGIN_STRING.len() {
+            return None;
+        }
+        if !data.starts_with(BEGIN_STRING) {
+            return Some(Err(
+                self.skip_garbage(ParseError::MissingField(Field::BeginString))
+            ));
+        }
+
+        // 8=FIX.4.4<SOH>9=<length><SOH>
+        let header = &data[..data.len().min(MAX_HEADER_LEN)];
+        let Some((begin_end, length_end)) = body_length_field(header) else {
+            if header.len() < MAX_HEADER_LEN {
+                return None;
+            }
+            return Some(Err(
+                self.skip_garbage(ParseError::MissingField(Field::BodyLength))
+            ));
+        };
+        let length_field = &data[begin_end..length_end];
+        let body_length = match length_field
+            .strip_prefix(b"9=")
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .and_then(|v| v.parse::<usize>().ok())
+            .filter(|v| *v <= MAX_BODY_LENGTH)
+        {
+            Some(body_length) => body_length,
+            None => {
+                let err = match length_field.strip_prefix(b"9=") {
+                    Some(v) => ParseError::InvalidValue(String::from_utf8_lossy(v).to_string()),
+                    None => ParseError::MissingField(Field::BodyLength),
+                };
+                return Some(Err(self.skip_garbage(err)));
+            }
+        };
+
+        let trailer_start = length_end + 1 + body_length;
+        let frame_end = trailer_start + TRAILER_LEN;
+        if data.len() < frame_end {
+            return None;
+        }
+        if !data[trailer_start..].starts_with(b"10=") || data[frame_end - 1] != SOH {
+            return Some(Err(
+                self.skip_garbage(ParseError::MissingField(Field::CheckSum))
+            ));
+        }
+
+        let frame = data[..frame_end].to_vec();
+        self.start += frame_end;
+        Some(Ok(frame))
+    }
+
+    // skips the bytes until the next BeginString
+    fn skip_garbage(&mut self, err: ParseError) -> InvalidFrame {
+        let data = &self.buffer[self.start..];
+        let skip = find(&data[1..], BEGIN_STRING)
+            .map(|pos| pos + 1)
+            .unwrap_or(data.len().saturating_sub(BEGIN_STRING.len() - 1));
+        let raw = String::from_utf8_lossy(&data[..skip]).to_string();
+        self.start += skip;
+        InvalidFrame { raw, err }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Framer;
+    use crate::{
+        messages::raw_message,
+        types::{ParseError, DELIMITER},
+    };
+
+    fn message(seq: u32) -> String {
+        raw_message(
+            &format!(
+                "35=0{}34={}{}49=CSERVER{}",
+                DELIMITER, seq, DELIMITER, DELIMITER
+            ),
+            DELIMITER,
+        )
+    }
+
+    #[test]
+    fn test_split_chunks() {
+        let stream = [message(1), message(2), message(3)].concat();
+        let mut framer = Framer::new();
+        let mut seqs = Vec::new();
+        // feed by 5 bytes
+        for chunk in stream.as_bytes().chunks(5) {
+            framer.extend(chunk);
+            while let Some(res) = framer.next_message() {
+                seqs.push(res.unwrap().get_tag_value(34).unwrap().to_string());
+            }
+        }
+        assert_eq!(seqs, vec!["1", "2", "3"]);
+        assert_eq!(framer.pending(), 0);
+    }
+
+    #[test]
+    fn test_skip_garbage() {
+        let mut framer = Framer::new();
+        framer.extend(format!("garbage{}", message(1)).as_bytes());
+
+        let invalid = framer.next_message().unwrap().unwrap_err();
+        assert_eq!(invalid.raw, "garbage");
+        assert!(framer.next_message().unwrap().is_ok());
+        assert!(framer.next_message().is_none());
+    }
+
+    #[test]
+    fn test_invalid_body_length() {
+        let mut framer = Framer::new();
+        let invalid = message(1).replacen("9=", "9=1", 1);
+        // enough bytes for the wrong body length
+        framer.extend(format!("{}{}", invalid, message(2).repeat(5)).as_bytes());
+
+        let invalid = framer.next_message().unwrap().unwrap_err();
+        assert_eq!(
+            invalid.err,
+            ParseError::MissingField(crate::types::Field::CheckSum)
+        );
+        let res = framer.next_message().unwrap().unwrap();
+        assert_eq!(res.get_tag_value(34), Some("2"));
+    }
+
+    #[test]
+    fn test_too_large_body_length() {
+        let mut framer = Framer::new();
+        let invalid = format!(
+            "8=FIX.4.4{}9=999999999{}35=0{}",
+            DELIMITER, DELIMITER, DELIMITER
+        );
+        framer.extend(format!("{}{}", invalid, message(1)).as_bytes());
+
+        let invalid = framer.next_message().unwrap().unwrap_err();
+        assert_eq!(invalid.err, ParseError::InvalidValue("999999999".into()));
+        let res = framer.next_message().unwrap().unwrap();
+        assert_eq!(res.get_tag_value(34), Some("1"));
+
+        // without the end of the BodyLength
+        framer.extend(format!("8=FIX.4.4{}9={}", DELIMITER, "9".repeat(40)).as_bytes());
+        assert!(framer.next_message().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_invalid_checksum() {
+        let mut framer = Framer::new();
+        framer.extend(message(1).replace("CSERVER", "CSERVEr").as_bytes());
+        let invalid = framer.next_message().unwrap().unwrap_err();
+        assert!(matches!(invalid.err, ParseError::CheckSumMismatch { .. }));
+    }
+}
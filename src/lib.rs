@@ -1,9 +1,22 @@
+pub mod bar;
 mod fixapi;
+pub mod framing;
 mod market_client;
 #[allow(dead_code)]
 mod messages;
+pub mod order_book;
+pub mod order_manager;
 mod parse_func;
+pub mod position_book;
+pub mod recorder;
+pub mod replay;
+mod runtime;
+mod session;
 mod socket;
+pub mod store;
+pub mod stream;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 mod trade_client;
 pub mod types;
 
@@ -1,19 +1,34 @@
 use std::{
-    collections::{HashMap, HashSet},
-    sync::Arc,
+    collections::{BTreeMap, HashMap, HashSet},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use futures::StreamExt;
 use uuid::Uuid;
 
-use async_std::sync::{Mutex, RwLock};
-use async_std::task;
+use async_channel::{bounded, unbounded, Receiver, Sender};
+use async_lock::{Mutex, RwLock};
 
 use crate::{
+    bar::{Bar, BarAggregators, GapPolicy, Timeframe},
     fixapi::FixApi,
     messages::MarketDataReq,
+    order_book::OrderBook,
+    recorder::{Tick, TickEvent, TickRecorder},
+    replay::{ReplaySource, ReplaySpeed},
+    runtime,
+    store::SessionStore,
+    stream::{MarketDataStream, StreamOptions, Subscribers},
     types::{
-        ConnectionHandler, DepthPrice, Error, Field, IncrementalRefresh, InternalMDResult,
-        MarketDataHandler, MarketType, SpotPrice,
+        Config, ConnectionHandler, DepthEvent, DepthPrice, Error, Field, IncrementalRefresh,
+        InternalMDResult, MarketDataHandler, MarketType, ReconnectPolicy, SpotPrice, SubID,
+        SubscriptionOptions,
     },
 };
 
@@ -31,55 +46,612 @@ pub struct MarketClient {
     depth_req_states: Arc<Mutex<HashMap<u32, RequestState>>>,
 
     spot_market_data: Arc<Mutex<HashMap<u32, SpotPrice>>>,
-    depth_market_data: Arc<RwLock<HashMap<u32, HashMap<String, DepthPrice>>>>,
+    depth_market_data: Arc<RwLock<HashMap<u32, OrderBook>>>,
+
+    spot_streams: Arc<Subscribers<SpotPrice>>,
+    depth_streams: Arc<Subscribers<DepthEvent>>,
+    waiters: Arc<SubscriptionWaiters>,
+    options: Arc<SubscriptionOptionsMap>,
+    recorder: Option<Arc<TickRecorder>>,
+    bars: Arc<BarAggregators>,
+    bar_streams: Arc<Subscribers<Bar>>,
     //
     //
     market_data_handler: Option<Arc<dyn MarketDataHandler + Send + Sync>>,
+
+    timeout: u64,
 }
 
-fn insert_entry_to(e: HashMap<Field, String>, depth_data: &mut HashMap<String, DepthPrice>) {
-    if e.len() < 4 {
-        return;
-    }
-    let eid = e.get(&Field::MDEntryID).unwrap();
-    depth_data.insert(
-        eid.clone(),
-        DepthPrice {
-            price_type: e.get(&Field::MDEntryType).unwrap().parse().unwrap(),
-            price: e.get(&Field::MDEntryPx).unwrap().parse::<f64>().unwrap(),
-            size: e.get(&Field::MDEntrySize).unwrap().parse::<f64>().unwrap(),
-        },
-    );
+fn entry_value(e: &HashMap<Field, String>, field: Field) -> Result<&String, Error> {
+    e.get(&field).ok_or(Error::FieldNotFoundError(field))
 }
 
-fn depth_data_from_entries(data: Vec<HashMap<Field, String>>) -> HashMap<String, DepthPrice> {
+fn parse_entry<T: FromStr>(
+    e: &HashMap<Field, String>,
+    field: Field,
+    msg_type: char,
+) -> Result<T, Error> {
+    let value = entry_value(e, field)?;
+    value.parse::<T>().map_err(|_| Error::ParseError {
+        field,
+        value: value.clone(),
+        msg_type: msg_type.to_string(),
+    })
+}
+
+fn depth_price_of(e: &HashMap<Field, String>, msg_type: char) -> Result<DepthPrice, Error> {
+    Ok(DepthPrice {
+        price_type: parse_entry(e, Field::MDEntryType, msg_type)?,
+        price: parse_entry(e, Field::MDEntryPx, msg_type)?,
+        size: parse_entry(e, Field::MDEntrySize, msg_type)?,
+    })
+}
+
+fn depth_data_from_entries(
+    data: Vec<HashMap<Field, String>>,
+) -> Result<HashMap<String, DepthPrice>, Error> {
     let mut depth_data = HashMap::new();
     for e in data.into_iter() {
-        insert_entry_to(e, &mut depth_data);
+        if e.len() < 4 {
+            continue;
+        }
+        let price = depth_price_of(&e, 'W')?;
+        depth_data.insert(entry_value(&e, Field::MDEntryID)?.clone(), price);
     }
-    depth_data
+    Ok(depth_data)
 }
 
-fn spot_price_from_market_data(data: Vec<HashMap<Field, String>>) -> SpotPrice {
+fn spot_price_from_market_data(data: Vec<HashMap<Field, String>>) -> Result<SpotPrice, Error> {
     let mut price = SpotPrice {
         bid: 0f64,
         ask: 0f64,
     };
 
-    for i in 0..2 {
-        let value = data[i]
-            .get(&Field::MDEntryPx)
-            .unwrap()
-            .parse::<f64>()
-            .unwrap();
-
-        if data[i].get(&Field::MDEntryType).unwrap() == "0" {
+    for entry in data.iter().take(2) {
+        let value = parse_entry::<f64>(entry, Field::MDEntryPx, 'W')?;
+        if entry_value(entry, Field::MDEntryType)? == "0" {
             price.bid = value;
         } else {
             price.ask = value;
         }
     }
-    price
+    Ok(price)
+}
+
+fn incremental_refresh_from_entries(
+    data: Vec<HashMap<Field, String>>,
+) -> Result<Vec<IncrementalRefresh>, Error> {
+    let mut incre_list = Vec::new();
+    for e in data.into_iter() {
+        let symbol = parse_entry::<u32>(&e, Field::Symbol, 'X')?;
+
+        match e.get(&Field::MDUpdateAction) {
+            Some(s) if s == "2" => {
+                // delete
+                incre_list.push(IncrementalRefresh::Delete {
+                    symbol_id: symbol,
+                    entry_id: entry_value(&e, Field::MDEntryID)?.clone(),
+                });
+            }
+            Some(s) if s == "0" => {
+                // new
+                incre_list.push(IncrementalRefresh::New {
+                    symbol_id: symbol,
+                    entry_id: entry_value(&e, Field::MDEntryID)?.clone(),
+                    data: depth_price_of(&e, 'X')?,
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(incre_list)
+}
+
+/// Options of the subscribed symbols, to handle the messages and to request them again.
+#[derive(Default)]
+struct SubscriptionOptionsMap {
+    options: std::sync::Mutex<HashMap<(MarketType, u32), SubscriptionOptions>>,
+}
+
+impl SubscriptionOptionsMap {
+    fn get(&self, market_type: MarketType, symbol_id: u32) -> SubscriptionOptions {
+        self.options
+            .lock()
+            .unwrap()
+            .get(&(market_type, symbol_id))
+            .cloned()
+            .unwrap_or_else(|| match market_type {
+                MarketType::Spot => SubscriptionOptions::spot(),
+                MarketType::Depth => SubscriptionOptions::depth(),
+            })
+    }
+
+    fn insert(&self, market_type: MarketType, symbol_id: u32, options: SubscriptionOptions) {
+        self.options
+            .lock()
+            .unwrap()
+            .insert((market_type, symbol_id), options);
+    }
+
+    fn remove(&self, market_type: MarketType, symbol_id: u32) {
+        self.options
+            .lock()
+            .unwrap()
+            .remove(&(market_type, symbol_id));
+    }
+
+    fn clear(&self) {
+        self.options.lock().unwrap().clear();
+    }
+}
+
+fn market_data_req(
+    md_req_id: String,
+    subscription_req_type: char,
+    options: &SubscriptionOptions,
+    symbols: &[u32],
+) -> MarketDataReq {
+    MarketDataReq::new(
+        md_req_id,
+        subscription_req_type,
+        options.market_depth,
+        options.update_type.map(u32::from),
+        &options.entry_type_chars(),
+        symbols,
+    )
+}
+
+/// Symbols rejected by the MarketDataRequestReject of `md_req_id`.
+///
+/// A request can have several symbols. Only the symbol of the message is rejected if it is one of
+/// them, otherwise all the symbols of the request are rejected.
+fn rejected_symbols(
+    states: &HashMap<u32, RequestState>,
+    symbol_id: u32,
+    md_req_id: &str,
+) -> Vec<u32> {
+    let requested =
+        |state: &RequestState| matches!(state, RequestState::Requested(id) if id == md_req_id);
+    if states.get(&symbol_id).map(requested).unwrap_or(false) {
+        return vec![symbol_id];
+    }
+    states
+        .iter()
+        .filter(|(_, state)| requested(state))
+        .map(|(symbol_id, _)| *symbol_id)
+        .collect()
+}
+
+/// Receives Ok on the first snapshot, or the error message of the reject.
+type Waiter = Sender<Result<(), String>>;
+
+/// Requests waiting for the first snapshot or the reject of the subscription.
+#[derive(Default)]
+struct SubscriptionWaiters {
+    waiters: std::sync::Mutex<HashMap<(MarketType, u32), Vec<Waiter>>>,
+}
+
+impl SubscriptionWaiters {
+    fn wait(&self, market_type: MarketType, symbol_id: u32) -> Receiver<Result<(), String>> {
+        let (sender, receiver) = bounded(1);
+        let mut waiters = self.waiters.lock().unwrap();
+        // drop the waiters given up
+        waiters.retain(|_, senders| {
+            senders.retain(|sender| !sender.is_closed());
+            !senders.is_empty()
+        });
+        waiters
+            .entry((market_type, symbol_id))
+            .or_default()
+            .push(sender);
+        receiver
+    }
+
+    fn notify(&self, market_type: MarketType, symbol_id: u32, result: Result<(), String>) {
+        let senders = self
+            .waiters
+            .lock()
+            .unwrap()
+            .remove(&(market_type, symbol_id));
+        for sender in senders.into_iter().flatten() {
+            sender.try_send(result.clone()).ok();
+        }
+    }
+}
+
+/// Entries of the incremental refresh grouped by the symbol.
+fn refresh_by_symbol(refresh: &[IncrementalRefresh]) -> BTreeMap<u32, Vec<IncrementalRefresh>> {
+    let mut by_symbol: BTreeMap<u32, Vec<IncrementalRefresh>> = BTreeMap::new();
+    for incre in refresh.iter() {
+        let symbol_id = match incre {
+            IncrementalRefresh::New { symbol_id, .. }
+            | IncrementalRefresh::Delete { symbol_id, .. } => *symbol_id,
+        };
+        by_symbol.entry(symbol_id).or_default().push(incre.clone());
+    }
+    by_symbol
+}
+
+/// Market data of a message, parsed before it is dispatched.
+enum MarketUpdate {
+    Spot {
+        symbol_id: u32,
+        price: Option<SpotPrice>,
+    },
+    DepthFull {
+        symbol_id: u32,
+        depth: HashMap<String, DepthPrice>,
+    },
+    // the entries can be of the different symbols
+    DepthIncremental {
+        refresh: Vec<IncrementalRefresh>,
+    },
+    Reject {
+        symbol_id: u32,
+        md_req_id: String,
+        err_msg: String,
+    },
+    Unknown,
+}
+
+impl MarketUpdate {
+    fn new(mdresult: InternalMDResult, options: &SubscriptionOptionsMap) -> Result<Self, Error> {
+        Ok(match mdresult {
+            InternalMDResult::MD {
+                msg_type: 'W',
+                symbol_id,
+                data,
+                ..
+            } => {
+                // check whether data is spot or depth
+                if !data.is_empty() && !data[0].contains_key(&Field::MDEntryID) {
+                    // the price is complete with all the subscribed entry types
+                    let entry_types = options
+                        .get(MarketType::Spot, symbol_id)
+                        .entry_types
+                        .len()
+                        .max(1);
+                    MarketUpdate::Spot {
+                        symbol_id,
+                        price: (data.len() >= entry_types)
+                            .then(|| spot_price_from_market_data(data))
+                            .transpose()?,
+                    }
+                } else {
+                    MarketUpdate::DepthFull {
+                        symbol_id,
+                        depth: depth_data_from_entries(data)?,
+                    }
+                }
+            }
+            InternalMDResult::MD {
+                msg_type: 'X',
+                data,
+                ..
+            } => MarketUpdate::DepthIncremental {
+                refresh: incremental_refresh_from_entries(data)?,
+            },
+            InternalMDResult::MD { .. } => MarketUpdate::Unknown,
+            InternalMDResult::MDReject {
+                symbol_id,
+                md_req_id,
+                err_msg,
+                ..
+            } => MarketUpdate::Reject {
+                symbol_id,
+                md_req_id,
+                err_msg,
+            },
+        })
+    }
+
+    /// Events of the update by symbol.
+    fn events(&self) -> Vec<(u32, TickEvent)> {
+        match self {
+            MarketUpdate::Spot {
+                symbol_id,
+                price: Some(price),
+            } => vec![(*symbol_id, TickEvent::Spot(price.clone()))],
+            MarketUpdate::DepthFull { symbol_id, depth } => vec![(
+                *symbol_id,
+                TickEvent::Depth(DepthEvent::FullRefresh(depth.clone())),
+            )],
+            MarketUpdate::DepthIncremental { refresh } => refresh_by_symbol(refresh)
+                .into_iter()
+                .map(|(symbol_id, refresh)| {
+                    (
+                        symbol_id,
+                        TickEvent::Depth(DepthEvent::IncrementalRefresh(refresh)),
+                    )
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Message to the dispatcher.
+enum Dispatch {
+    /// The session logged on again, so the sequence numbers may start over.
+    Logon,
+    Update {
+        seq: u32,
+        received_time: NaiveDateTime,
+        sending_time: Option<NaiveDateTime>,
+        update: MarketUpdate,
+    },
+    /// Replies after the previous messages are dispatched.
+    Barrier(Sender<()>),
+    /// Current local time to complete the bars without the prices.
+    Clock(NaiveDateTime),
+}
+
+/// Delay of completing the bars by the clock, for the latency of the prices.
+const BAR_CLOSE_DELAY: TimeDelta = TimeDelta::seconds(1);
+
+/// Time of the server estimated from the last market data, by which the bars are completed.
+///
+/// The bars are timed by the SendingTime of the prices, so they are completed by the same clock
+/// even if the local clock is off.
+#[derive(Debug, Default)]
+struct ServerClock {
+    /// SendingTime and the local received time of the last message.
+    last: Option<(NaiveDateTime, NaiveDateTime)>,
+}
+
+impl ServerClock {
+    fn on_message(&mut self, sending_time: NaiveDateTime, received_time: NaiveDateTime) {
+        self.last = Some((sending_time, received_time));
+    }
+
+    /// Server time at the local time, advanced from the last message. `None` before any message.
+    fn now(&self, local_time: NaiveDateTime) -> Option<NaiveDateTime> {
+        let (sending_time, received_time) = self.last?;
+        Some(sending_time + (local_time - received_time).max(TimeDelta::zero()))
+    }
+}
+
+/// Applies the market data to the state, the handler and the streams one by one, in the order
+/// of the messages.
+struct Dispatcher {
+    spot_req_states: Arc<Mutex<HashMap<u32, RequestState>>>,
+    depth_req_states: Arc<Mutex<HashMap<u32, RequestState>>>,
+    spot_market_data: Arc<Mutex<HashMap<u32, SpotPrice>>>,
+    depth_market_data: Arc<RwLock<HashMap<u32, OrderBook>>>,
+    spot_streams: Arc<Subscribers<SpotPrice>>,
+    depth_streams: Arc<Subscribers<DepthEvent>>,
+    waiters: Arc<SubscriptionWaiters>,
+    recorder: Option<Arc<TickRecorder>>,
+    bars: Arc<BarAggregators>,
+    bar_streams: Arc<Subscribers<Bar>>,
+    handler: Option<Arc<dyn MarketDataHandler + Send + Sync>>,
+    /// The symbols not requested are regarded as requested, for the replay.
+    replay: bool,
+}
+
+impl Dispatcher {
+    async fn run(self, receiver: Receiver<Dispatch>) {
+        let mut last_seq = 0;
+        let mut clock = ServerClock::default();
+        while let Ok(dispatch) = receiver.recv().await {
+            match dispatch {
+                Dispatch::Logon => last_seq = 0,
+                Dispatch::Barrier(reply) => {
+                    reply.try_send(()).ok();
+                }
+                Dispatch::Clock(now) => {
+                    let Some(server_time) = clock.now(now) else {
+                        continue;
+                    };
+                    for bar in self.bars.close_until(server_time - BAR_CLOSE_DELAY) {
+                        self.publish_bar(bar).await;
+                    }
+                }
+                Dispatch::Update {
+                    seq,
+                    received_time,
+                    sending_time,
+                    update,
+                } => {
+                    // 0 if the message has no MsgSeqNum
+                    if seq != 0 {
+                        if seq <= last_seq {
+                            log::warn!(
+                                "Ignore the market data of MsgSeqNum({}) after {}",
+                                seq,
+                                last_seq
+                            );
+                            continue;
+                        }
+                        last_seq = seq;
+                    }
+                    let time = sending_time.unwrap_or(received_time);
+                    clock.on_message(time, received_time);
+                    for (symbol_id, event) in update.events() {
+                        if let Some(recorder) = &self.recorder {
+                            let tick = Tick {
+                                received_time,
+                                sending_time,
+                                symbol_id,
+                                event: event.clone(),
+                            };
+                            if let Err(err) = recorder.record(&tick) {
+                                log::error!("Failed to record the market data - {:?}", err);
+                            }
+                        }
+                        match event {
+                            TickEvent::Spot(price) => {
+                                for bar in self.bars.update(symbol_id, time, &price) {
+                                    self.publish_bar(bar).await;
+                                }
+                                self.spot_streams.publish(symbol_id, price)
+                            }
+                            TickEvent::Depth(event) => self.depth_streams.publish(symbol_id, event),
+                        }
+                    }
+                    self.dispatch(update).await;
+                }
+            }
+        }
+    }
+
+    async fn publish_bar(&self, bar: Bar) {
+        self.bar_streams.publish(bar.symbol_id, bar.clone());
+        if let Some(handler) = &self.handler {
+            handler.on_bar(bar).await;
+        }
+    }
+
+    async fn dispatch(&self, update: MarketUpdate) {
+        match update {
+            MarketUpdate::Spot { symbol_id, price } => {
+                let requested_symbol = self
+                    .spot_req_states
+                    .lock()
+                    .await
+                    .get(&symbol_id)
+                    .map(|v| matches!(v, RequestState::Requested(_)))
+                    .unwrap_or(self.replay);
+
+                if requested_symbol {
+                    self.spot_req_states
+                        .lock()
+                        .await
+                        .insert(symbol_id, RequestState::Accepted);
+                    // to handler
+                    if let Some(handler) = &self.handler {
+                        handler.on_accpeted_spot_subscription(symbol_id).await;
+                    }
+                }
+
+                // update spot data
+                if let Some(prices) = price {
+                    self.spot_market_data
+                        .lock()
+                        .await
+                        .insert(symbol_id, prices.clone());
+
+                    // to handler
+                    if let Some(handler) = &self.handler {
+                        handler.on_price_of(symbol_id, prices).await;
+                    }
+                }
+
+                if requested_symbol {
+                    self.waiters.notify(MarketType::Spot, symbol_id, Ok(()));
+                }
+            }
+            MarketUpdate::DepthFull { symbol_id, depth } => {
+                let requested_symbol = self
+                    .depth_req_states
+                    .lock()
+                    .await
+                    .get(&symbol_id)
+                    .map(|v| matches!(v, RequestState::Requested(_)))
+                    .unwrap_or(self.replay);
+
+                if requested_symbol {
+                    self.depth_req_states
+                        .lock()
+                        .await
+                        .insert(symbol_id, RequestState::Accepted);
+
+                    if let Some(handler) = &self.handler {
+                        handler.on_accpeted_depth_subscription(symbol_id).await;
+                    }
+                }
+
+                // FIXME which one should be first?
+                // to handler
+                if let Some(handler) = &self.handler {
+                    handler
+                        .on_market_depth_full_refresh(symbol_id, depth.clone())
+                        .await;
+                }
+
+                // update the depth data
+                self.depth_market_data
+                    .write()
+                    .await
+                    .insert(symbol_id, OrderBook::new(depth));
+
+                if requested_symbol {
+                    self.waiters.notify(MarketType::Depth, symbol_id, Ok(()));
+                }
+            }
+            // Market data incremental refresh, applied to the book of each symbol
+            MarketUpdate::DepthIncremental { refresh } => {
+                let accepted = {
+                    let states = self.depth_req_states.lock().await;
+                    refresh_by_symbol(&refresh)
+                        .into_iter()
+                        .filter(|(symbol_id, _)| {
+                            states.get(symbol_id) == Some(&RequestState::Accepted)
+                        })
+                        .collect::<Vec<_>>()
+                };
+                if accepted.is_empty() {
+                    return;
+                }
+
+                // FIXME which one should be first?
+                // to handler
+                if let Some(handler) = &self.handler {
+                    let refresh = accepted
+                        .iter()
+                        .flat_map(|(_, refresh)| refresh.iter().cloned())
+                        .collect();
+                    handler.on_market_depth_incremental_refresh(refresh).await;
+                }
+
+                let mut books = self.depth_market_data.write().await;
+                for (symbol_id, refresh) in accepted {
+                    books.entry(symbol_id).or_default().apply(&refresh);
+                }
+            }
+            MarketUpdate::Unknown => {}
+            MarketUpdate::Reject {
+                symbol_id,
+                md_req_id,
+                err_msg,
+            } => {
+                for market_type in [MarketType::Spot, MarketType::Depth] {
+                    let rejected = {
+                        let mut states = match market_type {
+                            MarketType::Spot => self.spot_req_states.lock().await,
+                            MarketType::Depth => self.depth_req_states.lock().await,
+                        };
+                        let rejected = rejected_symbols(&states, symbol_id, &md_req_id);
+                        for symbol_id in rejected.iter() {
+                            states.insert(*symbol_id, RequestState::Rejected);
+                        }
+                        rejected
+                    };
+                    for symbol_id in rejected.iter() {
+                        self.waiters
+                            .notify(market_type, *symbol_id, Err(err_msg.clone()));
+                    }
+                    // notify
+                    if let Some(handler) = &self.handler {
+                        for symbol_id in rejected {
+                            match market_type {
+                                MarketType::Spot => {
+                                    handler
+                                        .on_rejected_spot_subscription(symbol_id, err_msg.clone())
+                                        .await
+                                }
+                                MarketType::Depth => {
+                                    handler
+                                        .on_rejected_depth_subscription(symbol_id, err_msg.clone())
+                                        .await
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
 }
 
 impl MarketClient {
@@ -90,24 +662,47 @@ impl MarketClient {
         sender_comp_id: String,
         heartbeat_interval: Option<u32>,
     ) -> Self {
+        Self::with_config(Config::new(
+            host,
+            login,
+            password,
+            sender_comp_id,
+            heartbeat_interval.unwrap_or(30),
+        ))
+    }
+
+    /// Creates the client from a `Config`, e.g. to use the TLS transport.
+    pub fn with_config(config: Config) -> Self {
         Self {
-            internal: FixApi::new(
-                crate::types::SubID::QUOTE,
-                host,
-                login,
-                password,
-                sender_comp_id,
-                heartbeat_interval,
-            ),
+            internal: FixApi::new(SubID::QUOTE, config),
 
             spot_req_states: Arc::new(Mutex::new(HashMap::new())),
             spot_market_data: Arc::new(Mutex::new(HashMap::new())),
 
             depth_req_states: Arc::new(Mutex::new(HashMap::new())),
             depth_market_data: Arc::new(RwLock::new(HashMap::new())),
+
+            spot_streams: Arc::new(Subscribers::default()),
+            depth_streams: Arc::new(Subscribers::default()),
+            waiters: Arc::new(SubscriptionWaiters::default()),
+            options: Arc::new(SubscriptionOptionsMap::default()),
+            recorder: None,
+            bars: Arc::new(BarAggregators::default()),
+            bar_streams: Arc::new(Subscribers::default()),
             market_data_handler: None,
+
+            timeout: 5000,
         }
     }
+
+    /// Timeout in milliseconds of `subscribe_spot_and_wait` and `subscribe_depth_and_wait`.
+    pub fn get_timeout(&self) -> u64 {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: u64) {
+        self.timeout = timeout;
+    }
     pub fn register_market_handler_arc<T: MarketDataHandler + Send + Sync + 'static>(
         &mut self,
         handler: Arc<T>,
@@ -136,271 +731,143 @@ impl MarketClient {
         self.internal.register_connection_handler(handler);
     }
 
-    fn register_internal_handler(&mut self) {
-        // clone
-        // let trigger = self.internal.trigger.clone();
-        let spot_req_states_clone = self.spot_req_states.clone();
-        let spot_market_data_clone = self.spot_market_data.clone();
+    /// Sets the policy to reconnect automatically when the connection is lost. (disabled by default)
+    ///
+    /// After the session is logged on again, the symbols subscribed in the previous session are
+    /// requested again and the results are notified through the `MarketDataHandler`.
+    pub fn set_reconnect_policy(&mut self, policy: Option<ReconnectPolicy>) {
+        self.internal.set_reconnect_policy(policy);
+    }
 
-        let depth_req_states_clone = self.depth_req_states.clone();
-        let depth_market_data_clone = self.depth_market_data.clone();
+    /// Sets the store of the sequence numbers and the sent messages. (`MemoryStore` by default)
+    ///
+    /// With a persistent store such as `FileStore`, the session logs on without resetting the
+    /// sequence numbers.
+    pub fn set_session_store<S: SessionStore + Send + Sync + 'static>(&mut self, store: S) {
+        self.internal.set_session_store(store);
+    }
+
+    /// Records the spot prices and the depth updates of all the subscriptions.
+    ///
+    /// Set it before `connect`. Keep the recorder to `flush` it.
+    pub fn set_recorder(&mut self, recorder: Arc<TickRecorder>) {
+        self.recorder = Some(recorder);
+    }
 
-        let market_data_handler = self.market_data_handler.clone();
+    fn register_resubscription(&mut self, dispatch_sender: Sender<Dispatch>) {
+        let spot_req_states = self.spot_req_states.clone();
+        let depth_req_states = self.depth_req_states.clone();
+        let options = self.options.clone();
+        // nothing to restore at the first logon, and the symbols subscribed right after it
+        // must not be requested twice.
+        let logged_on = AtomicBool::new(false);
 
-        let market_callback = move |mdresult: InternalMDResult| {
-            // symbol_id is only valid for msg_type - 'W'
-
-            // let tx = trigger.clone();
-            let spot_req_states_clone = spot_req_states_clone.clone();
-            let spot_market_data_clone = spot_market_data_clone.clone();
-            let depth_req_states_clone = depth_req_states_clone.clone();
-            let depth_market_data_clone = depth_market_data_clone.clone();
-
-            let market_data_handler = market_data_handler.clone();
-
-            // let mtype = String::from(msg_type);
-            //
-            task::spawn(async move {
-                match mdresult {
-                    InternalMDResult::MD {
-                        msg_type,
-                        symbol_id,
-                        data,
-                    } => {
-                        match msg_type {
-                            'W' => {
-                                // check whether data is spot or depth
-                                if data.len() != 0 && !data[0].contains_key(&Field::MDEntryID) {
-                                    //spot
-                                    let requested_symbol = spot_req_states_clone
-                                        .lock()
-                                        .await
-                                        .get(&symbol_id)
-                                        .map(|v| match v {
-                                            RequestState::Requested(_) => true,
-                                            _ => false,
-                                        })
-                                        .unwrap_or(false);
-
-                                    if requested_symbol {
-                                        spot_req_states_clone
-                                            .lock()
-                                            .await
-                                            .insert(symbol_id, RequestState::Accepted);
-                                        // to handler
-                                        if let Some(handler) = &market_data_handler {
-                                            handler.on_accpeted_spot_subscription(symbol_id).await;
-                                        }
-                                    }
-
-                                    // update spot data
-                                    if data.len() >= 2 {
-                                        let prices = spot_price_from_market_data(data);
-
-                                        spot_market_data_clone
-                                            .lock()
-                                            .await
-                                            .insert(symbol_id, prices.clone());
-
-                                        // to handler
-                                        if let Some(handler) = &market_data_handler {
-                                            handler.on_price_of(symbol_id, prices).await;
-                                        }
-                                    }
-                                } else {
-                                    // depth
-                                    let requested_symbol = depth_req_states_clone
-                                        .lock()
-                                        .await
-                                        .get(&symbol_id)
-                                        .map(|v| match v {
-                                            RequestState::Requested(_) => true,
-                                            _ => false,
-                                        })
-                                        .unwrap_or(false);
-
-                                    if requested_symbol {
-                                        depth_req_states_clone
-                                            .lock()
-                                            .await
-                                            .insert(symbol_id, RequestState::Accepted);
-
-                                        if let Some(handler) = &market_data_handler {
-                                            handler.on_accpeted_depth_subscription(symbol_id).await;
-                                        }
-                                    }
-
-                                    {
-                                        let depth_data = depth_data_from_entries(data);
-
-                                        // FIXME which one should be first?
-                                        // to handler
-                                        if let Some(handler) = &market_data_handler {
-                                            handler
-                                                .on_market_depth_full_refresh(
-                                                    symbol_id,
-                                                    depth_data.clone(),
-                                                )
-                                                .await;
-                                        }
-
-                                        // update the depth data
-                                        depth_market_data_clone
-                                            .write()
-                                            .await
-                                            .insert(symbol_id, depth_data);
-                                    }
-                                }
-                            }
-                            'X' => {
-                                // ignore the symbol_id argument
-                                //
-                                // Market data incremental refresh
-                                if Some(&RequestState::Accepted)
-                                    == depth_req_states_clone.lock().await.get(&symbol_id)
-                                {
-                                    let mut incre_list = Vec::new();
-                                    for e in data.into_iter() {
-                                        let symbol =
-                                            e.get(&Field::Symbol).unwrap().parse::<u32>().unwrap();
-
-                                        match e.get(&Field::MDUpdateAction) {
-                                            Some(s) if s == "2" => {
-                                                // delete
-                                                incre_list.push(IncrementalRefresh::Delete {
-                                                    symbol_id: symbol,
-                                                    entry_id: e
-                                                        .get(&Field::MDEntryID)
-                                                        .unwrap()
-                                                        .clone(),
-                                                });
-                                            }
-                                            Some(s) if s == "0" => {
-                                                // new
-                                                let eid = e.get(&Field::MDEntryID).unwrap();
-                                                incre_list.push(IncrementalRefresh::New {
-                                                    symbol_id: symbol,
-                                                    entry_id: eid.clone(),
-                                                    data: DepthPrice {
-                                                        price_type: e
-                                                            .get(&Field::MDEntryType)
-                                                            .unwrap()
-                                                            .parse()
-                                                            .unwrap(),
-                                                        price: e
-                                                            .get(&Field::MDEntryPx)
-                                                            .unwrap()
-                                                            .parse::<f64>()
-                                                            .unwrap(),
-                                                        size: e
-                                                            .get(&Field::MDEntrySize)
-                                                            .unwrap()
-                                                            .parse::<f64>()
-                                                            .unwrap(),
-                                                    },
-                                                });
-                                            }
-                                            _ => {}
-                                        }
-                                    }
-
-                                    // FIXME which one should be first?
-                                    // to handler
-                                    if let Some(handler) = market_data_handler {
-                                        handler
-                                            .on_market_depth_incremental_refresh(incre_list.clone())
-                                            .await;
-                                    }
-
-                                    {
-                                        let mut depth_cont = depth_market_data_clone.write().await;
-                                        for incre in incre_list.into_iter() {
-                                            match incre {
-                                                IncrementalRefresh::New {
-                                                    symbol_id,
-                                                    entry_id,
-                                                    data,
-                                                } => {
-                                                    let s = depth_cont
-                                                        .entry(symbol_id)
-                                                        .or_insert(HashMap::new());
-                                                    s.insert(entry_id, data);
-                                                }
-                                                IncrementalRefresh::Delete {
-                                                    symbol_id,
-                                                    entry_id,
-                                                } => {
-                                                    let s = depth_cont
-                                                        .entry(symbol_id)
-                                                        .or_insert(HashMap::new());
-                                                    s.remove(&entry_id);
-                                                }
-                                            }
-                                        }
-                                    }
-                                    //
-                                }
-                            }
-                            _ => {}
+        self.internal.register_logon_callback(move |api: FixApi| {
+            dispatch_sender.try_send(Dispatch::Logon).ok();
+            if !logged_on.swap(true, Ordering::Relaxed) {
+                return;
+            }
+            let spot_req_states = spot_req_states.clone();
+            let depth_req_states = depth_req_states.clone();
+            let options = options.clone();
+            runtime::spawn(async move {
+                for (market_type, states) in [
+                    (MarketType::Spot, spot_req_states),
+                    (MarketType::Depth, depth_req_states),
+                ] {
+                    // the pending requests of the previous session are requested again as well.
+                    // one request for the symbols of the same options.
+                    let mut requests: Vec<(SubscriptionOptions, String, Vec<u32>)> = Vec::new();
+                    for (symbol_id, state) in states.lock().await.iter_mut() {
+                        if *state == RequestState::Rejected {
+                            continue;
                         }
-                    }
-                    InternalMDResult::MDReject {
-                        symbol_id,
-                        md_req_id,
-                        err_msg,
-                    } => {
-                        let spot_requested = spot_req_states_clone
-                            .lock()
-                            .await
-                            .values()
-                            .filter(|s| match s {
-                                RequestState::Requested(value) => value == md_req_id.as_str(),
-                                _ => false,
-                            })
-                            .count()
-                            == 1;
-                        if spot_requested {
-                            // change the state
-                            spot_req_states_clone
-                                .lock()
-                                .await
-                                .insert(symbol_id, RequestState::Rejected);
-                            // notify
-                            if let Some(handler) = &market_data_handler {
-                                handler
-                                    .on_rejected_spot_subscription(symbol_id, err_msg.clone())
-                                    .await;
+                        let symbol_options = options.get(market_type, *symbol_id);
+                        let idx = match requests.iter().position(|(o, _, _)| *o == symbol_options) {
+                            Some(idx) => idx,
+                            None => {
+                                let mdreqid = Uuid::new_v4().to_string();
+                                requests.push((symbol_options, mdreqid, Vec::new()));
+                                requests.len() - 1
                             }
-                        }
+                        };
+                        *state = RequestState::Requested(requests[idx].1.clone());
+                        requests[idx].2.push(*symbol_id);
+                    }
 
-                        let depth_requested = depth_req_states_clone
-                            .lock()
-                            .await
-                            .values()
-                            .filter(|s| match s {
-                                RequestState::Requested(value) => value == md_req_id.as_str(),
-                                _ => false,
-                            })
-                            .count()
-                            == 1;
-                        if depth_requested {
-                            // change the state
-                            depth_req_states_clone
-                                .lock()
-                                .await
-                                .insert(symbol_id, RequestState::Rejected);
-                            // notify
-                            if let Some(handler) = &market_data_handler {
-                                handler
-                                    .on_rejected_depth_subscription(symbol_id, err_msg.clone())
-                                    .await;
-                            }
+                    for (symbol_options, mdreqid, symbols) in requests {
+                        log::debug!("Resubscribe {} for symbols{:?}", market_type, symbols);
+                        let req = market_data_req(mdreqid, '1', &symbol_options, &symbols);
+                        if let Err(err) = api.send_message(req).await {
+                            log::error!("Failed to resubscribe symbols{:?} - {:?}", symbols, err);
                         }
                     }
                 }
             });
+        });
+    }
+
+    /// Starts the dispatcher of the session and returns the sender to it.
+    fn register_internal_handler(&mut self, replay: bool) -> Sender<Dispatch> {
+        let dispatcher = Dispatcher {
+            spot_req_states: self.spot_req_states.clone(),
+            depth_req_states: self.depth_req_states.clone(),
+            spot_market_data: self.spot_market_data.clone(),
+            depth_market_data: self.depth_market_data.clone(),
+            spot_streams: self.spot_streams.clone(),
+            depth_streams: self.depth_streams.clone(),
+            waiters: self.waiters.clone(),
+            recorder: self.recorder.clone(),
+            bars: self.bars.clone(),
+            bar_streams: self.bar_streams.clone(),
+            handler: self.market_data_handler.clone(),
+            replay,
+        };
+        let (sender, receiver) = unbounded();
+        runtime::spawn(dispatcher.run(receiver));
+
+        // the bars of the replay are completed by the time of the messages only.
+        if !replay {
+            let clock_sender = sender.clone();
+            runtime::spawn(async move {
+                let mut ticks = runtime::interval(Duration::from_secs(1));
+                while ticks.next().await.is_some() {
+                    // the other senders are dropped when the handler is registered again.
+                    if clock_sender.sender_count() <= 1 {
+                        break;
+                    }
+                    clock_sender
+                        .try_send(Dispatch::Clock(Utc::now().naive_utc()))
+                        .ok();
+                }
+            });
+        }
+
+        let dispatch_sender = sender.clone();
+        let options = self.options.clone();
+        let market_callback = move |mdresult: InternalMDResult| {
+            // called in the order of MsgSeqNum, and the dispatcher keeps the order.
+            let received_time = Utc::now().naive_utc();
+            let seq = mdresult.seq();
+            let sending_time = mdresult.sending_time();
+            let update = match MarketUpdate::new(mdresult, &options) {
+                Ok(update) => update,
+                Err(err) => {
+                    log::error!("Drop the market data of MsgSeqNum {} - {:?}", seq, err);
+                    return;
+                }
+            };
+            dispatch_sender
+                .try_send(Dispatch::Update {
+                    seq,
+                    received_time,
+                    sending_time,
+                    update,
+                })
+                .ok();
         };
         self.internal.register_market_callback(market_callback);
+        sender
     }
 
     /// Connects to a server
@@ -409,15 +876,41 @@ impl MarketClient {
     /// it proceeds to logon directly.
     pub async fn connect(&mut self) -> Result<(), Error> {
         // set market handler
-        self.register_internal_handler();
+        let dispatch_sender = self.register_internal_handler(false);
+        self.register_resubscription(dispatch_sender);
+        self.clear_states().await;
+
+        // connection
+        self.internal.connect().await?;
+        self.internal.logon(true).await
+    }
+
+    async fn clear_states(&self) {
         self.spot_req_states.lock().await.clear();
         self.depth_req_states.lock().await.clear();
         self.spot_market_data.lock().await.clear();
         self.depth_market_data.write().await.clear();
+        self.options.clear();
+    }
 
-        // connection
-        self.internal.connect().await?;
-        self.internal.logon(true).await
+    /// Replays the recorded messages of a FIX log instead of connecting to the server.
+    ///
+    /// The market data goes to the handler, the streams and the recorder as if it was received,
+    /// and the symbols in the log are regarded as subscribed. Returns after all the messages are
+    /// dispatched.
+    pub async fn replay(&mut self, source: &ReplaySource, speed: ReplaySpeed) -> Result<(), Error> {
+        let dispatch_sender = self.register_internal_handler(true);
+        let logon_sender = dispatch_sender.clone();
+        self.internal.register_logon_callback(move |_: FixApi| {
+            logon_sender.try_send(Dispatch::Logon).ok();
+        });
+        self.clear_states().await;
+
+        self.internal.replay(source, speed).await;
+
+        let (reply, done) = bounded(1);
+        dispatch_sender.try_send(Dispatch::Barrier(reply)).ok();
+        done.recv().await.map_err(|_| Error::NoResponse)
     }
 
     pub async fn disconnect(&mut self) -> Result<(), Error> {
@@ -454,7 +947,7 @@ impl MarketClient {
             .lock()
             .await
             .get(&symbol_id)
-            .map(|v| v.clone())
+            .cloned()
             .ok_or(Error::NotSubscribed(symbol_id, MarketType::Spot))
     }
 
@@ -463,62 +956,275 @@ impl MarketClient {
             .read()
             .await
             .get(&symbol_id)
-            .map(|v| v.clone())
+            .map(|book| book.entries().clone())
             .ok_or(Error::NotSubscribed(symbol_id, MarketType::Spot))
     }
 
-    pub async fn subscribe_spot(&self, symbol_id: u32) -> Result<(), Error> {
-        // FIXME later
-        // .. code is too messy. is there a better way?
+    /// Returns the price-sorted order book of the depth subscription.
+    pub async fn order_book(&self, symbol_id: u32) -> Result<OrderBook, Error> {
+        self.depth_market_data
+            .read()
+            .await
+            .get(&symbol_id)
+            .cloned()
+            .ok_or(Error::NotSubscribed(symbol_id, MarketType::Depth))
+    }
+
+    /// Returns the stream of the spot prices of the symbol.
+    ///
+    /// The prices are delivered in the order of the messages while the symbol is subscribed by
+    /// `subscribe_spot`. It does not subscribe the symbol by itself.
+    pub fn spot_stream(
+        &self,
+        symbol_id: u32,
+        options: StreamOptions,
+    ) -> MarketDataStream<SpotPrice> {
+        self.spot_streams.stream(symbol_id, options)
+    }
+
+    /// Returns the stream of the depth events of the symbol.
+    ///
+    /// The events are delivered in the order of the messages while the symbol is subscribed by
+    /// `subscribe_depth`. With `OverflowPolicy::Lagged`, the book built from the events is not
+    /// valid anymore after `Error::Lagged`. Subscribe the depth again to receive a full refresh.
+    pub fn depth_stream(
+        &self,
+        symbol_id: u32,
+        options: StreamOptions,
+    ) -> MarketDataStream<DepthEvent> {
+        self.depth_streams.stream(symbol_id, options)
+    }
+
+    /// Aggregates the spot prices of the symbol into the bars of the timeframe.
+    ///
+    /// The completed bars are delivered to `MarketDataHandler::on_bar` and `bar_stream`. The bars
+    /// are timed by the SendingTime(52) of the prices, and completed when a price of the later bar
+    /// arrives or a second after the end by the server clock, estimated from the SendingTime of
+    /// the last market data. It does not subscribe the symbol by itself.
+    ///
+    /// Returns `false` if the timeframe of the symbol is already aggregated.
+    pub fn aggregate_bars(
+        &self,
+        symbol_id: u32,
+        timeframe: Timeframe,
+        gap_policy: GapPolicy,
+    ) -> bool {
+        self.bars.add(symbol_id, timeframe, gap_policy)
+    }
+
+    pub fn remove_bars(&self, symbol_id: u32, timeframe: Timeframe) {
+        self.bars.remove(symbol_id, timeframe);
+    }
+
+    /// Returns the stream of the completed bars of the symbol, of all the aggregated timeframes.
+    pub fn bar_stream(&self, symbol_id: u32, options: StreamOptions) -> MarketDataStream<Bar> {
+        self.bar_streams.stream(symbol_id, options)
+    }
+
+    fn default_options(market_type: MarketType) -> SubscriptionOptions {
+        match market_type {
+            MarketType::Spot => SubscriptionOptions::spot(),
+            MarketType::Depth => SubscriptionOptions::depth(),
+        }
+    }
+
+    fn req_states(&self, market_type: MarketType) -> &Mutex<HashMap<u32, RequestState>> {
+        match market_type {
+            MarketType::Spot => &self.spot_req_states,
+            MarketType::Depth => &self.depth_req_states,
+        }
+    }
+
+    /// Requests the subscription of the symbols in one MarketDataRequest.
+    ///
+    /// Nothing is requested if any of the symbols is subscribed or being requested already.
+    async fn subscribe_many(
+        &self,
+        market_type: MarketType,
+        symbol_ids: &[u32],
+        options: &SubscriptionOptions,
+    ) -> Result<(), Error> {
+        if options.entry_types.is_empty() {
+            return Err(Error::InvalidArgument("No entry types".into()));
+        }
+        // the spot prices are distinguished from the depth by MarketDepth
+        match (market_type, options.market_depth) {
+            (MarketType::Spot, 1) | (MarketType::Depth, 0) | (MarketType::Depth, 2..) => {}
+            (_, market_depth) => {
+                return Err(Error::InvalidArgument(format!(
+                    "MarketDepth({}) of the {} subscription",
+                    market_depth, market_type
+                )));
+            }
+        }
+
+        let mut symbols: Vec<u32> = Vec::with_capacity(symbol_ids.len());
+        for symbol_id in symbol_ids {
+            if !symbols.contains(symbol_id) {
+                symbols.push(*symbol_id);
+            }
+        }
+        if symbols.is_empty() {
+            return Ok(());
+        }
 
         let mdreqid = Uuid::new_v4().to_string();
-        // check already subscribed?
-        if let Some(state) = self.spot_req_states.lock().await.get(&symbol_id) {
-            match state {
-                RequestState::Accepted => {
-                    return Err(Error::SubscribedAlready(symbol_id, MarketType::Spot));
+        {
+            let mut states = self.req_states(market_type).lock().await;
+            // check already subscribed?
+            for symbol_id in symbols.iter() {
+                match states.get(symbol_id) {
+                    Some(RequestState::Accepted) => {
+                        return Err(Error::SubscribedAlready(*symbol_id, market_type));
+                    }
+                    Some(RequestState::Requested(_)) => {
+                        return Err(Error::RequestingSubscription(*symbol_id, market_type));
+                    }
+                    _ => {}
                 }
-                RequestState::Requested(_) => {
-                    return Err(Error::RequestingSubscription(symbol_id, MarketType::Spot));
+            }
+
+            // add to requested symbol.
+            for symbol_id in symbols.iter() {
+                states.insert(*symbol_id, RequestState::Requested(mdreqid.clone()));
+                self.options
+                    .insert(market_type, *symbol_id, options.clone());
+            }
+        }
+
+        // intialize the request and send req
+        let req = market_data_req(mdreqid.clone(), '1', options, &symbols);
+        if let Err(err) = self.internal.send_message(req).await {
+            let mut states = self.req_states(market_type).lock().await;
+            for symbol_id in symbols.iter() {
+                if matches!(states.get(symbol_id), Some(RequestState::Requested(id)) if *id == mdreqid)
+                {
+                    states.remove(symbol_id);
+                    self.options.remove(market_type, *symbol_id);
                 }
-                _ => {}
             }
+            return Err(err);
         }
 
-        // add to requested symbol.
-        self.spot_req_states
-            .lock()
-            .await
-            .insert(symbol_id, RequestState::Requested(mdreqid.clone()));
+        Ok(())
+    }
 
-        // intialize the request and send req
-        let req = MarketDataReq::new(mdreqid, '1', 1, None, &['0', '1'], 1, symbol_id);
+    async fn subscribe_and_wait(
+        &self,
+        market_type: MarketType,
+        symbol_id: u32,
+    ) -> Result<(), Error> {
+        // wait before the request not to miss the response.
+        let receiver = self.waiters.wait(market_type, symbol_id);
+        self.subscribe_many(
+            market_type,
+            &[symbol_id],
+            &Self::default_options(market_type),
+        )
+        .await?;
+
+        let mdreqid = match self.req_states(market_type).lock().await.get(&symbol_id) {
+            Some(RequestState::Requested(mdreqid)) => mdreqid.clone(),
+            _ => String::new(),
+        };
+
+        let err = match runtime::timeout(Duration::from_millis(self.timeout), receiver.recv()).await
+        {
+            Some(Ok(Ok(()))) => return Ok(()),
+            Some(Ok(Err(err_msg))) => {
+                return Err(Error::SubscriptionError(symbol_id, err_msg, market_type));
+            }
+            Some(Err(err)) => err.into(),
+            None => Error::TimeoutError,
+        };
+        self.cancel_request(market_type, symbol_id, &mdreqid)
+            .await?;
+        Err(err)
+    }
+
+    /// Rolls back the subscription of `md_req_id` which has not been answered, so that it can be
+    /// requested again. The server is asked to unsubscribe in case the answer is only late.
+    async fn cancel_request(
+        &self,
+        market_type: MarketType,
+        symbol_id: u32,
+        md_req_id: &str,
+    ) -> Result<(), Error> {
+        {
+            let mut states = self.req_states(market_type).lock().await;
+            match states.get(&symbol_id) {
+                Some(RequestState::Requested(id)) if id == md_req_id => {
+                    states.remove(&symbol_id);
+                }
+                _ => return Ok(()),
+            }
+        }
+        let options = self.options.get(market_type, symbol_id);
+        self.options.remove(market_type, symbol_id);
+        let req = market_data_req("-1".into(), '2', &options, &[symbol_id]);
         self.internal.send_message(req).await?;
 
+        log::debug!(
+            "Cancelled the {} subscription of symbol({}) without answer",
+            market_type,
+            symbol_id
+        );
+
         Ok(())
     }
 
+    pub async fn subscribe_spot(&self, symbol_id: u32) -> Result<(), Error> {
+        self.subscribe_many(MarketType::Spot, &[symbol_id], &SubscriptionOptions::spot())
+            .await
+    }
+
+    /// Subscribes the spot prices of the symbol, and waits until the first price arrives.
+    ///
+    /// Returns `Error::SubscriptionError` if the subscription is rejected, or `Error::TimeoutError`
+    /// after the timeout. The unanswered request is cancelled, so the symbol can be subscribed
+    /// again.
+    pub async fn subscribe_spot_and_wait(&self, symbol_id: u32) -> Result<(), Error> {
+        self.subscribe_and_wait(MarketType::Spot, symbol_id).await
+    }
+
+    /// Subscribes the spot prices of the symbols with one request.
+    ///
+    /// The subscriptions are accepted or rejected per symbol.
+    pub async fn subscribe_spot_many(&self, symbol_ids: &[u32]) -> Result<(), Error> {
+        self.subscribe_many(MarketType::Spot, symbol_ids, &SubscriptionOptions::spot())
+            .await
+    }
+
+    /// Subscribes the spot prices of the symbols with the options, e.g. only the bid prices.
+    ///
+    /// The side not subscribed is 0 in `SpotPrice`.
+    pub async fn subscribe_spot_with_options(
+        &self,
+        symbol_ids: &[u32],
+        options: &SubscriptionOptions,
+    ) -> Result<(), Error> {
+        self.subscribe_many(MarketType::Spot, symbol_ids, options)
+            .await
+    }
+
     pub async fn unsubscribe_spot(&self, symbol_id: u32) -> Result<(), Error> {
         // if let Some(RequestState::Requested) =
-        let states = self
-            .spot_req_states
-            .lock()
-            .await
-            .get(&symbol_id)
-            .map(|v| v.clone());
+        let states = self.spot_req_states.lock().await.get(&symbol_id).cloned();
 
         match states {
             Some(RequestState::Requested(_)) => {
-                return Err(Error::RequestingSubscription(symbol_id, MarketType::Spot));
+                Err(Error::RequestingSubscription(symbol_id, MarketType::Spot))
             }
             Some(RequestState::Rejected) | None => {
-                return Err(Error::NotSubscribed(symbol_id, MarketType::Spot));
+                Err(Error::NotSubscribed(symbol_id, MarketType::Spot))
             }
             _ => {
                 self.spot_req_states.lock().await.remove(&symbol_id);
                 self.spot_market_data.lock().await.remove(&symbol_id);
-                let req = MarketDataReq::new("-1".into(), '2', 1, None, &['0', '1'], 1, symbol_id);
-                let _seq_num = self.internal.send_message(req).await?;
+                let options = self.options.get(MarketType::Spot, symbol_id);
+                self.options.remove(MarketType::Spot, symbol_id);
+                let req = market_data_req("-1".into(), '2', &options, &[symbol_id]);
+                self.internal.send_message(req).await?;
 
                 log::trace!("Unsubscribed spot for symbol({})", symbol_id);
 
@@ -528,52 +1234,59 @@ impl MarketClient {
     }
 
     pub async fn subscribe_depth(&self, symbol_id: u32) -> Result<(), Error> {
-        let mdreqid = Uuid::new_v4().to_string();
-        // check already subscribed?
-        if let Some(state) = self.depth_req_states.lock().await.get(&symbol_id) {
-            match state {
-                RequestState::Accepted => {
-                    return Err(Error::SubscribedAlready(symbol_id, MarketType::Depth));
-                }
-                RequestState::Requested(_) => {
-                    return Err(Error::RequestingSubscription(symbol_id, MarketType::Depth));
-                }
-                _ => {}
-            }
-        }
+        self.subscribe_many(
+            MarketType::Depth,
+            &[symbol_id],
+            &SubscriptionOptions::depth(),
+        )
+        .await
+    }
 
-        // add to requested symbol.
-        self.depth_req_states
-            .lock()
-            .await
-            .insert(symbol_id, RequestState::Requested(mdreqid.clone()));
+    /// Subscribes the market depth of the symbol, and waits until the first full refresh arrives.
+    ///
+    /// Returns `Error::SubscriptionError` if the subscription is rejected, or `Error::TimeoutError`
+    /// after the timeout. The unanswered request is cancelled, so the symbol can be subscribed
+    /// again.
+    pub async fn subscribe_depth_and_wait(&self, symbol_id: u32) -> Result<(), Error> {
+        self.subscribe_and_wait(MarketType::Depth, symbol_id).await
+    }
 
-        // intialize the request and send req
-        let req = MarketDataReq::new(mdreqid, '1', 0, None, &['0', '1'], 1, symbol_id);
-        self.internal.send_message(req).await?;
+    /// Subscribes the market depth of the symbols with one request.
+    ///
+    /// The subscriptions are accepted or rejected per symbol.
+    pub async fn subscribe_depth_many(&self, symbol_ids: &[u32]) -> Result<(), Error> {
+        self.subscribe_many(MarketType::Depth, symbol_ids, &SubscriptionOptions::depth())
+            .await
+    }
 
-        Ok(())
+    /// Subscribes the market depth of the symbols with the options.
+    ///
+    /// With `MDUpdateType::FullRefresh`, every update is delivered as a full refresh.
+    pub async fn subscribe_depth_with_options(
+        &self,
+        symbol_ids: &[u32],
+        options: &SubscriptionOptions,
+    ) -> Result<(), Error> {
+        self.subscribe_many(MarketType::Depth, symbol_ids, options)
+            .await
     }
 
     pub async fn unsubscribe_depth(&self, symbol_id: u32) -> Result<(), Error> {
-        let states = self
-            .depth_req_states
-            .lock()
-            .await
-            .get(&symbol_id)
-            .map(|v| v.clone());
+        let states = self.depth_req_states.lock().await.get(&symbol_id).cloned();
 
         match states {
             Some(RequestState::Requested(_)) => {
-                return Err(Error::RequestingSubscription(symbol_id, MarketType::Depth));
+                Err(Error::RequestingSubscription(symbol_id, MarketType::Depth))
             }
             Some(RequestState::Rejected) | None => {
-                return Err(Error::NotSubscribed(symbol_id, MarketType::Depth));
+                Err(Error::NotSubscribed(symbol_id, MarketType::Depth))
             }
             _ => {
                 self.depth_req_states.lock().await.remove(&symbol_id);
                 self.depth_market_data.write().await.remove(&symbol_id);
-                let req = MarketDataReq::new("-1".into(), '2', 0, None, &['0', '1'], 1, symbol_id);
+                let options = self.options.get(MarketType::Depth, symbol_id);
+                self.options.remove(MarketType::Depth, symbol_id);
+                let req = market_data_req("-1".into(), '2', &options, &[symbol_id]);
                 self.internal.send_message(req).await?;
 
                 log::trace!("Unsubscribed depth for symbol({})", symbol_id);
@@ -583,3 +1296,292 @@ impl MarketClient {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        rejected_symbols, Dispatch, Dispatcher, MarketUpdate, RequestState, ServerClock,
+        SubscriptionOptionsMap,
+    };
+    use crate::{
+        bar::{GapPolicy, Timeframe},
+        order_book::OrderBook,
+        types::{
+            DepthPrice, Error, Field, IncrementalRefresh, InternalMDResult, MarketType, PriceType,
+            SpotPrice, SubscriptionOptions,
+        },
+    };
+    use async_channel::unbounded;
+    use chrono::{TimeDelta, Utc};
+    use futures::executor::block_on;
+    use std::collections::HashMap;
+
+    fn price(price: f64) -> DepthPrice {
+        DepthPrice {
+            price_type: PriceType::Bid,
+            price,
+            size: 1.0,
+        }
+    }
+
+    fn new_entry(entry_id: &str) -> IncrementalRefresh {
+        IncrementalRefresh::New {
+            symbol_id: 1,
+            entry_id: entry_id.into(),
+            data: price(1.0),
+        }
+    }
+
+    fn incremental(seq: u32, refresh: Vec<IncrementalRefresh>) -> Dispatch {
+        Dispatch::Update {
+            seq,
+            received_time: Utc::now().naive_utc(),
+            sending_time: None,
+            update: MarketUpdate::DepthIncremental { refresh },
+        }
+    }
+
+    fn dispatcher() -> Dispatcher {
+        Dispatcher {
+            spot_req_states: Default::default(),
+            depth_req_states: Default::default(),
+            spot_market_data: Default::default(),
+            depth_market_data: Default::default(),
+            spot_streams: Default::default(),
+            depth_streams: Default::default(),
+            waiters: Default::default(),
+            recorder: None,
+            bars: Default::default(),
+            bar_streams: Default::default(),
+            handler: None,
+            replay: false,
+        }
+    }
+
+    #[test]
+    fn test_dispatch_in_sequence() {
+        let dispatcher = dispatcher();
+        let depth_market_data = dispatcher.depth_market_data.clone();
+        block_on(dispatcher.depth_req_states.lock())
+            .insert(1, RequestState::Requested("req".into()));
+        let depth_req_states = dispatcher.depth_req_states.clone();
+
+        let (sender, receiver) = unbounded();
+        let full = HashMap::from([("a".to_string(), price(1.0)), ("b".to_string(), price(2.0))]);
+        for dispatch in [
+            Dispatch::Update {
+                seq: 1,
+                received_time: Utc::now().naive_utc(),
+                sending_time: None,
+                update: MarketUpdate::DepthFull {
+                    symbol_id: 1,
+                    depth: full,
+                },
+            },
+            incremental(
+                2,
+                vec![
+                    IncrementalRefresh::Delete {
+                        symbol_id: 1,
+                        entry_id: "a".into(),
+                    },
+                    new_entry("c"),
+                ],
+            ),
+            // duplicated
+            incremental(2, vec![new_entry("d")]),
+            // the sequence numbers are reset by the new session
+            Dispatch::Logon,
+            incremental(1, vec![new_entry("e")]),
+        ] {
+            sender.try_send(dispatch).ok();
+        }
+        drop(sender);
+        block_on(dispatcher.run(receiver));
+
+        assert_eq!(
+            block_on(depth_req_states.lock()).get(&1),
+            Some(&RequestState::Accepted)
+        );
+        let mut entries = block_on(depth_market_data.read())[&1]
+            .entries()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        entries.sort();
+        assert_eq!(entries, vec!["b", "c", "e"]);
+    }
+
+    #[test]
+    fn test_server_clock() {
+        let mut clock = ServerClock::default();
+        let local = Utc::now().naive_utc();
+        assert_eq!(clock.now(local), None);
+
+        // the local clock is 10 seconds ahead of the server
+        let sending_time = local - TimeDelta::seconds(10);
+        clock.on_message(sending_time, local);
+        assert_eq!(
+            clock.now(local + TimeDelta::seconds(2)),
+            Some(sending_time + TimeDelta::seconds(2))
+        );
+        assert_eq!(clock.now(local - TimeDelta::seconds(1)), Some(sending_time));
+    }
+
+    #[test]
+    fn test_close_bars_by_server_time() {
+        let dispatcher = dispatcher();
+        let bars = dispatcher.bars.clone();
+        bars.add(1, Timeframe::S1, GapPolicy::Skip);
+
+        // the local clock is 10 seconds ahead of the server
+        let local = Utc::now().naive_utc();
+        let start = Timeframe::S1.start_of(local - TimeDelta::seconds(10));
+        let spot = |millis: i64| Dispatch::Update {
+            seq: 0,
+            received_time: local,
+            sending_time: Some(start + TimeDelta::milliseconds(millis)),
+            update: MarketUpdate::Spot {
+                symbol_id: 1,
+                price: Some(SpotPrice { bid: 1.0, ask: 1.2 }),
+            },
+        };
+        let (sender, receiver) = unbounded();
+        for dispatch in [
+            spot(100),
+            // not closed by the local time
+            Dispatch::Clock(local + TimeDelta::milliseconds(500)),
+            spot(700),
+        ] {
+            sender.try_send(dispatch).ok();
+        }
+        drop(sender);
+        block_on(dispatcher.run(receiver));
+
+        let closed = bars.close_until(start + TimeDelta::seconds(1));
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].tick_count, 2);
+    }
+
+    #[test]
+    fn test_incremental_of_symbols() {
+        let dispatcher = dispatcher();
+        {
+            let mut states = block_on(dispatcher.depth_req_states.lock());
+            states.insert(1, RequestState::Accepted);
+            states.insert(2, RequestState::Accepted);
+            states.insert(3, RequestState::Requested("req".into()));
+            let mut books = block_on(dispatcher.depth_market_data.write());
+            for symbol_id in [1, 2] {
+                let entry = format!("{}-a", symbol_id);
+                books.insert(
+                    symbol_id,
+                    OrderBook::new(HashMap::from([(entry, price(1.0))])),
+                );
+            }
+        }
+
+        let entry = |symbol_id: &str, action: &str, entry_id: &str| {
+            HashMap::from([
+                (Field::Symbol, symbol_id.to_string()),
+                (Field::MDUpdateAction, action.to_string()),
+                (Field::MDEntryID, entry_id.to_string()),
+                (Field::MDEntryType, "1".to_string()),
+                (Field::MDEntryPx, "1.5".to_string()),
+                (Field::MDEntrySize, "1000".to_string()),
+            ])
+        };
+        // one message of the symbols of a batched subscription
+        let mdresult = InternalMDResult::MD {
+            seq: 1,
+            sending_time: None,
+            msg_type: 'X',
+            symbol_id: 1,
+            data: vec![
+                entry("1", "0", "1-b"),
+                entry("2", "2", "2-a"),
+                entry("2", "0", "2-b"),
+                entry("3", "0", "3-a"),
+            ],
+        };
+        let update = MarketUpdate::new(mdresult, &SubscriptionOptionsMap::default()).unwrap();
+        block_on(dispatcher.dispatch(update));
+
+        let books = block_on(dispatcher.depth_market_data.read());
+        let mut entries = books[&1].entries().keys().cloned().collect::<Vec<_>>();
+        entries.sort();
+        assert_eq!(entries, vec!["1-a", "1-b"]);
+        let entries = books[&2].entries().keys().cloned().collect::<Vec<_>>();
+        assert_eq!(entries, vec!["2-b"]);
+        // not accepted yet
+        assert!(!books.contains_key(&3));
+    }
+
+    #[test]
+    fn test_rejected_symbols() {
+        let states = HashMap::from([
+            (1, RequestState::Requested("a".into())),
+            (2, RequestState::Requested("a".into())),
+            (3, RequestState::Requested("b".into())),
+            (4, RequestState::Accepted),
+        ]);
+        assert_eq!(rejected_symbols(&states, 2, "a"), vec![2]);
+        // without the symbol
+        let mut rejected = rejected_symbols(&states, 0, "a");
+        rejected.sort();
+        assert_eq!(rejected, vec![1, 2]);
+        assert!(rejected_symbols(&states, 4, "c").is_empty());
+    }
+
+    #[test]
+    fn test_spot_of_entry_types() {
+        let spot = |data: Vec<(&str, &str)>| InternalMDResult::MD {
+            seq: 1,
+            sending_time: None,
+            msg_type: 'W',
+            symbol_id: 1,
+            data: data
+                .into_iter()
+                .map(|(entry_type, price)| {
+                    HashMap::from([
+                        (Field::MDEntryType, entry_type.to_string()),
+                        (Field::MDEntryPx, price.to_string()),
+                    ])
+                })
+                .collect(),
+        };
+        let options = SubscriptionOptionsMap::default();
+        // both sides are required by default
+        assert!(matches!(
+            MarketUpdate::new(spot(vec![("0", "1.1")]), &options),
+            Ok(MarketUpdate::Spot { price: None, .. })
+        ));
+
+        options.insert(
+            MarketType::Spot,
+            1,
+            SubscriptionOptions {
+                entry_types: vec![PriceType::Bid],
+                ..SubscriptionOptions::spot()
+            },
+        );
+        match MarketUpdate::new(spot(vec![("0", "1.1")]), &options) {
+            Ok(MarketUpdate::Spot {
+                price: Some(price), ..
+            }) => {
+                assert_eq!(price.bid, 1.1);
+                assert_eq!(price.ask, 0.0);
+            }
+            _ => panic!("unexpected update"),
+        }
+
+        // malformed price
+        assert!(matches!(
+            MarketUpdate::new(spot(vec![("0", "1.1"), ("1", "abc")]), &options),
+            Err(Error::ParseError {
+                field: Field::MDEntryPx,
+                ..
+            })
+        ));
+    }
+}
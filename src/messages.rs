@@ -1,63 +1,94 @@
-use crate::types::{Config, Field, OrderType, Side, SubID};
+use crate::types::{Config, Field, OrderType, ParseError, Side, SubID, TimeInForce};
 use chrono::Utc;
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::Range};
 
 // Response
+
+/// Received message. The values of the fields are the ranges in the raw message.
 #[derive(Debug, Clone)]
 pub struct ResponseMessage {
     message: String,
     field_idx: HashMap<u32, usize>,
-    fields: Vec<(u32, String)>,
+    fields: Vec<(u32, Range<usize>)>,
 }
 
 impl ResponseMessage {
-    pub fn new(message: &str, delimiter: &str) -> Self {
-        let message = message.replace(delimiter, "|");
+    /// Parses the message after validating the BodyLength(9) and the CheckSum(10).
+    ///
+    /// The tags unknown to `Field` are kept and can be read with `get_tag_value`.
+    pub fn new(message: &str, delimiter: &str) -> Result<Self, ParseError> {
+        Self::parse(message.to_string(), delimiter).map_err(|(_, err)| err)
+    }
+
+    /// Parses the message without copying it. The message is returned back with the error.
+    pub fn parse(message: String, delimiter: &str) -> Result<Self, (String, ParseError)> {
+        match Self::index_fields(&message, delimiter) {
+            Ok((field_idx, fields)) => Ok(Self {
+                message,
+                field_idx,
+                fields,
+            }),
+            Err(err) => Err((message, err)),
+        }
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn index_fields(
+        message: &str,
+        delimiter: &str,
+    ) -> Result<(HashMap<u32, usize>, Vec<(u32, Range<usize>)>), ParseError> {
+        validate(message, delimiter)?;
+
         let mut idx_map = HashMap::new();
-        let fields = message
-            .split("|")
-            .filter(|field| !field.is_empty() && field.contains("="))
-            .enumerate()
-            .map(|(idx, field)| {
-                let parts = field.split("=").collect::<Vec<_>>();
-                let value = &parts[1..].join("=");
-
-                let field = parts[0].parse::<u32>().unwrap();
-                if !idx_map.contains_key(&field) {
-                    idx_map.insert(parts[0].parse::<u32>().unwrap(), idx);
-                }
-                (field, value.to_string())
-            })
-            .collect::<Vec<_>>();
-        Self {
-            message,
-            field_idx: idx_map,
-            fields,
+        let mut fields = Vec::new();
+        let mut pos = 0;
+        for field in message.split(delimiter) {
+            let start = pos;
+            pos += field.len() + delimiter.len();
+            if field.is_empty() {
+                continue;
+            }
+            let (tag, value) = field
+                .split_once('=')
+                .and_then(|(tag, value)| tag.parse::<u32>().ok().map(|tag| (tag, value)))
+                .ok_or_else(|| ParseError::MalformedField(field.to_string()))?;
+            let value_start = start + field.len() - value.len();
+            idx_map.entry(tag).or_insert(fields.len());
+            fields.push((tag, value_start..start + field.len()));
+        }
+        if !idx_map.contains_key(&(Field::MsgType as u32)) {
+            return Err(ParseError::MissingField(Field::MsgType));
         }
+        Ok((idx_map, fields))
+    }
+
+    fn value(&self, idx: usize) -> &str {
+        &self.message[self.fields[idx].1.clone()]
     }
 
     pub fn matching_field_value(&self, msg_type: &str, field: Field, value: &str) -> bool {
-        (self.get_message_type() == msg_type)
-            && (self
-                .get_field_value(field)
-                .map(|v| v == value)
-                .unwrap_or(false))
+        (self.get_message_type() == msg_type) && (self.get_field_str(field) == Some(value))
     }
 
     pub fn get_field_value(&self, field: Field) -> Option<String> {
-        self.field_idx
-            .get(&(field as u32))
-            .map(|idx| self.fields[*idx].1.clone())
-        // self.fields.get(&(field as u32)).map(|v| v.clone())
+        self.get_field_str(field).map(|v| v.to_string())
+    }
+
+    /// Returns the value of the field without allocation.
+    pub fn get_field_str(&self, field: Field) -> Option<&str> {
+        self.get_tag_value(field as u32)
+    }
+
+    /// Returns the value of the tag including the tags not defined in `Field`.
+    pub fn get_tag_value(&self, tag: u32) -> Option<&str> {
+        self.field_idx.get(&tag).map(|idx| self.value(*idx))
     }
 
     pub fn get_message_type(&self) -> &str {
-        self.field_idx
-            .get(&(Field::MsgType as u32))
-            .map(|idx| &self.fields[*idx].1)
-            .unwrap()
+        self.get_field_str(Field::MsgType).unwrap()
     }
 
+    /// Returns the raw message with the delimiter as received.
     pub fn get_message(&self) -> &str {
         &self.message
     }
@@ -75,27 +106,30 @@ impl ResponseMessage {
         let mut result = vec![];
         let mut item = HashMap::new();
         // located more than 8
-        if let Some(start_idx) = self.field_idx.get(&(count_key as u32)) {
-            for (k, v) in &self.fields[*start_idx..] {
+        if let Some(start_idx) = self.field_idx.get(&count_key) {
+            for (k, range) in &self.fields[*start_idx..] {
+                let v = &self.message[range.clone()];
                 match count {
                     Some(0) => {
                         return result;
                     }
                     None => {
-                        if *k == count_key as u32 {
-                            count = Some(v.parse::<usize>().unwrap());
+                        if *k == count_key {
+                            count = Some(v.parse::<usize>().unwrap_or(0));
                         }
                         continue;
                     }
                     _ => {
-                        let key = Field::try_from(*k)
-                            .expect("Failed to parse u32 to Field in get_repeating_groups");
+                        let Ok(key) = Field::try_from(*k) else {
+                            log::trace!("Unknown tag({}) in the repeating group", k);
+                            continue;
+                        };
 
                         match end_field {
                             Some(end_key) => {
                                 // exclude the checksum
                                 if key != Field::CheckSum {
-                                    item.insert(key, v.clone());
+                                    item.insert(key, v.to_string());
                                 }
 
                                 if key == end_key {
@@ -112,7 +146,7 @@ impl ResponseMessage {
                                 }
 
                                 if key != Field::CheckSum {
-                                    item.insert(key, v.clone());
+                                    item.insert(key, v.to_string());
                                 }
                             }
                         }
@@ -120,7 +154,7 @@ impl ResponseMessage {
                         //     || (end_field.is_none() && key == start_field && !item.is_empty())
                         // {
                         // }
-                        // item.insert(key, v.clone());
+                        // item.insert(key, v.to_string());
                     }
                 }
             }
@@ -133,6 +167,114 @@ impl ResponseMessage {
 fn format_field<T: std::fmt::Display>(field: Field, value: T) -> String {
     format!("{}={}", field as u32, value)
 }
+
+fn validate(message: &str, delimiter: &str) -> Result<(), ParseError> {
+    let mut parts = message.splitn(3, delimiter);
+    if !parts.next().is_some_and(|field| field.starts_with("8=")) {
+        return Err(ParseError::MissingField(Field::BeginString));
+    }
+    let body_length = parts
+        .next()
+        .and_then(|field| field.strip_prefix("9="))
+        .ok_or(ParseError::MissingField(Field::BodyLength))?;
+    let expected_length = body_length
+        .parse::<usize>()
+        .map_err(|_| ParseError::InvalidValue(body_length.to_string()))?;
+    let body = parts
+        .next()
+        .ok_or(ParseError::MissingField(Field::MsgType))?;
+    let body_start = message.len() - body.len();
+
+    let trailer_start = message
+        .rfind(&format!("{}10=", delimiter))
+        .map(|pos| pos + delimiter.len())
+        .filter(|pos| *pos >= body_start)
+        .ok_or(ParseError::MissingField(Field::CheckSum))?;
+    let actual_length = trailer_start - body_start;
+    if expected_length != actual_length {
+        return Err(ParseError::BodyLengthMismatch {
+            expected: expected_length,
+            actual: actual_length,
+        });
+    }
+
+    let trailer = &message[trailer_start + 3..];
+    let value = trailer.strip_suffix(delimiter).unwrap_or(trailer);
+    let expected_checksum = value
+        .parse::<u32>()
+        .map_err(|_| ParseError::InvalidValue(value.to_string()))?;
+    let actual_checksum = checksum(&message[..trailer_start]);
+    if expected_checksum != actual_checksum {
+        return Err(ParseError::CheckSumMismatch {
+            expected: expected_checksum,
+            actual: actual_checksum,
+        });
+    }
+    Ok(())
+}
+
+/// Wraps the fields (from MsgType) with the BeginString, BodyLength and CheckSum for the tests
+/// and the mock server.
+#[cfg(any(test, feature = "testing"))]
+pub(crate) fn raw_message(body: &str, delimiter: &str) -> String {
+    let header_and_body = format!(
+        "8=FIX.4.4{}9={}{}{}",
+        delimiter,
+        body.len(),
+        delimiter,
+        body
+    );
+    let checksum = checksum(&header_and_body);
+    format!("{}10={:03}{}", header_and_body, checksum, delimiter)
+}
+
+fn checksum(header_and_body: &str) -> u32 {
+    header_and_body
+        .as_bytes()
+        .iter()
+        .map(|byte| *byte as u32)
+        .sum::<u32>()
+        % 256
+}
+
+/// Rebuilds the sent message to resend it with PossDupFlag(43) and OrigSendingTime(122).
+///
+/// The SendingTime(52) is updated, and the BodyLength(9) and CheckSum(10) are recalculated.
+pub fn to_poss_dup(message: &str, delimiter: &str) -> String {
+    let poss_dup = format!("{}=", Field::PossDupFlag as u32);
+    let sending_time = format!("{}=", Field::SendingTime as u32);
+    let mut fields = Vec::new();
+    for field in message.split(delimiter).filter(|field| !field.is_empty()) {
+        if field.starts_with("8=") || field.starts_with("9=") || field.starts_with("10=") {
+            continue;
+        }
+        if field.starts_with(&poss_dup) {
+            continue;
+        }
+        if let Some(orig) = field.strip_prefix(&sending_time) {
+            fields.push(format_field(
+                Field::SendingTime,
+                Utc::now().format("%Y%m%d-%H:%M:%S"),
+            ));
+            fields.push(format_field(Field::PossDupFlag, "Y"));
+            fields.push(format_field(Field::OrigSendingTime, orig));
+            continue;
+        }
+        fields.push(field.to_string());
+    }
+
+    let body = fields.join(delimiter);
+    let header_and_body = format!(
+        "8=FIX.4.4{}9={}{}{}{}",
+        delimiter,
+        body.len() + delimiter.len(),
+        delimiter,
+        body,
+        delimiter
+    );
+    let checksum = checksum(&header_and_body);
+    format!("{}10={:03}{}", header_and_body, checksum, delimiter)
+}
 // Request
 
 // motivated from the cTraderFixPy .
@@ -147,7 +289,9 @@ pub trait RequestMessage: Send {
         let body = self.get_body(delimiter, config);
         let header = self.get_header(
             sub_id,
-            body.as_ref().map(|s| s.len()).unwrap_or(0),
+            body.as_ref()
+                .map(|s| s.len() + delimiter.len())
+                .unwrap_or(0),
             sequence_number,
             delimiter,
             config,
@@ -168,7 +312,7 @@ pub trait RequestMessage: Send {
         delimiter: &str,
         config: &Config,
     ) -> String {
-        let fields = vec![
+        let fields = [
             format_field(Field::MsgType, self.get_message_type()),
             format_field(Field::SenderCompID, &config.sender_comp_id),
             format_field(Field::TargetCompID, "CSERVER"),
@@ -181,16 +325,14 @@ pub trait RequestMessage: Send {
         format!(
             "8=FIX.4.4{}9={}{}{}",
             delimiter,
-            len_body + fields_joined.len() + 2,
+            len_body + fields_joined.len() + delimiter.len(),
             delimiter,
             fields_joined
         )
     }
 
     fn get_trailer(&self, header_and_body: &str) -> String {
-        let message_bytes = header_and_body.as_bytes();
-        let checksum = message_bytes.iter().map(|byte| *byte as u32).sum::<u32>() % 256;
-        format!("10={:03}", checksum)
+        format!("10={:03}", checksum(header_and_body))
     }
 
     fn get_body(&self, delimiter: &str, config: &Config) -> Option<String>;
@@ -222,11 +364,8 @@ impl RequestMessage for LogonReq {
             format_field(Field::Password, &config.password),
         ];
 
-        match self.reset_seq_num {
-            Some(true) => {
-                fields.push("141=Y".to_string());
-            } // Field::ResetSeqNumFlag
-            _ => {}
+        if let Some(true) = self.reset_seq_num {
+            fields.push("141=Y".to_string()); // Field::ResetSeqNumFlag
         }
 
         Some(fields.join(delimiter))
@@ -238,11 +377,21 @@ impl RequestMessage for LogonReq {
 }
 
 #[derive(Debug, Clone, Default)]
-pub struct LogoutReq;
+pub struct LogoutReq {
+    text: Option<String>,
+}
+
+impl LogoutReq {
+    pub fn new(text: Option<String>) -> Self {
+        Self { text }
+    }
+}
 
 impl RequestMessage for LogoutReq {
     fn get_body(&self, _delimiter: &str, _config: &Config) -> Option<String> {
-        None
+        self.text
+            .as_ref()
+            .map(|text| format_field(Field::Text, text))
     }
 
     fn get_message_type(&self) -> &str {
@@ -311,7 +460,7 @@ impl ResendReq {
 
 impl RequestMessage for ResendReq {
     fn get_body(&self, delimiter: &str, _config: &Config) -> Option<String> {
-        let fields = vec![
+        let fields = [
             format_field(Field::BeginSeqNo, self.begin_seq_no),
             format_field(Field::EndSeqNo, self.end_seq_no),
         ];
@@ -364,8 +513,7 @@ pub struct MarketDataReq {
     md_update_type: Option<u32>,
     no_md_entry_types: u32,
     md_entry_type: Vec<char>,
-    no_related_sym: u32,
-    symbol: u32,
+    symbols: Vec<u32>,
 }
 
 impl MarketDataReq {
@@ -375,8 +523,7 @@ impl MarketDataReq {
         market_depth: u32,
         md_update_type: Option<u32>,
         md_entry_type: &[char],
-        no_related_sym: u32,
-        symbol: u32,
+        symbols: &[u32],
     ) -> Self {
         Self {
             md_req_id,
@@ -385,8 +532,7 @@ impl MarketDataReq {
             md_update_type,
             no_md_entry_types: md_entry_type.len() as u32,
             md_entry_type: md_entry_type.into(),
-            no_related_sym,
-            symbol,
+            symbols: symbols.into(),
         }
     }
 }
@@ -404,10 +550,10 @@ impl RequestMessage for MarketDataReq {
             .iter()
             .for_each(|c| fields.push(format_field(Field::MDEntryType, c)));
 
-        fields.extend([
-            format_field(Field::NoRelatedSym, self.no_related_sym),
-            format_field(Field::Symbol, &self.symbol),
-        ]);
+        fields.push(format_field(Field::NoRelatedSym, self.symbols.len()));
+        self.symbols
+            .iter()
+            .for_each(|symbol| fields.push(format_field(Field::Symbol, symbol)));
 
         if let Some(md_update_type) = self.md_update_type {
             fields.push(format_field(Field::MDUpdateType, md_update_type));
@@ -432,11 +578,13 @@ pub struct NewOrderSingleReq {
     pub price: Option<f64>,
     pub stop_px: Option<f64>,
     pub expire_time: Option<chrono::NaiveDateTime>,
+    pub time_in_force: Option<TimeInForce>,
     pub pos_maint_rpt_id: Option<String>,
     pub designation: Option<String>,
 }
 
 impl NewOrderSingleReq {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         cl_ord_id: String,
         symbol: u32,
@@ -447,6 +595,7 @@ impl NewOrderSingleReq {
         price: Option<f64>,
         stop_px: Option<f64>,
         expire_time: Option<chrono::NaiveDateTime>,
+        time_in_force: Option<TimeInForce>,
         pos_maint_rpt_id: Option<String>,
         designation: Option<String>,
     ) -> Self {
@@ -460,6 +609,7 @@ impl NewOrderSingleReq {
             price,
             stop_px,
             expire_time,
+            time_in_force,
             pos_maint_rpt_id,
             designation,
         }
@@ -470,7 +620,7 @@ impl RequestMessage for NewOrderSingleReq {
     fn get_body(&self, delimiter: &str, _config: &Config) -> Option<String> {
         let mut fields = vec![
             format_field(Field::ClOrdId, &self.cl_ord_id),
-            format_field(Field::Symbol, &self.symbol),
+            format_field(Field::Symbol, self.symbol),
             format_field(Field::Side, self.side as u32),
             format_field(
                 Field::TransactTime,
@@ -495,6 +645,9 @@ impl RequestMessage for NewOrderSingleReq {
                 expire_time.format("%Y%m%d-%H:%M:%S"),
             ));
         }
+        if let Some(time_in_force) = self.time_in_force {
+            fields.push(format_field(Field::TimeInForce, time_in_force as u32));
+        }
         if let Some(pos_maint_rpt_id) = &self.pos_maint_rpt_id {
             fields.push(format_field(Field::PosMaintRptID, pos_maint_rpt_id));
         }
@@ -753,12 +906,81 @@ impl RequestMessage for SecurityListReq {
 
 #[cfg(test)]
 mod tests {
-    use super::ResponseMessage;
-    use crate::types::{Field, DELIMITER};
+    use super::{raw_message, RequestMessage, ResponseMessage};
+    use crate::types::{Field, ParseError, SubID, DELIMITER};
+
+    #[test]
+    fn test_build_and_parse() {
+        let config = crate::types::Config::new(
+            "localhost".into(),
+            "user".into(),
+            "pass".into(),
+            "sender".into(),
+            30,
+        );
+        // without body
+        let msg = super::HeartbeatReq::new(None).build(SubID::QUOTE, 1, "|", &config);
+        assert!(ResponseMessage::new(&msg, "|").is_ok());
+        // with body
+        let msg = super::TestReq::new("test".into()).build(SubID::QUOTE, 2, "|", &config);
+        assert!(ResponseMessage::new(&msg, "|").is_ok());
+    }
+
+    #[test]
+    fn test_parse_validation() {
+        let res = raw_message("35=0|34=2|49=CSERVER|9999=custom|", "|");
+        let msg = ResponseMessage::new(&res, "|").unwrap();
+        assert_eq!(msg.get_message_type(), "0");
+        // unknown tag is kept
+        assert_eq!(msg.get_tag_value(9999), Some("custom"));
+
+        let invalid_length = res.replacen("9=", "9=1", 1);
+        assert!(matches!(
+            ResponseMessage::new(&invalid_length, "|"),
+            Err(ParseError::BodyLengthMismatch { .. })
+        ));
+
+        let invalid_checksum = res.replace("custom", "cust0m");
+        assert!(matches!(
+            ResponseMessage::new(&invalid_checksum, "|"),
+            Err(ParseError::CheckSumMismatch { .. })
+        ));
+
+        let malformed = raw_message("35=0|34=2|abc=1|", "|");
+        assert_eq!(
+            ResponseMessage::new(&malformed, "|").unwrap_err(),
+            ParseError::MalformedField("abc=1".into())
+        );
+
+        let no_msg_type = raw_message("34=2|", "|");
+        assert_eq!(
+            ResponseMessage::new(&no_msg_type, "|").unwrap_err(),
+            ParseError::MissingField(Field::MsgType)
+        );
+
+        assert!(ResponseMessage::new("garbage", "|").is_err());
+        assert!(ResponseMessage::new("8=FIX.4.4|9=5|35=0|", "|").is_err());
+    }
+    #[test]
+    fn test_market_data_req_symbols() {
+        let config = crate::types::Config::new(
+            "localhost".into(),
+            "user".into(),
+            "pass".into(),
+            "sender".into(),
+            30,
+        );
+        let req = super::MarketDataReq::new("1".into(), '1', 1, None, &['0', '1'], &[1, 2, 3]);
+        assert_eq!(
+            req.get_body("|", &config).unwrap(),
+            "262=1|263=1|264=1|267=2|269=0|269=1|146=3|55=1|55=2|55=3"
+        );
+    }
+
     #[test]
     fn test_parse_repeating_group_spot_market() {
-        let res = "8=FIX.4.4|9=134|35=W|34=2|49=CSERVER|50=QUOTE|52=20170117-10:26:54.630|56=live.theBroker.12345|57=any_string|55=1|268=2|269=0|270=1.06625|269=1|270=1.0663|10=118|".to_string().replace("|", DELIMITER);
-        let msg = ResponseMessage::new(&res, DELIMITER);
+        let res = "8=FIX.4.4|9=139|35=W|34=2|49=CSERVER|50=QUOTE|52=20170117-10:26:54.630|56=live.theBroker.12345|57=any_string|55=1|268=2|269=0|270=1.06625|269=1|270=1.0663|10=089|".to_string().replace("|", DELIMITER);
+        let msg = ResponseMessage::new(&res, DELIMITER).unwrap();
         let result = msg.get_repeating_groups(
             Field::NoMDEntries,
             Field::MDEntryType,
@@ -777,8 +999,8 @@ mod tests {
 
     #[test]
     fn test_parse_repeating_group_depth_market() {
-        let res = "8=FIX.4.4|9=310|35=W|34=2|49=CSERVER|50=QUOTE|52=20180925-12:05:28.284|56=live.theBroker.12345|57=Quote|55=1|268=6|269=1|270=1.11132|271=3000000|278=16|269=1|270=1.11134|271=5000000|278=17|269=1|270=1.11133|271=3000000|278=15|269=0|270=1.1112|271=2000000|278=12|269=0|270=1.11121|271=1000000|278=13|269=0|270=1.11122|271=3000000|278=14|10=247|".to_string().replace("|", DELIMITER);
-        let msg = ResponseMessage::new(&res, DELIMITER);
+        let res = "8=FIX.4.4|9=320|35=W|34=2|49=CSERVER|50=QUOTE|52=20180925-12:05:28.284|56=live.theBroker.12345|57=Quote|55=1|268=6|269=1|270=1.11132|271=3000000|278=16|269=1|270=1.11134|271=5000000|278=17|269=1|270=1.11133|271=3000000|278=15|269=0|270=1.1112|271=2000000|278=12|269=0|270=1.11121|271=1000000|278=13|269=0|270=1.11122|271=3000000|278=14|10=175|".to_string().replace("|", DELIMITER);
+        let msg = ResponseMessage::new(&res, DELIMITER).unwrap();
         let result = msg.get_repeating_groups(Field::NoMDEntries, Field::MDEntryType, None);
 
         assert_eq!(6, result.len());
@@ -792,9 +1014,8 @@ mod tests {
     }
     #[test]
     fn test_parse_repeating_group_market_incre() {
-        let res = "8=FIX.4.4|9=376|35=X|34=3|49=CSERVER|50=QUOTE|52=20170117-11:13:44.555|56=live.theBroker.12345|57=any_string|268=8|279=0|269=0|278=7491|55=1|270=1.06897|271=1000000|279=0|269=0|278=7490|55=1|270=1.06898|271=1000000|279=0|269=0|278=7489|55=1|270=1.06874|271=32373000|279=0|269=1|278=7496|55=1|270=1.06931|271=34580000|279=2|278=7477|55=1|279=2|278=7468|55=1|279=2|278=7467|55=1|279=2|278=7484|55=1|10=192|
-".to_string().replace("|", DELIMITER);
-        let msg = ResponseMessage::new(&res, DELIMITER);
+        let res = "8=FIX.4.4|9=381|35=X|34=3|49=CSERVER|50=QUOTE|52=20170117-11:13:44.555|56=live.theBroker.12345|57=any_string|268=8|279=0|269=0|278=7491|55=1|270=1.06897|271=1000000|279=0|269=0|278=7490|55=1|270=1.06898|271=1000000|279=0|269=0|278=7489|55=1|270=1.06874|271=32373000|279=0|269=1|278=7496|55=1|270=1.06931|271=34580000|279=2|278=7477|55=1|279=2|278=7468|55=1|279=2|278=7467|55=1|279=2|278=7484|55=1|10=154|".to_string().replace("|", DELIMITER);
+        let msg = ResponseMessage::new(&res, DELIMITER).unwrap();
         let result = msg.get_repeating_groups(Field::NoMDEntries, Field::MDUpdateAction, None);
 
         assert_eq!(8, result.len());
@@ -814,7 +1035,7 @@ mod tests {
                     assert!(group.contains_key(&Field::MDEntryID));
                 }
                 _ => {
-                    assert!(false);
+                    unreachable!();
                 }
             }
         }
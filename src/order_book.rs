@@ -0,0 +1,303 @@
+//! Price-sorted order book of the depth subscription.
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+};
+
+use crate::types::{DepthPrice, IncrementalRefresh, PriceType};
+
+/// Sum of the sizes of the entries at a price.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PriceLevel {
+    pub price: f64,
+    pub size: f64,
+}
+
+/// Price ordered by `f64::total_cmp` to key the levels.
+#[derive(Debug, Clone, Copy)]
+struct OrderedPrice(f64);
+
+impl PartialEq for OrderedPrice {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for OrderedPrice {}
+
+impl PartialOrd for OrderedPrice {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OrderedPrice {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Level {
+    size: f64,
+    // removed with the last entry, not by the size, which can be left with the rounding error
+    entries: usize,
+}
+
+/// Order book maintained from the full and incremental refreshes of the market depth.
+///
+/// The entries of the same price are merged into one level. The bids are sorted from the highest
+/// price and the asks from the lowest price. An incremental refresh updates only the levels of
+/// its entries.
+#[derive(Debug, Clone, Default)]
+pub struct OrderBook {
+    entries: HashMap<String, DepthPrice>,
+    bids: BTreeMap<OrderedPrice, Level>,
+    asks: BTreeMap<OrderedPrice, Level>,
+}
+
+impl OrderBook {
+    /// Creates the order book from the entries of the full refresh, keyed by MDEntryID.
+    pub fn new(entries: HashMap<String, DepthPrice>) -> Self {
+        let mut book = Self::default();
+        for entry in entries.values() {
+            book.add_level(entry);
+        }
+        book.entries = entries;
+        book
+    }
+
+    /// Applies the incremental refresh of the symbol of the book.
+    pub fn apply(&mut self, refresh: &[IncrementalRefresh]) {
+        for incre in refresh {
+            let removed = match incre {
+                IncrementalRefresh::New { entry_id, data, .. } => {
+                    self.add_level(data);
+                    self.entries.insert(entry_id.clone(), data.clone())
+                }
+                IncrementalRefresh::Delete { entry_id, .. } => self.entries.remove(entry_id),
+            };
+            if let Some(removed) = removed {
+                self.remove_level(&removed);
+            }
+        }
+    }
+
+    fn side_mut(&mut self, price_type: PriceType) -> &mut BTreeMap<OrderedPrice, Level> {
+        match price_type {
+            PriceType::Bid => &mut self.bids,
+            PriceType::Ask => &mut self.asks,
+        }
+    }
+
+    fn add_level(&mut self, entry: &DepthPrice) {
+        let level = self
+            .side_mut(entry.price_type)
+            .entry(OrderedPrice(entry.price))
+            .or_default();
+        level.size += entry.size;
+        level.entries += 1;
+    }
+
+    fn remove_level(&mut self, entry: &DepthPrice) {
+        let side = self.side_mut(entry.price_type);
+        let price = OrderedPrice(entry.price);
+        if let Some(level) = side.get_mut(&price) {
+            level.size -= entry.size;
+            level.entries -= 1;
+            if level.entries == 0 {
+                side.remove(&price);
+            }
+        }
+    }
+
+    /// Entries keyed by MDEntryID.
+    pub fn entries(&self) -> &HashMap<String, DepthPrice> {
+        &self.entries
+    }
+
+    /// Bid levels from the highest price.
+    pub fn bids(&self) -> impl Iterator<Item = PriceLevel> + '_ {
+        self.bids.iter().rev().map(to_price_level)
+    }
+
+    /// Ask levels from the lowest price.
+    pub fn asks(&self) -> impl Iterator<Item = PriceLevel> + '_ {
+        self.asks.iter().map(to_price_level)
+    }
+
+    /// Levels from the best price.
+    pub fn levels(&self, price_type: PriceType) -> Box<dyn Iterator<Item = PriceLevel> + '_> {
+        match price_type {
+            PriceType::Bid => Box::new(self.bids()),
+            PriceType::Ask => Box::new(self.asks()),
+        }
+    }
+
+    pub fn best_bid(&self) -> Option<PriceLevel> {
+        self.bids().next()
+    }
+
+    pub fn best_ask(&self) -> Option<PriceLevel> {
+        self.asks().next()
+    }
+
+    pub fn mid(&self) -> Option<f64> {
+        Some((self.best_bid()?.price + self.best_ask()?.price) / 2.0)
+    }
+
+    pub fn spread(&self) -> Option<f64> {
+        Some(self.best_ask()?.price - self.best_bid()?.price)
+    }
+
+    /// Size at the price, or 0 if there is no level at the price.
+    pub fn size_at(&self, price_type: PriceType, price: f64) -> f64 {
+        let side = match price_type {
+            PriceType::Bid => &self.bids,
+            PriceType::Ask => &self.asks,
+        };
+        side.get(&OrderedPrice(price))
+            .map(|level| level.size)
+            .unwrap_or(0.0)
+    }
+
+    /// Sum of the sizes of the best `levels` levels.
+    pub fn cumulative_depth(&self, price_type: PriceType, levels: usize) -> f64 {
+        self.levels(price_type)
+            .take(levels)
+            .map(|level| level.size)
+            .sum()
+    }
+
+    /// Volume weighted average price to fill the volume against the levels, from the best price.
+    ///
+    /// For a buy, pass `PriceType::Ask`. Returns `None` if the volume is not positive or the book
+    /// does not have enough size.
+    pub fn vwap(&self, price_type: PriceType, volume: f64) -> Option<f64> {
+        if volume <= 0.0 {
+            return None;
+        }
+        let mut remaining = volume;
+        let mut notional = 0.0;
+        for level in self.levels(price_type) {
+            let size = level.size.min(remaining);
+            notional += size * level.price;
+            remaining -= size;
+            if remaining <= 0.0 {
+                return Some(notional / volume);
+            }
+        }
+        None
+    }
+}
+
+fn to_price_level((price, level): (&OrderedPrice, &Level)) -> PriceLevel {
+    PriceLevel {
+        price: price.0,
+        size: level.size,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OrderBook, PriceLevel};
+    use crate::types::{DepthPrice, IncrementalRefresh, PriceType};
+    use std::collections::HashMap;
+
+    fn entry(price_type: PriceType, price: f64, size: f64) -> DepthPrice {
+        DepthPrice {
+            price_type,
+            price,
+            size,
+        }
+    }
+
+    fn book() -> OrderBook {
+        OrderBook::new(HashMap::from([
+            ("1".into(), entry(PriceType::Bid, 1.0, 100.0)),
+            ("2".into(), entry(PriceType::Bid, 1.5, 200.0)),
+            ("3".into(), entry(PriceType::Bid, 1.5, 50.0)),
+            ("4".into(), entry(PriceType::Ask, 3.0, 100.0)),
+            ("5".into(), entry(PriceType::Ask, 2.0, 100.0)),
+        ]))
+    }
+
+    #[test]
+    fn test_sorted_levels() {
+        let book = book();
+        assert_eq!(
+            book.bids().collect::<Vec<_>>(),
+            vec![
+                PriceLevel {
+                    price: 1.5,
+                    size: 250.0
+                },
+                PriceLevel {
+                    price: 1.0,
+                    size: 100.0
+                }
+            ]
+        );
+        assert_eq!(book.best_ask().unwrap().price, 2.0);
+        assert_eq!(book.mid(), Some(1.75));
+        assert_eq!(book.spread(), Some(0.5));
+        assert_eq!(book.size_at(PriceType::Bid, 1.5), 250.0);
+        assert_eq!(book.size_at(PriceType::Ask, 1.5), 0.0);
+        assert_eq!(book.cumulative_depth(PriceType::Bid, 1), 250.0);
+        assert_eq!(book.cumulative_depth(PriceType::Ask, 5), 200.0);
+    }
+
+    #[test]
+    fn test_vwap() {
+        let book = book();
+        assert_eq!(book.vwap(PriceType::Ask, 50.0), Some(2.0));
+        assert_eq!(book.vwap(PriceType::Ask, 200.0), Some(2.5));
+        assert_eq!(book.vwap(PriceType::Ask, 201.0), None);
+        assert_eq!(book.vwap(PriceType::Bid, 0.0), None);
+    }
+
+    #[test]
+    fn test_incremental_refresh() {
+        let mut book = book();
+        book.apply(&[
+            IncrementalRefresh::Delete {
+                symbol_id: 1,
+                entry_id: "5".into(),
+            },
+            IncrementalRefresh::New {
+                symbol_id: 1,
+                entry_id: "6".into(),
+                data: entry(PriceType::Ask, 2.5, 10.0),
+            },
+        ]);
+        assert_eq!(book.entries().len(), 5);
+        assert_eq!(book.best_ask().unwrap().price, 2.5);
+        assert_eq!(book.asks().count(), 2);
+
+        // replaced entry moves to the new level, and the emptied level is removed
+        book.apply(&[IncrementalRefresh::New {
+            symbol_id: 1,
+            entry_id: "6".into(),
+            data: entry(PriceType::Ask, 3.0, 20.0),
+        }]);
+        assert_eq!(book.entries().len(), 5);
+        assert_eq!(
+            book.asks().collect::<Vec<_>>(),
+            vec![PriceLevel {
+                price: 3.0,
+                size: 120.0
+            }]
+        );
+        book.apply(&[IncrementalRefresh::Delete {
+            symbol_id: 1,
+            entry_id: "3".into(),
+        }]);
+        assert_eq!(book.size_at(PriceType::Bid, 1.5), 200.0);
+        assert_eq!(book.bids().count(), 2);
+
+        let book = OrderBook::default();
+        assert_eq!(book.best_bid(), None);
+        assert_eq!(book.mid(), None);
+    }
+}
@@ -0,0 +1,290 @@
+//! State of the orders kept from the execution reports.
+
+use std::{collections::HashMap, sync::RwLock};
+
+use crate::types::{
+    Error, ExecutionReport, ExecutionType, OrderMassStatus, OrderReport, OrderStatus,
+};
+
+/// Orders of the account, keyed by the OrderID(37).
+///
+/// Set it to `TradeClient::set_order_manager` to seed it from the order mass status at every
+/// logon and to apply the execution reports. The filled, canceled, expired and rejected orders are
+/// kept until `clear`.
+#[derive(Debug, Default)]
+pub struct OrderManager {
+    orders: RwLock<HashMap<String, OrderReport>>,
+}
+
+/// Order statuses of the execution type.
+fn expected_statuses(exec_type: ExecutionType) -> &'static [OrderStatus] {
+    match exec_type {
+        ExecutionType::New => &[OrderStatus::New],
+        ExecutionType::Replace => &[OrderStatus::New, OrderStatus::ParitallyFilled],
+        ExecutionType::Trade => &[OrderStatus::ParitallyFilled, OrderStatus::Filled],
+        ExecutionType::Canceled => &[OrderStatus::Cancelled],
+        ExecutionType::Expired => &[OrderStatus::Expired],
+        ExecutionType::Rejected => &[OrderStatus::Rejected],
+        ExecutionType::OrderStatus => &[
+            OrderStatus::New,
+            OrderStatus::ParitallyFilled,
+            OrderStatus::Filled,
+            OrderStatus::Rejected,
+            OrderStatus::Cancelled,
+            OrderStatus::Expired,
+        ],
+    }
+}
+
+fn check_transition(from: Option<OrderStatus>, report: &ExecutionReport) -> Result<(), Error> {
+    let to = report.order_report.order_status;
+    let legal = expected_statuses(report.exec_type).contains(&to)
+        && match from {
+            None | Some(OrderStatus::New) => true,
+            Some(OrderStatus::ParitallyFilled) => to != OrderStatus::New,
+            // no more changes after the terminal statuses
+            Some(from) => from == to,
+        };
+    if legal {
+        Ok(())
+    } else {
+        Err(Error::IllegalOrderTransition {
+            order_id: report.order_report.order_id.clone(),
+            from,
+            to,
+            exec_type: report.exec_type,
+        })
+    }
+}
+
+impl OrderManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the open orders with the reports of the order mass status.
+    ///
+    /// The open orders not in the reports are removed, since they are closed while the session
+    /// was down. If some reports failed to parse, the status is incomplete and the open orders are
+    /// kept, updated by the reports parsed.
+    pub fn seed(&self, status: &OrderMassStatus) {
+        let mut orders = self.orders.write().unwrap();
+        if status.failures.is_empty() {
+            orders.retain(|_, order| !order.order_status.is_open());
+        }
+        for report in status.reports.iter() {
+            orders.insert(
+                report.order_report.order_id.clone(),
+                report.order_report.clone(),
+            );
+        }
+    }
+
+    /// Applies the execution report to the order.
+    ///
+    /// Returns `Error::IllegalOrderTransition` without changing the order if the status does not
+    /// match the execution type, or the order is already closed.
+    pub fn apply(&self, report: &ExecutionReport) -> Result<(), Error> {
+        let mut orders = self.orders.write().unwrap();
+        let order_id = &report.order_report.order_id;
+        check_transition(orders.get(order_id).map(|v| v.order_status), report)?;
+        orders.insert(order_id.clone(), report.order_report.clone());
+        Ok(())
+    }
+
+    pub fn order(&self, order_id: &str) -> Option<OrderReport> {
+        self.orders.read().unwrap().get(order_id).cloned()
+    }
+
+    pub fn order_by_cl_ord_id(&self, cl_ord_id: &str) -> Option<OrderReport> {
+        self.orders
+            .read()
+            .unwrap()
+            .values()
+            .find(|order| order.cl_ord_id == cl_ord_id)
+            .cloned()
+    }
+
+    /// New and partially filled orders.
+    pub fn open_orders(&self) -> Vec<OrderReport> {
+        self.orders
+            .read()
+            .unwrap()
+            .values()
+            .filter(|order| order.order_status.is_open())
+            .cloned()
+            .collect()
+    }
+
+    /// Orders of the symbol, including the closed ones.
+    pub fn orders_for_symbol(&self, symbol: u32) -> Vec<OrderReport> {
+        self.orders
+            .read()
+            .unwrap()
+            .values()
+            .filter(|order| order.symbol == symbol)
+            .cloned()
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.orders.write().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OrderManager;
+    use crate::types::{
+        Error, ExecutionReport, ExecutionType, Field, OrderMassStatus, OrderReport, OrderStatus,
+        OrderType, Side, TimeInForce,
+    };
+    use chrono::Utc;
+
+    fn report(
+        order_id: &str,
+        symbol: u32,
+        exec_type: ExecutionType,
+        order_status: OrderStatus,
+    ) -> ExecutionReport {
+        ExecutionReport {
+            exec_type,
+            order_report: OrderReport {
+                symbol,
+                order_id: order_id.into(),
+                cl_ord_id: format!("cl-{}", order_id),
+                exec_id: None,
+                pos_main_rept_id: Some("1".into()),
+                designation: None,
+                order_status,
+                order_type: OrderType::Limit,
+                side: Side::BUY,
+                price: Some(1.1),
+                stop_px: None,
+                avx_px: None,
+                absolute_tp: None,
+                reltative_tp: None,
+                absolute_sl: None,
+                reltative_sl: None,
+                trailing_sl: None,
+                trigger_method_sl: None,
+                guaranteed_sl: None,
+                cum_qty: None,
+                order_qty: 1000.0,
+                leaves_qty: 1000.0,
+                last_qty: None,
+                last_px: None,
+                time_in_force: Some(TimeInForce::GoodTillCancel),
+                transact_time: Utc::now().naive_utc(),
+                expire_time: None,
+                text: None,
+                ord_rej_reason: None,
+                mass_status_req_id: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let manager = OrderManager::new();
+        manager
+            .apply(&report("1", 1, ExecutionType::New, OrderStatus::New))
+            .unwrap();
+        manager
+            .apply(&report("2", 2, ExecutionType::New, OrderStatus::New))
+            .unwrap();
+        manager
+            .apply(&report(
+                "1",
+                1,
+                ExecutionType::Trade,
+                OrderStatus::ParitallyFilled,
+            ))
+            .unwrap();
+        assert_eq!(manager.open_orders().len(), 2);
+
+        manager
+            .apply(&report(
+                "2",
+                2,
+                ExecutionType::Canceled,
+                OrderStatus::Cancelled,
+            ))
+            .unwrap();
+        assert_eq!(manager.open_orders().len(), 1);
+        assert_eq!(
+            manager.order_by_cl_ord_id("cl-1").unwrap().order_status,
+            OrderStatus::ParitallyFilled
+        );
+        assert_eq!(manager.orders_for_symbol(2).len(), 1);
+        assert!(manager.order("3").is_none());
+    }
+
+    #[test]
+    fn test_illegal_transition() {
+        let manager = OrderManager::new();
+        // the status does not match the execution type
+        assert!(matches!(
+            manager.apply(&report("1", 1, ExecutionType::Trade, OrderStatus::New)),
+            Err(Error::IllegalOrderTransition { from: None, .. })
+        ));
+
+        manager
+            .apply(&report(
+                "1",
+                1,
+                ExecutionType::Canceled,
+                OrderStatus::Cancelled,
+            ))
+            .unwrap();
+        let err = manager
+            .apply(&report("1", 1, ExecutionType::Trade, OrderStatus::Filled))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::IllegalOrderTransition {
+                from: Some(OrderStatus::Cancelled),
+                to: OrderStatus::Filled,
+                ..
+            }
+        ));
+        assert_eq!(
+            manager.order("1").unwrap().order_status,
+            OrderStatus::Cancelled
+        );
+    }
+
+    #[test]
+    fn test_seed() {
+        let manager = OrderManager::new();
+        manager
+            .apply(&report("1", 1, ExecutionType::New, OrderStatus::New))
+            .unwrap();
+        manager
+            .apply(&report("2", 1, ExecutionType::Trade, OrderStatus::Filled))
+            .unwrap();
+        manager.seed(&OrderMassStatus {
+            reports: vec![report("3", 1, ExecutionType::OrderStatus, OrderStatus::New)],
+            failures: Vec::new(),
+        });
+
+        let open_orders = manager.open_orders();
+        assert_eq!(open_orders.len(), 1);
+        assert_eq!(open_orders[0].order_id, "3");
+        assert!(manager.order("1").is_none());
+        assert!(manager.order("2").is_some());
+
+        // the open orders are kept if the status is incomplete
+        manager.seed(&OrderMassStatus {
+            reports: vec![report("4", 1, ExecutionType::OrderStatus, OrderStatus::New)],
+            failures: vec![Error::FieldNotFoundError(Field::OrderID)],
+        });
+        let mut open_orders = manager
+            .open_orders()
+            .into_iter()
+            .map(|v| v.order_id)
+            .collect::<Vec<_>>();
+        open_orders.sort();
+        assert_eq!(open_orders, vec!["3", "4"]);
+    }
+}
@@ -1,13 +1,54 @@
+use std::str::FromStr;
+
 use chrono::NaiveDateTime;
 
 use crate::{
     messages::ResponseMessage,
     types::{
-        Error, ExecutionReport, ExecutionType, Field, OrderReport, OrderStatus, OrderType,
-        PositionReport, Side, SymbolInformation,
+        Error, ExecutionReport, ExecutionType, Field, OrdRejReason, OrderMassStatus, OrderReport,
+        OrderStatus, OrderType, PositionReport, Side, SymbolInformation,
     },
 };
 
+const TIME_FORMAT: &str = "%Y%m%d-%H:%M:%S%.3f";
+
+fn parse_error(res: &ResponseMessage, field: Field, value: &str) -> Error {
+    Error::ParseError {
+        field,
+        value: value.to_string(),
+        msg_type: res.get_message_type().to_string(),
+    }
+}
+
+fn required(res: &ResponseMessage, field: Field) -> Result<String, Error> {
+    res.get_field_value(field)
+        .ok_or(Error::FieldNotFoundError(field))
+}
+
+fn parse_value<T: FromStr>(res: &ResponseMessage, field: Field, value: &str) -> Result<T, Error> {
+    value
+        .parse::<T>()
+        .map_err(|_| parse_error(res, field, value))
+}
+
+fn parse_required<T: FromStr>(res: &ResponseMessage, field: Field) -> Result<T, Error> {
+    parse_value(res, field, &required(res, field)?)
+}
+
+fn parse_optional<T: FromStr>(res: &ResponseMessage, field: Field) -> Result<Option<T>, Error> {
+    res.get_field_value(field)
+        .map(|v| parse_value(res, field, &v))
+        .transpose()
+}
+
+fn parse_time(res: &ResponseMessage, field: Field, value: &str) -> Result<NaiveDateTime, Error> {
+    NaiveDateTime::parse_from_str(value, TIME_FORMAT).map_err(|_| parse_error(res, field, value))
+}
+
+fn parse_flag(res: &ResponseMessage, field: Field) -> Option<bool> {
+    res.get_field_value(field).map(|v| v == "Y")
+}
+
 pub fn parse_security_list(res: &ResponseMessage) -> Result<Vec<SymbolInformation>, Error> {
     let sec_list = res.get_repeating_groups(Field::NoRelatedSym, Field::Symbol, None);
     let mut result = Vec::new();
@@ -15,168 +56,242 @@ pub fn parse_security_list(res: &ResponseMessage) -> Result<Vec<SymbolInformatio
         if symbol.len() < 3 {
             continue;
         }
+        let get = |field: Field| symbol.get(&field).ok_or(Error::FieldNotFoundError(field));
         result.push(SymbolInformation {
-            name: symbol
-                .get(&Field::SymbolName)
-                .ok_or(Error::FieldNotFoundError(Field::SymbolName))?
-                .clone(),
-            id: symbol
-                .get(&Field::Symbol)
-                .ok_or(Error::FieldNotFoundError(Field::Symbol))?
-                .parse::<u32>()
-                .unwrap(),
-            digits: symbol
-                .get(&Field::SymbolDigits)
-                .ok_or(Error::FieldNotFoundError(Field::SymbolDigits))?
-                .parse::<u32>()
-                .unwrap(),
+            name: get(Field::SymbolName)?.clone(),
+            id: parse_value(res, Field::Symbol, get(Field::Symbol)?)?,
+            digits: parse_value(res, Field::SymbolDigits, get(Field::SymbolDigits)?)?,
         });
     }
     Ok(result)
 }
 
 pub fn parse_positions(res: Vec<ResponseMessage>) -> Result<Vec<PositionReport>, Error> {
-    Ok(res
-        .into_iter()
-        .filter(|res| res.get_field_value(Field::PosReqResult).unwrap() == "0")
-        .filter(|res| {
-            res.get_field_value(Field::NoPositions)
-                .map(|v| v == "1")
-                .unwrap_or(false)
-        })
-        .map(|res| PositionReport {
-            symbol_id: res
-                .get_field_value(Field::Symbol)
-                .unwrap()
-                .parse::<u32>()
-                .unwrap(),
-            position_id: res.get_field_value(Field::PosMaintRptID).unwrap(),
-            long_qty: res
-                .get_field_value(Field::LongQty)
-                .unwrap()
-                .parse::<f64>()
-                .unwrap(),
-            short_qty: res
-                .get_field_value(Field::ShortQty)
-                .unwrap()
-                .parse::<f64>()
-                .unwrap(),
-            settle_price: res
-                .get_field_value(Field::SettlPrice)
-                .unwrap()
-                .parse::<f64>()
-                .unwrap(),
-            absolute_tp: res
-                .get_field_value(Field::AbsoluteTP)
-                .map(|v| v.parse::<f64>().unwrap()),
-            absolute_sl: res
-                .get_field_value(Field::AbsoluteSL)
-                .map(|v| v.parse::<f64>().unwrap()),
-            trailing_sl: res.get_field_value(Field::TrailingSL).map(|v| v == "Y"),
-            trigger_method_sl: res
-                .get_field_value(Field::TriggerMethodSL)
-                .map(|v| v.parse::<u32>().unwrap()),
-            guaranteed_sl: res.get_field_value(Field::GuaranteedSL).map(|v| v == "Y"),
-        })
-        .collect())
+    let mut result = Vec::new();
+    for res in res.into_iter() {
+        if required(&res, Field::PosReqResult)? != "0"
+            || res.get_field_value(Field::NoPositions).as_deref() != Some("1")
+        {
+            continue;
+        }
+        result.push(PositionReport {
+            symbol_id: parse_required(&res, Field::Symbol)?,
+            position_id: required(&res, Field::PosMaintRptID)?,
+            long_qty: parse_required(&res, Field::LongQty)?,
+            short_qty: parse_required(&res, Field::ShortQty)?,
+            settle_price: parse_required(&res, Field::SettlPrice)?,
+            absolute_tp: parse_optional(&res, Field::AbsoluteTP)?,
+            absolute_sl: parse_optional(&res, Field::AbsoluteSL)?,
+            trailing_sl: parse_flag(&res, Field::TrailingSL),
+            trigger_method_sl: parse_optional(&res, Field::TriggerMethodSL)?,
+            guaranteed_sl: parse_flag(&res, Field::GuaranteedSL),
+        });
+    }
+    Ok(result)
 }
 
 //
 // ORDER
 //
 pub fn parse_execution_report(res: ResponseMessage) -> Result<ExecutionReport, Error> {
+    let side = required(&res, Field::Side)?;
+    let side = parse_value::<u32>(&res, Field::Side, &side)
+        .and_then(|v| Side::try_from(v).map_err(|_| parse_error(&res, Field::Side, &side)))?;
+
     Ok(ExecutionReport {
-        exec_type: res
-            .get_field_value(Field::ExecType)
-            .unwrap()
-            .parse::<ExecutionType>()
-            .unwrap(),
+        exec_type: parse_required::<ExecutionType>(&res, Field::ExecType)?,
         order_report: OrderReport {
-            symbol: res
-                .get_field_value(Field::Symbol)
-                .unwrap_or("0".into())
-                .parse::<u32>()
-                .unwrap(),
-            order_id: res.get_field_value(Field::OrderID).unwrap(),
-            cl_ord_id: res.get_field_value(Field::ClOrdId).unwrap(),
-            pos_main_rept_id: res.get_field_value(Field::PosMaintRptID).unwrap(),
+            symbol: parse_optional(&res, Field::Symbol)?.unwrap_or(0),
+            order_id: required(&res, Field::OrderID)?,
+            cl_ord_id: required(&res, Field::ClOrdId)?,
+            exec_id: res.get_field_value(Field::ExecID),
+            pos_main_rept_id: res.get_field_value(Field::PosMaintRptID),
             designation: res.get_field_value(Field::Designation),
 
-            order_status: res
-                .get_field_value(Field::OrdStatus)
-                .map(|v| v.parse::<OrderStatus>().unwrap())
-                .unwrap(),
-            order_type: res
-                .get_field_value(Field::OrdType)
-                .map(|v| v.parse::<OrderType>().unwrap())
-                .unwrap(),
-            side: res
-                .get_field_value(Field::Side)
-                .map(|v| Side::try_from(v.parse::<u32>().unwrap()).unwrap())
-                .unwrap(),
-
-            price: res
-                .get_field_value(Field::Price)
-                .map(|v| v.parse::<f64>().unwrap()),
-            stop_px: res
-                .get_field_value(Field::StopPx)
-                .map(|v| v.parse::<f64>().unwrap()),
-            avx_px: res
-                .get_field_value(Field::AvgPx)
-                .map(|v| v.parse::<f64>().unwrap()),
-
-            absolute_tp: res
-                .get_field_value(Field::AbsoluteTP)
-                .map(|v| v.parse::<f64>().unwrap()),
-            reltative_tp: res
-                .get_field_value(Field::RelativeTP)
-                .map(|v| v.parse::<f64>().unwrap()),
-            absolute_sl: res
-                .get_field_value(Field::AbsoluteSL)
-                .map(|v| v.parse::<f64>().unwrap()),
-            reltative_sl: res
-                .get_field_value(Field::RelativeSL)
-                .map(|v| v.parse::<f64>().unwrap()),
-            trailing_sl: res.get_field_value(Field::TrailingSL).map(|v| v == "Y"),
-            trigger_method_sl: res
-                .get_field_value(Field::TriggerMethodSL)
-                .map(|v| v.parse::<u32>().unwrap()),
-            guaranteed_sl: res.get_field_value(Field::GuaranteedSL).map(|v| v == "Y"),
-
-            cum_qty: res
-                .get_field_value(Field::CumQty)
-                .map(|v| v.parse::<f64>().unwrap()),
-            order_qty: res
-                .get_field_value(Field::OrderQty)
-                .unwrap_or("0.0".into())
-                .parse::<f64>()
-                .unwrap(),
-            leaves_qty: res
-                .get_field_value(Field::LeavesQty)
-                .unwrap_or("0.0".into())
-                .parse::<f64>()
-                .unwrap(),
-            last_qty: res
-                .get_field_value(Field::OrderQty)
-                .map(|v| v.parse::<f64>().unwrap()),
-
-            time_in_force: res.get_field_value(Field::TimeInForce).unwrap(),
-            transact_time: res
-                .get_field_value(Field::TransactTime)
-                .map(|v| NaiveDateTime::parse_from_str(v.as_str(), "%Y%m%d-%H:%M:%S%.3f").unwrap())
-                .unwrap(),
+            order_status: parse_required::<OrderStatus>(&res, Field::OrdStatus)?,
+            order_type: parse_required::<OrderType>(&res, Field::OrdType)?,
+            side,
+
+            price: parse_optional(&res, Field::Price)?,
+            stop_px: parse_optional(&res, Field::StopPx)?,
+            avx_px: parse_optional(&res, Field::AvgPx)?,
+
+            absolute_tp: parse_optional(&res, Field::AbsoluteTP)?,
+            reltative_tp: parse_optional(&res, Field::RelativeTP)?,
+            absolute_sl: parse_optional(&res, Field::AbsoluteSL)?,
+            reltative_sl: parse_optional(&res, Field::RelativeSL)?,
+            trailing_sl: parse_flag(&res, Field::TrailingSL),
+            trigger_method_sl: parse_optional(&res, Field::TriggerMethodSL)?,
+            guaranteed_sl: parse_flag(&res, Field::GuaranteedSL),
+
+            cum_qty: parse_optional(&res, Field::CumQty)?,
+            order_qty: parse_optional(&res, Field::OrderQty)?.unwrap_or(0.0),
+            leaves_qty: parse_optional(&res, Field::LeavesQty)?.unwrap_or(0.0),
+            last_qty: parse_optional(&res, Field::LastQty)?,
+            last_px: parse_optional(&res, Field::LastPx)?,
+
+            time_in_force: parse_optional(&res, Field::TimeInForce)?,
+            transact_time: parse_time(
+                &res,
+                Field::TransactTime,
+                &required(&res, Field::TransactTime)?,
+            )?,
             expire_time: res
                 .get_field_value(Field::ExpireTime)
-                .map(|v| NaiveDateTime::parse_from_str(v.as_str(), "%Y%m%d-%H:%M:%S%.3f").unwrap()),
+                .map(|v| parse_time(&res, Field::ExpireTime, &v))
+                .transpose()?,
 
             text: res.get_field_value(Field::Text),
+            ord_rej_reason: parse_optional::<u32>(&res, Field::OrdRejReason)?
+                .map(OrdRejReason::from),
+            mass_status_req_id: res.get_field_value(Field::MassStatusReqID),
         },
     })
 }
 
-pub fn parse_order_mass_status(res: Vec<ResponseMessage>) -> Result<Vec<ExecutionReport>, Error> {
-    Ok(res
-        .into_iter()
-        .map(|res| parse_execution_report(res).unwrap())
-        .collect::<Vec<_>>())
+/// Parses the execution reports of the order mass status.
+/// The reports failed to parse are collected in `failures` instead of failing the whole batch.
+pub fn parse_order_mass_status(res: Vec<ResponseMessage>) -> OrderMassStatus {
+    let mut result = OrderMassStatus::default();
+    for res in res.into_iter() {
+        match parse_execution_report(res) {
+            Ok(report) => result.reports.push(report),
+            Err(err) => {
+                log::warn!("Failed to parse the execution report - {}", err);
+                result.failures.push(err);
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_execution_report, parse_order_mass_status};
+    use crate::{
+        messages::{raw_message, ResponseMessage},
+        types::{Error, ExecutionType, Field, OrdRejReason, TimeInForce},
+    };
+
+    fn execution_report(fields: &str) -> ResponseMessage {
+        let body = format!(
+            "35=8|34=5|49=CSERVER|50=TRADE|52=20240115-08:00:00.000|56=sender|57=TRADE|{}",
+            fields
+        );
+        ResponseMessage::new(&raw_message(&body, "|"), "|").unwrap()
+    }
+
+    const ORDER: &str = "11=order1|37=101|39=0|40=2|44=1.1|54=1|55=1|59=1|60=20240115-08:00:00.000|721=201|150=0|151=1000|38=1000|";
+
+    #[test]
+    fn test_parse_execution_report() {
+        let report = parse_execution_report(execution_report(ORDER)).unwrap();
+        assert_eq!(report.exec_type, ExecutionType::New);
+        assert_eq!(report.order_report.cl_ord_id, "order1");
+        assert_eq!(report.order_report.price, Some(1.1));
+        assert_eq!(report.order_report.order_qty, 1000.0);
+        assert_eq!(report.order_report.last_qty, None);
+        assert_eq!(report.order_report.ord_rej_reason, None);
+    }
+
+    #[test]
+    fn test_parse_fill_and_reject() {
+        let fill = ORDER.replace("39=0", "39=1").replace("150=0", "150=F")
+            + "17=exec1|6=1.15|14=400|31=1.2|32=400|584=mass1|";
+        let report = parse_execution_report(execution_report(&fill)).unwrap();
+        assert_eq!(report.exec_type, ExecutionType::Trade);
+        assert_eq!(report.order_report.exec_id.as_deref(), Some("exec1"));
+        assert_eq!(report.order_report.order_qty, 1000.0);
+        assert_eq!(report.order_report.last_qty, Some(400.0));
+        assert_eq!(report.order_report.last_px, Some(1.2));
+        assert_eq!(report.order_report.avx_px, Some(1.15));
+        assert_eq!(
+            report.order_report.mass_status_req_id.as_deref(),
+            Some("mass1")
+        );
+
+        for (value, reason) in [
+            ("1", OrdRejReason::UnknownSymbol),
+            ("42", OrdRejReason::Other(42)),
+            ("99", OrdRejReason::Other(99)),
+        ] {
+            let reject = ORDER.replace("39=0", "39=8").replace("150=0", "150=8")
+                + &format!("103={}|", value);
+            let report = parse_execution_report(execution_report(&reject)).unwrap();
+            assert_eq!(report.order_report.ord_rej_reason, Some(reason));
+        }
+    }
+
+    #[test]
+    fn test_parse_reject_without_position() {
+        // a rejected order has no position, and may not echo TimeInForce
+        let reject = ORDER
+            .replace("39=0", "39=8")
+            .replace("150=0", "150=8")
+            .replace("59=1|", "")
+            .replace("721=201|", "")
+            + "103=1|58=Unknown symbol|";
+        let report = parse_execution_report(execution_report(&reject)).unwrap();
+        assert_eq!(report.exec_type, ExecutionType::Rejected);
+        assert_eq!(report.order_report.pos_main_rept_id, None);
+        assert_eq!(report.order_report.time_in_force, None);
+        assert_eq!(report.order_report.text.as_deref(), Some("Unknown symbol"));
+
+        let report = parse_execution_report(execution_report(ORDER)).unwrap();
+        assert_eq!(report.order_report.pos_main_rept_id.as_deref(), Some("201"));
+        assert_eq!(
+            report.order_report.time_in_force,
+            Some(TimeInForce::GoodTillCancel)
+        );
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let res = execution_report(&ORDER.replace("44=1.1", "44=abc"));
+        match parse_execution_report(res) {
+            Err(Error::ParseError {
+                field,
+                value,
+                msg_type,
+            }) => {
+                assert_eq!(field, Field::Price);
+                assert_eq!(value, "abc");
+                assert_eq!(msg_type, "8");
+            }
+            res => panic!("unexpected result : {:?}", res),
+        }
+
+        let res = execution_report(&ORDER.replace("150=0", "150=Z"));
+        assert!(matches!(
+            parse_execution_report(res),
+            Err(Error::ParseError {
+                field: Field::ExecType,
+                ..
+            })
+        ));
+
+        let res = execution_report(&ORDER.replace("37=101|", ""));
+        assert!(matches!(
+            parse_execution_report(res),
+            Err(Error::FieldNotFoundError(Field::OrderID))
+        ));
+    }
+
+    #[test]
+    fn test_parse_order_mass_status_partial_failure() {
+        let result = parse_order_mass_status(vec![
+            execution_report(ORDER),
+            execution_report(&ORDER.replace("54=1", "54=9")),
+            execution_report(&ORDER.replace("order1", "order2")),
+        ]);
+        assert_eq!(result.reports.len(), 2);
+        assert_eq!(result.failures.len(), 1);
+        assert!(matches!(
+            result.failures[0],
+            Error::ParseError {
+                field: Field::Side,
+                ..
+            }
+        ));
+    }
 }
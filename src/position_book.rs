@@ -0,0 +1,269 @@
+//! Open positions kept from the fills, and their unrealised P&L.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::RwLock,
+};
+
+use crate::{
+    types::{Error, ExecutionReport, ExecutionType, PositionReport, Side, SpotPrice},
+    MarketClient,
+};
+
+/// Volume regarded as closed.
+const EPSILON: f64 = 1e-9;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Position {
+    pub position_id: String,
+    pub symbol_id: u32,
+    /// `Side::BUY` for a long position.
+    pub side: Side,
+    pub volume: f64,
+    /// Average price of the fills opening the position.
+    pub entry_price: f64,
+}
+
+impl Position {
+    /// Unrealised P&L in the quote currency, closing a long position at the bid and a short
+    /// position at the ask.
+    pub fn unrealized_pnl(&self, price: &SpotPrice) -> f64 {
+        match self.side {
+            Side::BUY => (price.bid - self.entry_price) * self.volume,
+            Side::SELL => (self.entry_price - price.ask) * self.volume,
+        }
+    }
+}
+
+/// Unrealised P&L of a position at the current price.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PositionPnl {
+    pub position: Position,
+    pub price: SpotPrice,
+    pub pnl: f64,
+}
+
+/// Open positions, keyed by the PosMaintRptID(721).
+///
+/// Set it to `TradeClient::set_position_book` to seed it from `fetch_positions` at every logon
+/// and to apply the fills of the execution reports.
+#[derive(Debug, Default)]
+pub struct PositionBook {
+    positions: RwLock<HashMap<String, Position>>,
+}
+
+impl PositionBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the positions with the reports of `fetch_positions`.
+    pub fn seed(&self, reports: &[PositionReport]) {
+        let mut positions = self.positions.write().unwrap();
+        positions.clear();
+        for report in reports.iter() {
+            let volume = report.long_qty - report.short_qty;
+            if volume.abs() < EPSILON {
+                continue;
+            }
+            positions.insert(
+                report.position_id.clone(),
+                Position {
+                    position_id: report.position_id.clone(),
+                    symbol_id: report.symbol_id,
+                    side: if volume > 0.0 { Side::BUY } else { Side::SELL },
+                    volume: volume.abs(),
+                    entry_price: report.settle_price,
+                },
+            );
+        }
+    }
+
+    /// Applies the fill of the execution report to the position, at LastPx(31) or AvgPx(6).
+    ///
+    /// A fill of the same side increases the position at the average price, and the other side
+    /// reduces it. Returns `false` if the report is not a fill, or has no PosMaintRptID(721).
+    pub fn apply(&self, report: &ExecutionReport) -> bool {
+        let order = &report.order_report;
+        let (Some(position_id), Some(qty), Some(price)) = (
+            &order.pos_main_rept_id,
+            order.last_qty,
+            order.last_px.or(order.avx_px),
+        ) else {
+            return false;
+        };
+        if report.exec_type != ExecutionType::Trade || qty <= 0.0 {
+            return false;
+        }
+
+        let mut positions = self.positions.write().unwrap();
+        let position = positions
+            .entry(position_id.clone())
+            .or_insert_with(|| Position {
+                position_id: position_id.clone(),
+                symbol_id: order.symbol,
+                side: order.side,
+                volume: 0.0,
+                entry_price: price,
+            });
+        if position.side == order.side {
+            position.entry_price =
+                (position.entry_price * position.volume + price * qty) / (position.volume + qty);
+            position.volume += qty;
+        } else if position.volume - qty > EPSILON {
+            position.volume -= qty;
+        } else if qty - position.volume > EPSILON {
+            // reversed by the fill of the larger volume
+            position.volume = qty - position.volume;
+            position.side = order.side;
+            position.entry_price = price;
+        } else {
+            positions.remove(position_id);
+        }
+        true
+    }
+
+    pub fn position(&self, position_id: &str) -> Option<Position> {
+        self.positions.read().unwrap().get(position_id).cloned()
+    }
+
+    pub fn positions(&self) -> Vec<Position> {
+        self.positions.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn positions_for_symbol(&self, symbol_id: u32) -> Vec<Position> {
+        self.positions
+            .read()
+            .unwrap()
+            .values()
+            .filter(|position| position.symbol_id == symbol_id)
+            .cloned()
+            .collect()
+    }
+
+    /// Unrealised P&L of the positions at the spot prices of the market client.
+    ///
+    /// The symbols of the positions must be subscribed by `subscribe_spot`, otherwise it returns
+    /// `Error::NotSubscribed`.
+    pub async fn unrealized_pnl(&self, market: &MarketClient) -> Result<Vec<PositionPnl>, Error> {
+        let mut result = Vec::new();
+        for position in self.positions() {
+            let price = market.price_of(position.symbol_id).await?;
+            result.push(PositionPnl {
+                pnl: position.unrealized_pnl(&price),
+                position,
+                price,
+            });
+        }
+        Ok(result)
+    }
+
+    /// Sum of the unrealised P&L by symbol, in the quote currency of each symbol.
+    pub async fn unrealized_pnl_by_symbol(
+        &self,
+        market: &MarketClient,
+    ) -> Result<BTreeMap<u32, f64>, Error> {
+        let mut result = BTreeMap::new();
+        for pnl in self.unrealized_pnl(market).await? {
+            *result.entry(pnl.position.symbol_id).or_default() += pnl.pnl;
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PositionBook;
+    use crate::types::{
+        ExecutionReport, ExecutionType, OrderReport, OrderStatus, OrderType, PositionReport, Side,
+        SpotPrice, TimeInForce,
+    };
+    use chrono::Utc;
+
+    fn fill(position_id: &str, side: Side, qty: f64, price: f64) -> ExecutionReport {
+        ExecutionReport {
+            exec_type: ExecutionType::Trade,
+            order_report: OrderReport {
+                symbol: 1,
+                order_id: "1".into(),
+                cl_ord_id: "1".into(),
+                exec_id: None,
+                pos_main_rept_id: Some(position_id.into()),
+                designation: None,
+                order_status: OrderStatus::Filled,
+                order_type: OrderType::Market,
+                side,
+                price: None,
+                stop_px: None,
+                avx_px: Some(price),
+                absolute_tp: None,
+                reltative_tp: None,
+                absolute_sl: None,
+                reltative_sl: None,
+                trailing_sl: None,
+                trigger_method_sl: None,
+                guaranteed_sl: None,
+                cum_qty: Some(qty),
+                order_qty: qty,
+                leaves_qty: 0.0,
+                last_qty: Some(qty),
+                last_px: Some(price),
+                time_in_force: Some(TimeInForce::ImmediateOrCancel),
+                transact_time: Utc::now().naive_utc(),
+                expire_time: None,
+                text: None,
+                ord_rej_reason: None,
+                mass_status_req_id: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_apply_fills() {
+        let book = PositionBook::new();
+        assert!(book.apply(&fill("1", Side::BUY, 1000.0, 1.1)));
+        assert!(book.apply(&fill("1", Side::BUY, 3000.0, 1.3)));
+        let position = book.position("1").unwrap();
+        assert_eq!(position.volume, 4000.0);
+        assert!((position.entry_price - 1.25).abs() < 1e-9);
+
+        book.apply(&fill("1", Side::SELL, 1000.0, 1.4));
+        assert_eq!(book.position("1").unwrap().volume, 3000.0);
+        book.apply(&fill("1", Side::SELL, 3000.0, 1.4));
+        assert!(book.position("1").is_none());
+
+        let mut new = fill("2", Side::SELL, 1000.0, 1.1);
+        new.exec_type = ExecutionType::New;
+        assert!(!book.apply(&new));
+        assert!(book.positions().is_empty());
+    }
+
+    #[test]
+    fn test_unrealized_pnl() {
+        let book = PositionBook::new();
+        book.seed(&[PositionReport {
+            symbol_id: 1,
+            position_id: "1".into(),
+            long_qty: 0.0,
+            short_qty: 2000.0,
+            settle_price: 1.5,
+            absolute_tp: None,
+            absolute_sl: None,
+            trailing_sl: None,
+            trigger_method_sl: None,
+            guaranteed_sl: None,
+        }]);
+        book.apply(&fill("2", Side::BUY, 1000.0, 1.1));
+        assert_eq!(book.positions_for_symbol(1).len(), 2);
+
+        let price = SpotPrice {
+            bid: 1.2,
+            ask: 1.25,
+        };
+        let short = book.position("1").unwrap();
+        assert_eq!(short.side, Side::SELL);
+        assert!((short.unrealized_pnl(&price) - 500.0).abs() < 1e-6);
+        let long = book.position("2").unwrap();
+        assert!((long.unrealized_pnl(&price) - 100.0).abs() < 1e-6);
+    }
+}
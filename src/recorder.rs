@@ -0,0 +1,334 @@
+//! Recording of the market data to JSON-lines files, and the reader of the files.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Lines, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use chrono::{NaiveDate, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::types::{DepthEvent, Error, SpotPrice};
+
+const EXTENSION: &str = "jsonl";
+
+/// Market data event of a symbol.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TickEvent {
+    Spot(SpotPrice),
+    Depth(DepthEvent),
+}
+
+/// Recorded market data event, one per line.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tick {
+    /// Local time when the message was received. (UTC)
+    pub received_time: NaiveDateTime,
+    /// SendingTime(52) of the message.
+    pub sending_time: Option<NaiveDateTime>,
+    pub symbol_id: u32,
+    pub event: TickEvent,
+}
+
+/// When to start a new file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rotation {
+    /// Starts a new file when the file reaches the size in bytes.
+    pub max_size: Option<u64>,
+    /// Starts a new file when the UTC date changes.
+    pub daily: bool,
+}
+
+#[derive(Debug)]
+struct RecorderState {
+    date: NaiveDate,
+    index: u32,
+    size: u64,
+    writer: BufWriter<File>,
+}
+
+/// Appends the ticks to the files `{prefix}-{YYYYMMDD}-{index}.jsonl` in a directory.
+///
+/// The file of the latest index of today is appended when the recorder is opened again. The ticks
+/// are buffered, and written when `flush` is called or the recorder is dropped.
+#[derive(Debug)]
+pub struct TickRecorder {
+    dir: PathBuf,
+    prefix: String,
+    rotation: Rotation,
+    inner: Mutex<RecorderState>,
+}
+
+fn file_name(prefix: &str, date: NaiveDate, index: u32) -> String {
+    format!(
+        "{}-{}-{:04}.{}",
+        prefix,
+        date.format("%Y%m%d"),
+        index,
+        EXTENSION
+    )
+}
+
+fn open_append(path: &Path) -> Result<(BufWriter<File>, u64), Error> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((BufWriter::new(file), size))
+}
+
+impl TickRecorder {
+    /// Opens the recorder in `dir`. The directory is created if it does not exist.
+    pub fn open(
+        dir: impl AsRef<Path>,
+        prefix: impl Into<String>,
+        rotation: Rotation,
+    ) -> Result<Self, Error> {
+        let dir = dir.as_ref().to_path_buf();
+        let prefix = prefix.into();
+        fs::create_dir_all(&dir)?;
+
+        let date = Utc::now().date_naive();
+        let mut index = 0;
+        while dir.join(file_name(&prefix, date, index + 1)).exists() {
+            index += 1;
+        }
+        let (writer, size) = open_append(&dir.join(file_name(&prefix, date, index)))?;
+
+        Ok(Self {
+            dir,
+            prefix,
+            rotation,
+            inner: Mutex::new(RecorderState {
+                date,
+                index,
+                size,
+                writer,
+            }),
+        })
+    }
+
+    /// Path of the file being written.
+    pub fn current_path(&self) -> PathBuf {
+        let inner = self.inner.lock().unwrap();
+        self.dir
+            .join(file_name(&self.prefix, inner.date, inner.index))
+    }
+
+    pub fn record(&self, tick: &Tick) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(tick)?;
+        line.push(b'\n');
+
+        let mut inner = self.inner.lock().unwrap();
+        let today = Utc::now().date_naive();
+        let rotate_date = self.rotation.daily && today != inner.date;
+        let rotate_size = self
+            .rotation
+            .max_size
+            .is_some_and(|max_size| inner.size > 0 && inner.size + line.len() as u64 > max_size);
+        if rotate_date || rotate_size {
+            let (date, index) = if rotate_date {
+                (today, 0)
+            } else {
+                (inner.date, inner.index + 1)
+            };
+            inner.writer.flush()?;
+            let (writer, size) = open_append(&self.dir.join(file_name(&self.prefix, date, index)))?;
+            *inner = RecorderState {
+                date,
+                index,
+                size,
+                writer,
+            };
+        }
+
+        inner.writer.write_all(&line)?;
+        inner.size += line.len() as u64;
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<(), Error> {
+        self.inner.lock().unwrap().writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Iterates the ticks of the recorded files in order.
+pub struct TickReader {
+    paths: std::vec::IntoIter<PathBuf>,
+    lines: Option<Lines<BufReader<File>>>,
+}
+
+impl TickReader {
+    /// Reads a file.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let lines = BufReader::new(File::open(path)?).lines();
+        Ok(Self {
+            paths: Vec::new().into_iter(),
+            lines: Some(lines),
+        })
+    }
+
+    /// Reads the files of the recorder with `prefix` in `dir`, ordered by the date and the index.
+    pub fn open_dir(dir: impl AsRef<Path>, prefix: &str) -> Result<Self, Error> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let matched = path
+                .file_name()
+                .and_then(|v| v.to_str())
+                .is_some_and(|name| {
+                    name.starts_with(&format!("{}-", prefix))
+                        && name.ends_with(&format!(".{}", EXTENSION))
+                });
+            if matched {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(Self {
+            paths: paths.into_iter(),
+            lines: None,
+        })
+    }
+}
+
+impl Iterator for TickReader {
+    type Item = Result<Tick, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(lines) = self.lines.as_mut() {
+                match lines.next() {
+                    Some(Ok(line)) if line.trim().is_empty() => continue,
+                    Some(Ok(line)) => {
+                        return Some(serde_json::from_str(&line).map_err(Error::from))
+                    }
+                    Some(Err(err)) => return Some(Err(err.into())),
+                    None => self.lines = None,
+                }
+            }
+            let path = self.paths.next()?;
+            match File::open(path) {
+                Ok(file) => self.lines = Some(BufReader::new(file).lines()),
+                Err(err) => return Some(Err(err.into())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rotation, Tick, TickEvent, TickReader, TickRecorder};
+    use crate::types::{DepthEvent, DepthPrice, IncrementalRefresh, PriceType, SpotPrice};
+    use chrono::NaiveDate;
+    use std::{collections::HashMap, fs};
+
+    fn temp_dir() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("cfix-recorder-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn ticks() -> Vec<Tick> {
+        let now = NaiveDate::from_ymd_opt(2024, 1, 2)
+            .unwrap()
+            .and_hms_milli_opt(3, 4, 5, 678)
+            .unwrap();
+        let data = DepthPrice {
+            price_type: PriceType::Bid,
+            price: 1.1,
+            size: 1000.0,
+        };
+        vec![
+            Tick {
+                received_time: now,
+                sending_time: Some(now),
+                symbol_id: 1,
+                event: TickEvent::Spot(SpotPrice { bid: 1.1, ask: 1.2 }),
+            },
+            Tick {
+                received_time: now,
+                sending_time: None,
+                symbol_id: 2,
+                event: TickEvent::Depth(DepthEvent::FullRefresh(HashMap::from([(
+                    "1".to_string(),
+                    data.clone(),
+                )]))),
+            },
+            Tick {
+                received_time: now,
+                sending_time: Some(now),
+                symbol_id: 2,
+                event: TickEvent::Depth(DepthEvent::IncrementalRefresh(vec![
+                    IncrementalRefresh::Delete {
+                        symbol_id: 2,
+                        entry_id: "1".into(),
+                    },
+                    IncrementalRefresh::New {
+                        symbol_id: 2,
+                        entry_id: "2".into(),
+                        data,
+                    },
+                ])),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_record_and_read() {
+        let dir = temp_dir();
+        {
+            let recorder = TickRecorder::open(&dir, "ticks", Rotation::default()).unwrap();
+            for tick in ticks().iter() {
+                recorder.record(tick).unwrap();
+            }
+            recorder.flush().unwrap();
+
+            let read = TickReader::open(recorder.current_path())
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(read, ticks());
+        }
+
+        // appended to the same file
+        let recorder = TickRecorder::open(&dir, "ticks", Rotation::default()).unwrap();
+        recorder.record(&ticks()[0]).unwrap();
+        drop(recorder);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert_eq!(TickReader::open_dir(&dir, "ticks").unwrap().count(), 4);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotation_by_size() {
+        let dir = temp_dir();
+        let rotation = Rotation {
+            max_size: Some(1),
+            daily: false,
+        };
+        let recorder = TickRecorder::open(&dir, "ticks", rotation).unwrap();
+        for tick in ticks().iter() {
+            recorder.record(tick).unwrap();
+        }
+        drop(recorder);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+
+        // continues from the last file
+        let recorder = TickRecorder::open(&dir, "ticks", rotation).unwrap();
+        assert!(recorder
+            .current_path()
+            .to_string_lossy()
+            .ends_with("-0002.jsonl"));
+        drop(recorder);
+
+        let read = TickReader::open_dir(&dir, "ticks")
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(read, ticks());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
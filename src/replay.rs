@@ -0,0 +1,211 @@
+//! Replay of the recorded raw FIX messages through the handlers of the clients.
+
+use std::path::Path;
+
+use chrono::NaiveDateTime;
+
+use crate::{
+    fixapi::sending_time_of,
+    messages::ResponseMessage,
+    runtime,
+    types::{Error, Field, DELIMITER},
+};
+
+const SERVER_COMP_ID: &str = "CSERVER";
+
+/// Speed of the replay.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ReplaySpeed {
+    /// Without waiting between the messages.
+    #[default]
+    Max,
+    /// Waits for the interval of the SendingTime(52) of the messages.
+    Original,
+    /// `Original` accelerated by the factor. `Accelerated(10.0)` is 10 times faster.
+    Accelerated(f64),
+}
+
+/// Messages of a raw FIX log, one message per line with the SOH or `|` delimiter.
+///
+/// Anything before `8=FIX` in a line is ignored, e.g. the timestamp of the log. Only the messages
+/// from the server are replayed, and the lines failed to be parsed are skipped.
+#[derive(Debug, Clone, Default)]
+pub struct ReplaySource {
+    messages: Vec<ResponseMessage>,
+}
+
+impl ReplaySource {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Ok(Self::parse(&std::fs::read_to_string(path)?))
+    }
+
+    pub fn parse(log: &str) -> Self {
+        let mut messages = Vec::new();
+        for (line_no, line) in log.lines().enumerate() {
+            let Some(start) = line.find("8=FIX") else {
+                continue;
+            };
+            let message = line[start..].trim_end().replace('|', DELIMITER);
+            match ResponseMessage::new(&message, DELIMITER) {
+                Ok(res) if res.get_field_str(Field::SenderCompID) == Some(SERVER_COMP_ID) => {
+                    messages.push(res)
+                }
+                Ok(_) => {}
+                Err(err) => log::warn!("Skip the line {} - {:?}", line_no + 1, err),
+            }
+        }
+        Self { messages }
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Calls `f` with the messages in order, waiting between them for the speed.
+    pub(crate) async fn play<F: FnMut(ResponseMessage)>(&self, speed: ReplaySpeed, mut f: F) {
+        let factor = match speed {
+            ReplaySpeed::Max => None,
+            ReplaySpeed::Original => Some(1.0),
+            ReplaySpeed::Accelerated(factor) if factor > 0.0 => Some(factor),
+            ReplaySpeed::Accelerated(_) => None,
+        };
+        let mut last_time: Option<NaiveDateTime> = None;
+        for res in self.messages.iter() {
+            if let Some(factor) = factor {
+                let sending_time = sending_time_of(res);
+                if let (Some(last), Some(current)) = (last_time, sending_time) {
+                    if let Ok(interval) = (current - last).to_std() {
+                        runtime::sleep(interval.div_f64(factor)).await;
+                    }
+                }
+                last_time = sending_time.or(last_time);
+            }
+            f(res.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReplaySource, ReplaySpeed};
+    use crate::{
+        messages::raw_message,
+        types::{Config, DELIMITER},
+        MarketClient,
+    };
+    use std::time::Instant;
+
+    /// Message in the log format, with `|` in place of SOH.
+    fn message(msg_type: &str, seq: u32, sender: &str, time: &str, fields: &str) -> String {
+        let body = format!(
+            "35={}|34={}|49={}|52=20240102-03:04:{}|{}",
+            msg_type, seq, sender, time, fields
+        );
+        raw_message(&body.replace('|', DELIMITER), DELIMITER).replace(DELIMITER, "|")
+    }
+
+    #[test]
+    fn test_parse() {
+        let log = [
+            // with the prefix of the log
+            format!(
+                "2024-01-02 03:04:05 {}",
+                message("0", 1, "CSERVER", "05.000", "")
+            ),
+            message("0", 2, "CSERVER", "05.100", "").replace('|', DELIMITER),
+            // sent by the client
+            message("0", 1, "sender", "05.000", ""),
+            "not a message".into(),
+            // broken CheckSum(10)
+            message("1", 3, "CSERVER", "05.200", "").replace("05.200", "05.300"),
+        ]
+        .join("\n");
+        let source = ReplaySource::parse(&log);
+        assert_eq!(source.len(), 2);
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_play_speed() {
+        let log = [
+            message("0", 1, "CSERVER", "05.000", ""),
+            message("0", 2, "CSERVER", "05.200", ""),
+        ]
+        .join("\n");
+        let source = ReplaySource::parse(&log);
+
+        let mut seqs = Vec::new();
+        let start = Instant::now();
+        source
+            .play(ReplaySpeed::Max, |res| {
+                seqs.push(res.get_tag_value(34).unwrap().to_string())
+            })
+            .await;
+        assert_eq!(seqs, vec!["1", "2"]);
+        assert!(start.elapsed().as_millis() < 100);
+
+        let start = Instant::now();
+        source.play(ReplaySpeed::Accelerated(2.0), |_| {}).await;
+        assert!(start.elapsed().as_millis() >= 100);
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_replay_market_client() {
+        let log = [
+            message("A", 1, "CSERVER", "05.000", ""),
+            message(
+                "W",
+                2,
+                "CSERVER",
+                "05.100",
+                "55=1|268=2|269=0|270=1.1|269=1|270=1.2|",
+            ),
+            message(
+                "W",
+                3,
+                "CSERVER",
+                "05.200",
+                "55=2|268=2|269=0|270=1.3|271=1000|278=a|269=1|270=1.4|271=1000|278=b|",
+            ),
+            message(
+                "X",
+                4,
+                "CSERVER",
+                "05.300",
+                "268=2|279=2|278=b|55=2|279=0|269=1|278=c|55=2|270=1.35|271=500|",
+            ),
+            // malformed price, dropped without stopping the session
+            message(
+                "W",
+                5,
+                "CSERVER",
+                "05.400",
+                "55=1|268=2|269=0|270=abc|269=1|270=1.3|",
+            ),
+        ]
+        .join("\n");
+        let source = ReplaySource::parse(&log);
+        assert_eq!(source.len(), 5);
+
+        let config = Config::new(
+            "127.0.0.1".into(),
+            "user".into(),
+            "password".into(),
+            "sender".into(),
+            30,
+        );
+        let mut client = MarketClient::with_config(config);
+        client.replay(&source, ReplaySpeed::Max).await.unwrap();
+
+        assert_eq!(client.price_of(1).await.unwrap().ask, 1.2);
+        let book = client.order_book(2).await.unwrap();
+        assert_eq!(book.best_ask().unwrap().price, 1.35);
+        assert_eq!(book.entries().len(), 2);
+        assert!(client.depth_subscription_list().await.contains(&2));
+    }
+}
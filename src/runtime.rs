@@ -0,0 +1,139 @@
+//! Task spawning, timers and TCP streams of the async runtime.
+//!
+//! `async-std` is used by default. With the `tokio` feature, the tokio runtime is used natively
+//! and `async-std` is not required.
+
+#[cfg(not(any(feature = "tokio", feature = "async-std")))]
+compile_error!("either the `async_std_default` or the `tokio` feature must be enabled");
+
+use std::{future::Future, io, net::Shutdown, pin::Pin, time::Duration};
+
+use futures::Stream;
+
+/// TCP stream implementing the `futures` io traits.
+#[cfg(feature = "tokio")]
+pub(crate) type TcpStream = tokio_util::compat::Compat<tokio::net::TcpStream>;
+#[cfg(not(feature = "tokio"))]
+pub(crate) type TcpStream = async_std::net::TcpStream;
+
+/// Shuts down the stream from any task, while the reading and writing halves are in use.
+#[derive(Debug)]
+pub(crate) struct StreamHandle {
+    #[cfg(feature = "tokio")]
+    inner: std::net::TcpStream,
+    #[cfg(not(feature = "tokio"))]
+    inner: async_std::net::TcpStream,
+}
+
+impl StreamHandle {
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.inner.shutdown(Shutdown::Both)
+    }
+}
+
+#[cfg(feature = "tokio")]
+fn from_tokio(stream: tokio::net::TcpStream) -> io::Result<(TcpStream, StreamHandle)> {
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    // the cloned std stream shares the socket with the tokio stream.
+    let stream = stream.into_std()?;
+    let inner = stream.try_clone()?;
+    let stream = tokio::net::TcpStream::from_std(stream)?;
+    Ok((stream.compat(), StreamHandle { inner }))
+}
+
+pub(crate) async fn connect(addr: &str) -> io::Result<(TcpStream, StreamHandle)> {
+    #[cfg(feature = "tokio")]
+    {
+        from_tokio(tokio::net::TcpStream::connect(addr).await?)
+    }
+    #[cfg(not(feature = "tokio"))]
+    {
+        let stream = async_std::net::TcpStream::connect(addr).await?;
+        Ok((stream.clone(), StreamHandle { inner: stream }))
+    }
+}
+
+/// TCP listener for the mock server and the tests.
+#[cfg(any(test, feature = "testing"))]
+pub(crate) struct TcpListener {
+    #[cfg(feature = "tokio")]
+    inner: tokio::net::TcpListener,
+    #[cfg(not(feature = "tokio"))]
+    inner: async_std::net::TcpListener,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl TcpListener {
+    pub async fn bind(addr: &str) -> io::Result<Self> {
+        #[cfg(feature = "tokio")]
+        let inner = tokio::net::TcpListener::bind(addr).await?;
+        #[cfg(not(feature = "tokio"))]
+        let inner = async_std::net::TcpListener::bind(addr).await?;
+        Ok(Self { inner })
+    }
+
+    pub fn port(&self) -> io::Result<u16> {
+        Ok(self.inner.local_addr()?.port())
+    }
+
+    pub async fn accept(&self) -> io::Result<(TcpStream, StreamHandle)> {
+        let (stream, _) = self.inner.accept().await?;
+        #[cfg(feature = "tokio")]
+        {
+            from_tokio(stream)
+        }
+        #[cfg(not(feature = "tokio"))]
+        {
+            Ok((stream.clone(), StreamHandle { inner: stream }))
+        }
+    }
+}
+
+/// Spawns the task in the background.
+pub(crate) fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    #[cfg(feature = "tokio")]
+    tokio::spawn(future);
+    #[cfg(not(feature = "tokio"))]
+    async_std::task::spawn(future);
+}
+
+/// Runs the blocking function, e.g. the file I/O, on the thread pool for the blocking tasks.
+pub(crate) async fn spawn_blocking<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    #[cfg(feature = "tokio")]
+    return tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()));
+    #[cfg(not(feature = "tokio"))]
+    return async_std::task::spawn_blocking(f).await;
+}
+
+pub(crate) async fn sleep(duration: Duration) {
+    #[cfg(feature = "tokio")]
+    tokio::time::sleep(duration).await;
+    #[cfg(not(feature = "tokio"))]
+    async_std::task::sleep(duration).await;
+}
+
+/// Returns `None` if the future does not complete within the duration.
+pub(crate) async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    #[cfg(feature = "tokio")]
+    return tokio::time::timeout(duration, future).await.ok();
+    #[cfg(not(feature = "tokio"))]
+    return async_std::future::timeout(duration, future).await.ok();
+}
+
+/// Yields after every period. The first item is yielded after the period, not immediately.
+pub(crate) fn interval(period: Duration) -> Pin<Box<dyn Stream<Item = ()> + Send>> {
+    Box::pin(futures::stream::unfold((), move |_| async move {
+        sleep(period).await;
+        Some(((), ()))
+    }))
+}
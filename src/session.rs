@@ -0,0 +1,611 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    messages::{to_poss_dup, RequestMessage, ResponseMessage, SequenceReset},
+    types::{Config, Error, Field, SubID},
+};
+
+/// Administrative messages are not resent but replaced by SequenceReset-GapFill.
+pub fn is_admin_message(msg_type: &str) -> bool {
+    matches!(msg_type, "0" | "1" | "2" | "3" | "4" | "5" | "A")
+}
+
+/// Builds the messages to answer the ResendRequest from `begin` to `end` inclusive.
+///
+/// `stored` is the stored application messages in the range, ordered by the sequence number.
+/// They are resent with PossDupFlag and the missing sequence numbers (administrative messages)
+/// are skipped with SequenceReset-GapFill.
+pub fn resend_messages(
+    stored: Vec<(u32, String)>,
+    begin: u32,
+    end: u32,
+    sub_id: SubID,
+    delimiter: &str,
+    config: &Config,
+) -> Vec<String> {
+    let gap_fill = |seq: u32, new_seq: u32| {
+        let msg = SequenceReset::new(Some(true), new_seq).build(sub_id, seq, delimiter, config);
+        to_poss_dup(&msg, delimiter)
+    };
+
+    let mut messages = Vec::new();
+    let mut next = begin;
+    for (seq, msg) in stored {
+        if seq < next || seq > end {
+            continue;
+        }
+        if seq > next {
+            messages.push(gap_fill(next, seq));
+        }
+        messages.push(to_poss_dup(&msg, delimiter));
+        next = seq + 1;
+    }
+    if next <= end {
+        messages.push(gap_fill(next, end + 1));
+    }
+    messages
+}
+
+/// MsgSeqNum(34) of the message.
+pub fn seq_num(res: &ResponseMessage) -> Option<u32> {
+    res.get_field_value(Field::MsgSeqNum)
+        .and_then(|v| v.parse::<u32>().ok())
+}
+
+fn is_flag_set(res: &ResponseMessage, field: Field) -> bool {
+    res.get_field_value(field).is_some_and(|v| v == "Y")
+}
+
+/// A ResendRequest not filled in the time is sent again on the next message after the gap.
+const RESEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Messages queued after a gap. The later ones are dropped, since the ResendRequest is open-ended
+/// and the server resends them.
+const MAX_QUEUED_MESSAGES: usize = 10_000;
+
+/// Result of the sequencing of the received message.
+#[derive(Debug, Default)]
+pub struct Sequenced {
+    /// Messages to handle, in the order of the sequence number.
+    pub ready: Vec<ResponseMessage>,
+    /// Range of the ResendRequest to send. `(begin, 0)` means until the last message.
+    pub resend: Option<(u32, u32)>,
+}
+
+/// Tracks the sequence number of the incoming messages.
+///
+/// The messages after a gap are queued until the missing messages are resent by the server or
+/// skipped with a SequenceReset. Duplicated messages (PossDupFlag) that were already handled
+/// are dropped.
+#[derive(Debug)]
+pub struct InboundSequencer {
+    expected: u32,
+    // None for the message already handled (the logon response)
+    queue: BTreeMap<u32, Option<ResponseMessage>>,
+    // time of the pending ResendRequest
+    resend_requested: Option<Instant>,
+}
+
+impl InboundSequencer {
+    pub fn new(expected: u32) -> Self {
+        Self {
+            expected,
+            queue: BTreeMap::new(),
+            resend_requested: None,
+        }
+    }
+
+    /// The sequence number of the next message expected.
+    pub fn expected(&self) -> u32 {
+        self.expected
+    }
+
+    /// Checks the sequence number of the logon response. The logon is handled immediately even
+    /// if there is a gap before it.
+    ///
+    /// Returns `Error::SeqNumTooLow` if the sequence number is lower than expected, e.g. the
+    /// server was reset while the store kept the sequence numbers.
+    pub fn on_logon(
+        &mut self,
+        res: &ResponseMessage,
+        now: Instant,
+    ) -> Result<Option<(u32, u32)>, Error> {
+        let Some(seq) = seq_num(res) else {
+            return Ok(None);
+        };
+        if is_flag_set(res, Field::ResetSeqNumFlag) {
+            self.reset(seq + 1);
+            return Ok(None);
+        }
+        if seq > self.expected {
+            self.queue.insert(seq, None);
+            return Ok(self.request_resend(now));
+        }
+        if seq < self.expected {
+            return Err(self.too_low(seq));
+        }
+        self.expected += 1;
+        Ok(None)
+    }
+
+    /// Returns `Error::SeqNumTooLow` for a message lower than expected without PossDupFlag, which
+    /// is fatal to the session.
+    pub fn on_message(&mut self, res: ResponseMessage, now: Instant) -> Result<Sequenced, Error> {
+        let mut sequenced = Sequenced::default();
+        let Some(seq) = seq_num(&res) else {
+            sequenced.ready.push(res);
+            return Ok(sequenced);
+        };
+
+        // SequenceReset-Reset ignores the sequence number of the message itself
+        if res.get_message_type() == "4" && !is_flag_set(&res, Field::GapFillFlag) {
+            if let Some(new_seq) = res
+                .get_field_value(Field::NewSeqNo)
+                .and_then(|v| v.parse::<u32>().ok())
+            {
+                log::info!("SequenceReset to {}", new_seq);
+                if new_seq > self.expected {
+                    self.expected = new_seq;
+                }
+                self.queue = self.queue.split_off(&self.expected);
+                self.drain(&mut sequenced);
+            }
+            return Ok(sequenced);
+        }
+
+        if seq < self.expected {
+            if !is_flag_set(&res, Field::PossDupFlag) {
+                return Err(self.too_low(seq));
+            }
+            return Ok(sequenced);
+        }
+
+        if seq > self.expected {
+            log::warn!(
+                "Sequence gap detected, expecting {} but received {}",
+                self.expected,
+                seq
+            );
+            sequenced.resend = self.request_resend(now);
+            if res.get_message_type() == "2" {
+                // the ResendRequest is answered immediately so the both sides don't wait each other.
+                self.queue.insert(seq, None);
+                sequenced.ready.push(res);
+            } else if self.queue.len() < MAX_QUEUED_MESSAGES || self.queue.contains_key(&seq) {
+                self.queue.entry(seq).or_insert(Some(res));
+            } else {
+                log::warn!("Too many messages after the gap, drop {} to be resent", seq);
+            }
+            return Ok(sequenced);
+        }
+
+        self.apply(res, &mut sequenced);
+        self.drain(&mut sequenced);
+        Ok(sequenced)
+    }
+
+    fn too_low(&self, seq: u32) -> Error {
+        Error::SeqNumTooLow {
+            expected: self.expected,
+            received: seq,
+        }
+    }
+
+    fn reset(&mut self, expected: u32) {
+        self.expected = expected;
+        self.queue.clear();
+        self.resend_requested = None;
+    }
+
+    fn request_resend(&mut self, now: Instant) -> Option<(u32, u32)> {
+        if let Some(requested) = self.resend_requested {
+            if now.saturating_duration_since(requested) < RESEND_TIMEOUT {
+                return None;
+            }
+            log::warn!(
+                "ResendRequest timed out, request again from {}",
+                self.expected
+            );
+        }
+        self.resend_requested = Some(now);
+        Some((self.expected, 0))
+    }
+
+    // applies the message of the expected sequence number
+    fn apply(&mut self, res: ResponseMessage, sequenced: &mut Sequenced) {
+        if res.get_message_type() == "4" {
+            // SequenceReset-GapFill
+            let new_seq = res
+                .get_field_value(Field::NewSeqNo)
+                .and_then(|v| v.parse::<u32>().ok())
+                .unwrap_or(0);
+            self.expected = new_seq.max(self.expected + 1);
+            return;
+        }
+        self.expected += 1;
+        sequenced.ready.push(res);
+    }
+
+    fn drain(&mut self, sequenced: &mut Sequenced) {
+        while let Some(entry) = self.queue.first_entry() {
+            let seq = *entry.key();
+            if seq > self.expected {
+                break;
+            }
+            let res = entry.remove();
+            if seq < self.expected {
+                // skipped by the gap fill
+                continue;
+            }
+            match res {
+                Some(res) => self.apply(res, sequenced),
+                None => self.expected += 1,
+            }
+        }
+        if self.queue.is_empty() {
+            self.resend_requested = None;
+        }
+    }
+}
+
+/// Result of the liveness check.
+#[derive(Debug, PartialEq)]
+pub enum LivenessCheck {
+    Alive,
+    /// No message was received for a heartbeat interval. Send a TestRequest with the id.
+    SendTestRequest(String),
+    /// No Heartbeat for the TestRequest was received in time.
+    Dead,
+}
+
+/// Tracks the time of the last incoming message to detect the silent connection.
+#[derive(Debug)]
+pub struct Liveness {
+    interval: Duration,
+    last_received: Instant,
+    // pending TestReqID and the time sent
+    test_request: Option<(String, Instant)>,
+}
+
+impl Liveness {
+    /// The silence is allowed up to the heartbeat interval plus 20% for the transmission delay.
+    pub fn new(heart_beat: u32, now: Instant) -> Self {
+        Self {
+            interval: Duration::from_secs(heart_beat as u64) * 6 / 5,
+            last_received: now,
+            test_request: None,
+        }
+    }
+
+    pub fn on_received(&mut self, res: &ResponseMessage, now: Instant) {
+        self.last_received = now;
+        if res.get_message_type() == "0" {
+            if let Some((test_req_id, _)) = &self.test_request {
+                if res.get_field_value(Field::TestReqID).as_ref() == Some(test_req_id) {
+                    self.test_request = None;
+                }
+            }
+        }
+    }
+
+    pub fn check(&mut self, now: Instant) -> LivenessCheck {
+        if let Some((_, sent_at)) = &self.test_request {
+            if now.duration_since(*sent_at) >= self.interval {
+                return LivenessCheck::Dead;
+            }
+            return LivenessCheck::Alive;
+        }
+        if now.duration_since(self.last_received) >= self.interval {
+            let test_req_id = uuid::Uuid::new_v4().to_string();
+            self.test_request = Some((test_req_id.clone(), now));
+            return LivenessCheck::SendTestRequest(test_req_id);
+        }
+        LivenessCheck::Alive
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        resend_messages, InboundSequencer, Liveness, LivenessCheck, MAX_QUEUED_MESSAGES,
+        RESEND_TIMEOUT,
+    };
+    use crate::{
+        messages::{raw_message, NewOrderSingleReq, RequestMessage, ResponseMessage},
+        types::{Config, Error, Field, OrderType, Side, SubID},
+    };
+    use std::time::{Duration, Instant};
+
+    fn msg(seq: u32, msg_type: &str, extra: &str) -> ResponseMessage {
+        ResponseMessage::new(
+            &raw_message(&format!("35={}|34={}|{}", msg_type, seq, extra), "|"),
+            "|",
+        )
+        .unwrap()
+    }
+
+    fn seqs(res: &[ResponseMessage]) -> Vec<u32> {
+        res.iter()
+            .map(|r| r.get_field_value(crate::types::Field::MsgSeqNum).unwrap())
+            .map(|v| v.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_in_order() {
+        let now = Instant::now();
+        let mut sequencer = InboundSequencer::new(1);
+        for seq in 1..=3 {
+            let res = sequencer.on_message(msg(seq, "0", ""), now).unwrap();
+            assert_eq!(seqs(&res.ready), vec![seq]);
+            assert!(res.resend.is_none());
+        }
+        assert_eq!(sequencer.expected(), 4);
+    }
+
+    #[test]
+    fn test_gap_and_resend() {
+        let now = Instant::now();
+        let mut sequencer = InboundSequencer::new(1);
+        sequencer.on_message(msg(1, "0", ""), now).unwrap();
+
+        let res = sequencer.on_message(msg(4, "8", ""), now).unwrap();
+        assert!(res.ready.is_empty());
+        assert_eq!(res.resend, Some((2, 0)));
+
+        // no more resend request while waiting
+        let res = sequencer.on_message(msg(5, "8", ""), now).unwrap();
+        assert!(res.resend.is_none());
+        // except the ResendRequest of the server
+        let res = sequencer.on_message(msg(6, "2", "7=1|16=0|"), now).unwrap();
+        assert_eq!(seqs(&res.ready), vec![6]);
+        assert!(!sequencer.queue.is_empty());
+
+        let res = sequencer.on_message(msg(2, "8", "43=Y|"), now).unwrap();
+        assert_eq!(seqs(&res.ready), vec![2]);
+        let res = sequencer.on_message(msg(3, "8", "43=Y|"), now).unwrap();
+        assert_eq!(seqs(&res.ready), vec![3, 4, 5]);
+        assert!(sequencer.queue.is_empty());
+        assert_eq!(sequencer.expected(), 7);
+
+        // duplicated
+        let res = sequencer.on_message(msg(4, "8", "43=Y|"), now).unwrap();
+        assert!(res.ready.is_empty());
+    }
+
+    #[test]
+    fn test_gap_fill() {
+        let now = Instant::now();
+        let mut sequencer = InboundSequencer::new(1);
+        let res = sequencer.on_message(msg(5, "8", ""), now).unwrap();
+        assert_eq!(res.resend, Some((1, 0)));
+
+        let res = sequencer
+            .on_message(msg(1, "4", "43=Y|123=Y|36=3|"), now)
+            .unwrap();
+        assert!(res.ready.is_empty());
+        assert_eq!(sequencer.expected(), 3);
+
+        let res = sequencer.on_message(msg(3, "8", "43=Y|"), now).unwrap();
+        assert_eq!(seqs(&res.ready), vec![3]);
+        let res = sequencer
+            .on_message(msg(4, "4", "43=Y|123=Y|36=5|"), now)
+            .unwrap();
+        assert_eq!(seqs(&res.ready), vec![5]);
+        assert_eq!(sequencer.expected(), 6);
+    }
+
+    #[test]
+    fn test_sequence_reset() {
+        let now = Instant::now();
+        let mut sequencer = InboundSequencer::new(1);
+        sequencer.on_message(msg(3, "8", ""), now).unwrap();
+        sequencer.on_message(msg(12, "8", ""), now).unwrap();
+
+        let res = sequencer.on_message(msg(1, "4", "36=10|"), now).unwrap();
+        assert!(res.ready.is_empty());
+        assert_eq!(sequencer.expected(), 10);
+        assert!(!sequencer.queue.is_empty());
+
+        let res = sequencer.on_message(msg(10, "0", ""), now).unwrap();
+        assert_eq!(seqs(&res.ready), vec![10]);
+        let res = sequencer.on_message(msg(11, "0", ""), now).unwrap();
+        assert_eq!(seqs(&res.ready), vec![11, 12]);
+    }
+
+    #[test]
+    fn test_seq_too_low() {
+        let now = Instant::now();
+        let mut sequencer = InboundSequencer::new(5);
+        // the logon reply of the server reset while the store kept the sequence numbers
+        assert!(matches!(
+            sequencer.on_logon(&msg(1, "A", ""), now),
+            Err(Error::SeqNumTooLow {
+                expected: 5,
+                received: 1
+            })
+        ));
+
+        let mut sequencer = InboundSequencer::new(1);
+        sequencer.on_message(msg(1, "0", ""), now).unwrap();
+        sequencer.on_message(msg(2, "0", ""), now).unwrap();
+        let res = sequencer.on_message(msg(1, "8", "43=Y|"), now).unwrap();
+        assert!(res.ready.is_empty());
+        assert!(matches!(
+            sequencer.on_message(msg(2, "8", ""), now),
+            Err(Error::SeqNumTooLow {
+                expected: 3,
+                received: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn test_logon_gap() {
+        let now = Instant::now();
+        let mut sequencer = InboundSequencer::new(3);
+        assert_eq!(
+            sequencer.on_logon(&msg(6, "A", ""), now).unwrap(),
+            Some((3, 0))
+        );
+
+        sequencer.on_message(msg(3, "8", "43=Y|"), now).unwrap();
+        let res = sequencer
+            .on_message(msg(4, "4", "43=Y|123=Y|36=6|"), now)
+            .unwrap();
+        assert!(res.ready.is_empty());
+        assert_eq!(sequencer.expected(), 7);
+
+        let mut sequencer = InboundSequencer::new(3);
+        assert!(sequencer
+            .on_logon(&msg(1, "A", "141=Y|"), now)
+            .unwrap()
+            .is_none());
+        assert_eq!(sequencer.expected(), 2);
+    }
+
+    #[test]
+    fn test_resend_timeout() {
+        let now = Instant::now();
+        let mut sequencer = InboundSequencer::new(1);
+        assert_eq!(
+            sequencer.on_message(msg(3, "8", ""), now).unwrap().resend,
+            Some((1, 0))
+        );
+        let later = now + Duration::from_secs(5);
+        assert!(sequencer
+            .on_message(msg(4, "0", ""), later)
+            .unwrap()
+            .resend
+            .is_none());
+
+        // not answered in time
+        let later = now + RESEND_TIMEOUT;
+        assert_eq!(
+            sequencer.on_message(msg(5, "0", ""), later).unwrap().resend,
+            Some((1, 0))
+        );
+        assert!(sequencer
+            .on_message(msg(6, "0", ""), later)
+            .unwrap()
+            .resend
+            .is_none());
+    }
+
+    #[test]
+    fn test_queue_limit() {
+        let now = Instant::now();
+        let mut sequencer = InboundSequencer::new(1);
+        for seq in 2..MAX_QUEUED_MESSAGES as u32 + 12 {
+            sequencer.on_message(msg(seq, "0", ""), now).unwrap();
+        }
+        assert_eq!(sequencer.queue.len(), MAX_QUEUED_MESSAGES);
+
+        // the dropped messages are resent
+        sequencer.on_message(msg(1, "0", ""), now).unwrap();
+        let last = MAX_QUEUED_MESSAGES as u32 + 2;
+        assert_eq!(sequencer.expected(), last);
+        let res = sequencer.on_message(msg(last, "0", "43=Y|"), now).unwrap();
+        assert_eq!(seqs(&res.ready), vec![last]);
+    }
+
+    #[test]
+    fn test_resend_messages() {
+        let config = Config::new(
+            "localhost".into(),
+            "user".into(),
+            "pass".into(),
+            "sender".into(),
+            30,
+        );
+        let order = |seq: u32| {
+            NewOrderSingleReq::new(
+                format!("order{}", seq),
+                1,
+                Side::BUY,
+                None,
+                1000.0,
+                OrderType::Market,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .build(SubID::TRADE, seq, "|", &config)
+        };
+        // 1 : logon, 2 : order, 3-4 : heartbeats, 5 : order, 6 : heartbeat
+        let stored = vec![(2, order(2)), (5, order(5))];
+        let messages = resend_messages(stored, 1, 6, SubID::TRADE, "|", &config)
+            .iter()
+            .map(|msg| ResponseMessage::new(msg, "|").unwrap())
+            .collect::<Vec<_>>();
+
+        let summary = messages
+            .iter()
+            .map(|res| {
+                (
+                    res.get_message_type().to_string(),
+                    res.get_field_value(Field::MsgSeqNum).unwrap(),
+                    res.get_field_value(Field::NewSeqNo),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("4".into(), "1".into(), Some("2".into())),
+                ("D".into(), "2".into(), None),
+                ("4".into(), "3".into(), Some("5".into())),
+                ("D".into(), "5".into(), None),
+                ("4".into(), "6".into(), Some("7".into())),
+            ]
+        );
+
+        for (res, msg) in messages.iter().zip(resend_messages(
+            vec![(2, order(2))],
+            1,
+            6,
+            SubID::TRADE,
+            "|",
+            &config,
+        )) {
+            assert_eq!(res.get_field_value(Field::PossDupFlag).unwrap(), "Y");
+            assert!(res.get_field_value(Field::OrigSendingTime).is_some());
+
+            // the body length and the checksum are recalculated
+            let body_start = msg.find("35=").unwrap();
+            let body_end = msg.find("|10=").unwrap() + 1;
+            let body_length = res.get_field_value(Field::BodyLength).unwrap();
+            assert_eq!(body_length, (body_end - body_start).to_string());
+            let checksum = msg[..body_end].bytes().map(|b| b as u32).sum::<u32>() % 256;
+            assert_eq!(format!("{:03}", checksum), msg[body_end + 3..body_end + 6]);
+        }
+    }
+
+    #[test]
+    fn test_liveness() {
+        let start = Instant::now();
+        let after = |secs: u64| start + Duration::from_secs(secs);
+        let mut liveness = Liveness::new(30, start);
+
+        assert_eq!(liveness.check(after(30)), LivenessCheck::Alive);
+        liveness.on_received(&msg(1, "8", ""), after(30));
+
+        let LivenessCheck::SendTestRequest(test_req_id) = liveness.check(after(66)) else {
+            panic!("test request expected");
+        };
+        assert_eq!(liveness.check(after(70)), LivenessCheck::Alive);
+
+        // a heartbeat without the id does not answer the test request
+        liveness.on_received(&msg(2, "0", ""), after(80));
+        assert_eq!(liveness.check(after(102)), LivenessCheck::Dead);
+
+        liveness.on_received(&msg(3, "0", &format!("112={}|", test_req_id)), after(103));
+        assert_eq!(liveness.check(after(104)), LivenessCheck::Alive);
+    }
+}
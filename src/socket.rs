@@ -3,36 +3,110 @@ use std::sync::{
     Arc,
 };
 
-use async_std::{
-    channel::Sender,
-    io::{BufReader, ReadExt},
-    net::TcpStream,
-    task,
+use async_channel::Sender;
+use async_lock::Mutex;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures_rustls::{
+    pki_types::{pem::PemObject, CertificateDer, ServerName},
+    rustls::{crypto::ring, ClientConfig, RootCertStore},
+    TlsConnector,
 };
 
 use crate::{
+    framing::{Framer, InvalidFrame},
     messages::ResponseMessage,
-    types::{ConnectionHandler, Error, DELIMITER},
+    runtime::{self, StreamHandle},
+    types::{ConnectionHandler, Error, TlsConfig, Transport, DELIMITER},
 };
 
+type BoxedReader = Box<dyn AsyncRead + Send + Unpin>;
+type BoxedWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Write half of the socket.
+///
+/// It can be cloned and shared between the tasks. Each message is written and flushed while
+/// holding the lock so the messages are never interleaved.
+#[derive(Clone)]
+pub struct SocketWriter {
+    stream: Arc<StreamHandle>,
+    writer: Arc<Mutex<BoxedWriter>>,
+}
+
+impl SocketWriter {
+    pub async fn send(&self, msg: &str) -> std::io::Result<()> {
+        let mut writer = self.writer.lock().await;
+        writer.write_all(msg.as_bytes()).await?;
+        writer.flush().await
+    }
+
+    pub fn shutdown(&self) -> std::io::Result<()> {
+        self.stream.shutdown()
+    }
+}
+
+fn tls_connector(config: &TlsConfig) -> Result<TlsConnector, Error> {
+    let mut roots = RootCertStore::empty();
+    if config.webpki_roots {
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    }
+    for pem in config.root_certs.iter() {
+        for cert in CertificateDer::pem_slice_iter(pem.as_bytes()) {
+            let cert = cert.map_err(|err| Error::TlsError(err.to_string()))?;
+            roots
+                .add(cert)
+                .map_err(|err| Error::TlsError(err.to_string()))?;
+        }
+    }
+
+    let client_config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|err| Error::TlsError(err.to_string()))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(client_config)))
+}
+
 pub struct Socket {
-    pub stream: Arc<TcpStream>,
+    pub writer: SocketWriter,
+    reader: BoxedReader,
     res_sender: Sender<ResponseMessage>,
-    msg_buffer: String,
+    framer: Framer,
 }
 impl Socket {
     pub async fn connect(
         server: &str,
         port: u16,
+        transport: &Transport,
         res_sender: Sender<ResponseMessage>,
-    ) -> std::io::Result<Self> {
+    ) -> Result<Self, Error> {
         let addr = format!("{}:{}", server, port);
-        let stream = TcpStream::connect(addr).await?;
+        let (stream, handle) = runtime::connect(&addr).await?;
+
+        let (reader, writer): (BoxedReader, BoxedWriter) = match transport {
+            Transport::Plain => {
+                let (reader, writer) = stream.split();
+                (Box::new(reader), Box::new(writer))
+            }
+            Transport::Tls(tls) => {
+                let connector = tls_connector(tls)?;
+                let server_name =
+                    ServerName::try_from(tls.server_name.as_deref().unwrap_or(server).to_string())
+                        .map_err(|err| Error::TlsError(err.to_string()))?;
+                let tls_stream = connector.connect(server_name, stream).await?;
+                log::debug!("TLS handshake completed with {}", server);
+                let (reader, writer) = tls_stream.split();
+                (Box::new(reader), Box::new(writer))
+            }
+        };
+
         Ok(Socket {
-            stream: Arc::new(stream),
+            writer: SocketWriter {
+                stream: Arc::new(handle),
+                writer: Arc::new(Mutex::new(writer)),
+            },
+            reader,
             res_sender,
-            // res_msg: Arc::new(Mutex::new(VecDeque::new())),
-            msg_buffer: String::new(),
+            framer: Framer::new(),
         })
     }
 
@@ -41,73 +115,187 @@ impl Socket {
         is_connected: Arc<AtomicBool>,
         handler: Option<Arc<dyn ConnectionHandler + Send + Sync>>,
     ) -> Result<(), Error> {
-        let mut reader = BufReader::new(self.stream.as_ref());
         let mut buffer = vec![0u8; 4096];
         loop {
-            let bytes_read = reader.read(&mut buffer).await?;
+            let bytes_read = match self.reader.read(&mut buffer).await {
+                Ok(bytes_read) => bytes_read,
+                Err(err) => {
+                    log::error!("Failed to read the stream - {:?}", err);
+                    break;
+                }
+            };
             if bytes_read == 0 {
                 log::debug!("stream disconnected");
                 break;
             }
-            let res = String::from_utf8_lossy(&buffer[..bytes_read]);
-            log::trace!("Received msg : {}", res);
-            self.msg_buffer.push_str(res.as_ref());
+            self.framer.extend(&buffer[..bytes_read]);
 
-            loop {
-                let mut pos = self
-                    .msg_buffer
-                    .find(&format!("{}10=", DELIMITER))
-                    .unwrap_or(usize::MAX);
-
-                if pos == usize::MAX {
-                    break;
-                }
-                pos += 8;
-                //
-                let mut rest = None;
-                let res = if pos >= self.msg_buffer.len() {
-                    // send itself.
-                    ResponseMessage::new(&self.msg_buffer, DELIMITER)
-                } else {
-                    // } else if pos < self.msg_buffer.len() {
-                    rest = Some(self.msg_buffer.split_off(pos));
-                    ResponseMessage::new(&self.msg_buffer, DELIMITER)
-                };
-                // else {
-                //     println!("\n{:?}\n", self.msg_buffer);
-                //     break;
-                // };
-
-                log::debug!("Handle the response : {}", self.msg_buffer);
-                if let Err(err) = self.res_sender.send(res).await {
-                    log::error!("Failed to send ResponseMessage : {:?}", err);
-                    break;
-                }
-
-                if let Some(rest) = rest {
-                    self.msg_buffer = rest;
-                } else {
-                    self.msg_buffer.clear();
-                    break;
+            while let Some(res) = self.framer.next_message() {
+                match res {
+                    Ok(res) => {
+                        log::debug!(
+                            "Handle the response : {}",
+                            res.get_message().replace(DELIMITER, "|")
+                        );
+                        if let Err(err) = self.res_sender.send(res).await {
+                            log::error!("Failed to send ResponseMessage : {:?}", err);
+                            break;
+                        }
+                    }
+                    Err(InvalidFrame { raw, err }) => {
+                        log::error!("Invalid message({}) : {}", err, raw);
+                        if let Some(handler) = handler.clone() {
+                            runtime::spawn(async move {
+                                handler.on_invalid_message(raw, err).await;
+                            });
+                        }
+                    }
                 }
             }
-            // FIXME
-            // if self.msg_buffer.find(&format!("{}10=", DELIMITER)).is_some()
-            //     && self.msg_buffer.ends_with(DELIMITER)
-            // {
-            //     log::debug!("Handle response : {}", self.msg_buffer);
-            //     let res = ResponseMessage::new(&self.msg_buffer, DELIMITER);
-            //     self.msg_buffer.clear();
-            // }
         }
 
         is_connected.store(false, Ordering::Relaxed);
         // notify disconnection
         if let Some(handler) = handler {
-            task::spawn(async move {
+            runtime::spawn(async move {
                 handler.on_disconnect().await;
             });
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Socket;
+    use crate::{
+        messages::{HeartbeatReq, RequestMessage, TestReq},
+        runtime::{self, TcpListener},
+        types::{Config, SubID, TlsConfig, Transport, DELIMITER},
+    };
+    use async_channel::{bounded, Receiver};
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use futures_rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+        rustls::{crypto::ring, ServerConfig},
+        TlsAcceptor,
+    };
+    use std::sync::Arc;
+
+    fn test_config() -> Config {
+        Config::new(
+            "localhost".into(),
+            "user".into(),
+            "pass".into(),
+            "sender".into(),
+            30,
+        )
+    }
+
+    /// Spawns a TLS acceptor with a self-signed certificate for `localhost`.
+    /// It sends a heartbeat after the handshake and returns the first message it received.
+    async fn spawn_tls_acceptor() -> (u16, String, Receiver<String>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let cert_pem = certified.cert.pem();
+        let cert: CertificateDer<'static> = certified.cert.der().clone();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+            certified.signing_key.serialize_der(),
+        ));
+
+        let server_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key)
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.port().unwrap();
+
+        let (sender, receiver) = bounded(1);
+        runtime::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(_) => {
+                    sender.send(String::new()).await.ok();
+                    return;
+                }
+            };
+            let heartbeat =
+                HeartbeatReq::new(None).build(SubID::QUOTE, 1, DELIMITER, &test_config());
+            stream.write_all(heartbeat.as_bytes()).await.unwrap();
+            stream.flush().await.unwrap();
+
+            let mut buffer = vec![0u8; 1024];
+            let n = stream.read(&mut buffer).await.unwrap_or(0);
+            sender
+                .send(String::from_utf8_lossy(&buffer[..n]).to_string())
+                .await
+                .ok();
+        });
+        (port, cert_pem, receiver)
+    }
+
+    #[test]
+    fn test_default_ports() {
+        let config = test_config();
+        assert_eq!(config.port_for(SubID::QUOTE), 5201);
+        assert_eq!(config.port_for(SubID::TRADE), 5202);
+
+        let config = config.with_tls(TlsConfig::default());
+        assert_eq!(config.port_for(SubID::QUOTE), 5211);
+        assert_eq!(config.port_for(SubID::TRADE), 5212);
+
+        let config = config.with_port(15211);
+        assert_eq!(config.port_for(SubID::TRADE), 15211);
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_tls_connect_with_self_signed_root() {
+        let (port, cert_pem, server) = spawn_tls_acceptor().await;
+
+        let tls = TlsConfig {
+            webpki_roots: false,
+            ..TlsConfig::default()
+        }
+        .add_root_cert(cert_pem);
+        let (sender, receiver) = bounded(1);
+        let mut socket = Socket::connect("localhost", port, &Transport::Tls(tls), sender)
+            .await
+            .unwrap();
+        let writer = socket.writer.clone();
+        runtime::spawn(async move {
+            socket
+                .recv_loop(Arc::new(std::sync::atomic::AtomicBool::new(true)), None)
+                .await
+                .ok();
+        });
+
+        // receive the message through the encrypted stream
+        let res = receiver.recv().await.unwrap();
+        assert_eq!(res.get_message_type(), "0");
+
+        // and send one back
+        let req = TestReq::new("test".into()).build(SubID::QUOTE, 1, DELIMITER, &test_config());
+        writer.send(&req).await.unwrap();
+        assert_eq!(server.recv().await.unwrap(), req);
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_tls_rejects_untrusted_certificate() {
+        let (port, _, server) = spawn_tls_acceptor().await;
+
+        let tls = TlsConfig {
+            webpki_roots: false,
+            ..TlsConfig::default()
+        };
+        let (sender, _receiver) = bounded(1);
+        let res = Socket::connect("localhost", port, &Transport::Tls(tls), sender).await;
+        assert!(res.is_err());
+        assert_eq!(server.recv().await.unwrap(), "");
+    }
+}
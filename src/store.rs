@@ -0,0 +1,575 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use async_trait::async_trait;
+use chrono::{NaiveDate, Utc};
+
+use crate::{runtime, types::Error};
+
+/// Storage of the session state : the sequence numbers and the sent messages.
+///
+/// The sent messages are used to answer the ResendRequest of the server.
+#[async_trait]
+pub trait SessionStore {
+    /// Returns the sequence number of the next outgoing message.
+    async fn next_sender_seq_num(&self) -> Result<u32, Error>;
+
+    /// Returns the expected sequence number of the next incoming message.
+    async fn next_target_seq_num(&self) -> Result<u32, Error>;
+
+    async fn set_next_sender_seq_num(&self, seq: u32) -> Result<(), Error>;
+
+    /// Sets the next sender sequence number only if it is larger than the current one, so that
+    /// the concurrent senders never move it backwards.
+    async fn advance_next_sender_seq_num(&self, seq: u32) -> Result<(), Error> {
+        if seq > self.next_sender_seq_num().await? {
+            self.set_next_sender_seq_num(seq).await?;
+        }
+        Ok(())
+    }
+
+    async fn set_next_target_seq_num(&self, seq: u32) -> Result<(), Error>;
+
+    /// Saves the sent message. The next sender sequence number becomes `seq + 1` if it is larger.
+    async fn save_message(&self, seq: u32, message: &str) -> Result<(), Error>;
+
+    /// Returns the sent messages between `begin` and `end` inclusive, ordered by the sequence number.
+    /// `end` of 0 means no upper bound.
+    async fn get_messages(&self, begin: u32, end: u32) -> Result<Vec<(u32, String)>, Error>;
+
+    /// Resets the sequence numbers to 1 and removes the sent messages.
+    async fn reset(&self) -> Result<(), Error>;
+
+    /// Writes the state kept in memory, if any. Called by the session periodically and on logout.
+    async fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Whether the state survives the restart of the process.
+    ///
+    /// The session logs on without ResetSeqNumFlag(141) when the store is persistent.
+    fn is_persistent(&self) -> bool {
+        false
+    }
+}
+
+/// Number of the sent messages kept by `MemoryStore::new`.
+pub const DEFAULT_MAX_MESSAGES: usize = 1000;
+
+fn in_range(seq: u32, begin: u32, end: u32) -> bool {
+    seq >= begin && (end == 0 || seq <= end)
+}
+
+#[derive(Debug)]
+struct MemoryState {
+    next_sender_seq: u32,
+    next_target_seq: u32,
+    messages: VecDeque<(u32, String)>,
+    /// The oldest messages are dropped above it.
+    max_messages: usize,
+}
+
+impl Default for MemoryState {
+    fn default() -> Self {
+        Self {
+            next_sender_seq: 1,
+            next_target_seq: 1,
+            messages: VecDeque::new(),
+            max_messages: usize::MAX,
+        }
+    }
+}
+
+impl MemoryState {
+    fn save_message(&mut self, seq: u32, message: &str) {
+        self.next_sender_seq = self.next_sender_seq.max(seq + 1);
+        self.messages.push_back((seq, message.to_string()));
+        while self.messages.len() > self.max_messages {
+            self.messages.pop_front();
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self {
+            max_messages: self.max_messages,
+            ..Self::default()
+        };
+    }
+
+    fn get_messages(&self, begin: u32, end: u32) -> Vec<(u32, String)> {
+        let mut messages = self
+            .messages
+            .iter()
+            .filter(|(seq, _)| in_range(*seq, begin, end))
+            .cloned()
+            .collect::<Vec<_>>();
+        messages.sort_by_key(|(seq, _)| *seq);
+        messages
+    }
+}
+
+/// In-memory session store. It is used by default and the state is lost when the process exits.
+///
+/// Only the last `DEFAULT_MAX_MESSAGES` sent messages are kept. The older ones requested by the
+/// server are replaced by SequenceReset-GapFill.
+#[derive(Debug)]
+pub struct MemoryStore {
+    state: Mutex<MemoryState>,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_MAX_MESSAGES)
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps the last `max_messages` sent messages.
+    pub fn with_capacity(max_messages: usize) -> Self {
+        Self {
+            state: Mutex::new(MemoryState {
+                max_messages,
+                ..MemoryState::default()
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemoryStore {
+    async fn next_sender_seq_num(&self) -> Result<u32, Error> {
+        Ok(self.state.lock().unwrap().next_sender_seq)
+    }
+
+    async fn next_target_seq_num(&self) -> Result<u32, Error> {
+        Ok(self.state.lock().unwrap().next_target_seq)
+    }
+
+    async fn set_next_sender_seq_num(&self, seq: u32) -> Result<(), Error> {
+        self.state.lock().unwrap().next_sender_seq = seq;
+        Ok(())
+    }
+
+    async fn advance_next_sender_seq_num(&self, seq: u32) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        state.next_sender_seq = state.next_sender_seq.max(seq);
+        Ok(())
+    }
+
+    async fn set_next_target_seq_num(&self, seq: u32) -> Result<(), Error> {
+        self.state.lock().unwrap().next_target_seq = seq;
+        Ok(())
+    }
+
+    async fn save_message(&self, seq: u32, message: &str) -> Result<(), Error> {
+        self.state.lock().unwrap().save_message(seq, message);
+        Ok(())
+    }
+
+    async fn get_messages(&self, begin: u32, end: u32) -> Result<Vec<(u32, String)>, Error> {
+        Ok(self.state.lock().unwrap().get_messages(begin, end))
+    }
+
+    async fn reset(&self) -> Result<(), Error> {
+        self.state.lock().unwrap().reset();
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct FileState {
+    date: NaiveDate,
+    state: MemoryState,
+    messages_file: File,
+    /// The next target sequence number is changed after the last write.
+    dirty: bool,
+}
+
+#[derive(Debug)]
+struct FileInner {
+    seqnums_path: PathBuf,
+    seqnums_temp_path: PathBuf,
+    messages_path: PathBuf,
+    state: Mutex<FileState>,
+}
+
+/// File-backed session store.
+///
+/// The directory holds two files:
+/// * `seqnums` - the session date and the next sender/target sequence numbers. It is replaced
+///   through a temporary file, and the next sender sequence number is recovered from the saved
+///   messages if it is broken.
+/// * `messages` - the sent messages, appended one per line.
+///
+/// The file I/O runs on the blocking threads, not on the executor. The sent messages and the next
+/// sender sequence number are written before the message is sent, so they survive a crash. The
+/// next target sequence number is written by `flush`, which the session calls every second and
+/// on logout. After a crash it can be behind, and the server resends the last messages with
+/// PossDupFlag.
+///
+/// The session of cTrader lasts for a day, so the store is reset when the UTC date changes.
+/// Use a separate directory for each session (QUOTE and TRADE).
+#[derive(Debug)]
+pub struct FileStore {
+    inner: Arc<FileInner>,
+}
+
+impl FileStore {
+    /// Opens the store in `dir` and loads the state of today. The directory is created if it does not exist.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, Error> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let seqnums_path = dir.join("seqnums");
+        let seqnums_temp_path = dir.join("seqnums.tmp");
+        let messages_path = dir.join("messages");
+        let today = Utc::now().date_naive();
+
+        let mut state = MemoryState::default();
+        let mut date = today;
+        if let Ok(content) = fs::read_to_string(&seqnums_path) {
+            let parts = content.split_whitespace().collect::<Vec<_>>();
+            if let [saved_date, sender, target] = parts[..] {
+                date = NaiveDate::parse_from_str(saved_date, "%Y%m%d").unwrap_or(today);
+                state.next_sender_seq = sender.parse().unwrap_or(1);
+                state.next_target_seq = target.parse().unwrap_or(1);
+            }
+        }
+
+        if messages_path.exists() {
+            let reader = BufReader::new(File::open(&messages_path)?);
+            for line in reader.lines() {
+                let line = line?;
+                if let Some((seq, message)) = line.split_once(',') {
+                    if let Ok(seq) = seq.parse::<u32>() {
+                        state.save_message(seq, message);
+                    }
+                }
+            }
+        }
+
+        let messages_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&messages_path)?;
+
+        let inner = FileInner {
+            seqnums_path,
+            seqnums_temp_path,
+            messages_path,
+            state: Mutex::new(FileState {
+                date,
+                state,
+                messages_file,
+                dirty: false,
+            }),
+        };
+        {
+            let mut state = inner.state.lock().unwrap();
+            if state.date != today {
+                inner.reset_state(&mut state, today)?;
+            } else {
+                inner.write_seqnums(&mut state)?;
+            }
+        }
+        Ok(Self {
+            inner: Arc::new(inner),
+        })
+    }
+
+    /// Runs `f` on the blocking threads.
+    async fn blocking<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&FileInner) -> Result<T, Error> + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        runtime::spawn_blocking(move || f(&inner)).await
+    }
+}
+
+impl FileInner {
+    fn write_seqnums(&self, state: &mut FileState) -> Result<(), Error> {
+        // the rename replaces the file at once, so a crash never leaves it empty
+        fs::write(
+            &self.seqnums_temp_path,
+            format!(
+                "{} {} {}",
+                state.date.format("%Y%m%d"),
+                state.state.next_sender_seq,
+                state.state.next_target_seq
+            ),
+        )?;
+        fs::rename(&self.seqnums_temp_path, &self.seqnums_path)?;
+        state.dirty = false;
+        Ok(())
+    }
+
+    fn reset_state(&self, state: &mut FileState, date: NaiveDate) -> Result<(), Error> {
+        state.date = date;
+        state.state.reset();
+        state.messages_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.messages_path)?;
+        self.write_seqnums(state)
+    }
+
+    fn lock_today(&self) -> Result<MutexGuard<'_, FileState>, Error> {
+        let mut state = self.state.lock().unwrap();
+        let today = Utc::now().date_naive();
+        if state.date != today {
+            log::info!("Session date changed, reset the session store");
+            self.reset_state(&mut state, today)?;
+        }
+        Ok(state)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.dirty {
+            self.write_seqnums(&mut state)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FileInner {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            log::error!("Failed to flush the session store - {:?}", err);
+        }
+    }
+}
+
+#[async_trait]
+impl SessionStore for FileStore {
+    async fn next_sender_seq_num(&self) -> Result<u32, Error> {
+        self.blocking(|inner| Ok(inner.lock_today()?.state.next_sender_seq))
+            .await
+    }
+
+    async fn next_target_seq_num(&self) -> Result<u32, Error> {
+        self.blocking(|inner| Ok(inner.lock_today()?.state.next_target_seq))
+            .await
+    }
+
+    async fn set_next_sender_seq_num(&self, seq: u32) -> Result<(), Error> {
+        self.blocking(move |inner| {
+            let mut state = inner.state.lock().unwrap();
+            state.state.next_sender_seq = seq;
+            inner.write_seqnums(&mut state)
+        })
+        .await
+    }
+
+    async fn advance_next_sender_seq_num(&self, seq: u32) -> Result<(), Error> {
+        self.blocking(move |inner| {
+            let mut state = inner.lock_today()?;
+            if seq <= state.state.next_sender_seq {
+                return Ok(());
+            }
+            state.state.next_sender_seq = seq;
+            inner.write_seqnums(&mut state)
+        })
+        .await
+    }
+
+    /// Written by `flush`, not to write a file for every received message.
+    async fn set_next_target_seq_num(&self, seq: u32) -> Result<(), Error> {
+        let mut state = self.inner.state.lock().unwrap();
+        state.state.next_target_seq = seq;
+        state.dirty = true;
+        Ok(())
+    }
+
+    async fn save_message(&self, seq: u32, message: &str) -> Result<(), Error> {
+        let message = message.to_string();
+        self.blocking(move |inner| {
+            let mut state = inner.state.lock().unwrap();
+            writeln!(state.messages_file, "{},{}", seq, message)?;
+            state.state.save_message(seq, &message);
+            inner.write_seqnums(&mut state)
+        })
+        .await
+    }
+
+    async fn get_messages(&self, begin: u32, end: u32) -> Result<Vec<(u32, String)>, Error> {
+        Ok(self
+            .inner
+            .state
+            .lock()
+            .unwrap()
+            .state
+            .get_messages(begin, end))
+    }
+
+    async fn reset(&self) -> Result<(), Error> {
+        self.blocking(|inner| {
+            let mut state = inner.state.lock().unwrap();
+            inner.reset_state(&mut state, Utc::now().date_naive())
+        })
+        .await
+    }
+
+    async fn flush(&self) -> Result<(), Error> {
+        self.blocking(|inner| inner.flush()).await
+    }
+
+    fn is_persistent(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileStore, MemoryStore, SessionStore};
+    use chrono::{Duration, Utc};
+    use std::fs;
+
+    fn temp_dir() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("cfix-store-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_memory_store() {
+        let store = MemoryStore::new();
+        assert_eq!(store.next_sender_seq_num().await.unwrap(), 1);
+
+        store.save_message(1, "first").await.unwrap();
+        store.save_message(2, "second").await.unwrap();
+        store.save_message(3, "third").await.unwrap();
+        assert_eq!(store.next_sender_seq_num().await.unwrap(), 4);
+        // never moves backwards
+        store.advance_next_sender_seq_num(3).await.unwrap();
+        assert_eq!(store.next_sender_seq_num().await.unwrap(), 4);
+        store.advance_next_sender_seq_num(5).await.unwrap();
+        assert_eq!(store.next_sender_seq_num().await.unwrap(), 5);
+
+        let messages = store.get_messages(2, 0).await.unwrap();
+        assert_eq!(
+            messages,
+            vec![(2, "second".to_string()), (3, "third".to_string())]
+        );
+        assert_eq!(store.get_messages(1, 1).await.unwrap().len(), 1);
+
+        store.reset().await.unwrap();
+        assert_eq!(store.next_sender_seq_num().await.unwrap(), 1);
+        assert!(store.get_messages(1, 0).await.unwrap().is_empty());
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_memory_store_capacity() {
+        let store = MemoryStore::with_capacity(2);
+        for seq in 1..=5 {
+            store.save_message(seq, "message").await.unwrap();
+        }
+        let seqs = store.get_messages(1, 0).await.unwrap();
+        assert_eq!(seqs.iter().map(|v| v.0).collect::<Vec<_>>(), vec![4, 5]);
+        assert_eq!(store.next_sender_seq_num().await.unwrap(), 6);
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_file_store_restores_state() {
+        let dir = temp_dir();
+        {
+            let store = FileStore::open(&dir).unwrap();
+            store.save_message(1, "8=FIX.4.4\u{1}35=A").await.unwrap();
+            store.save_message(2, "8=FIX.4.4\u{1}35=D").await.unwrap();
+            store.set_next_target_seq_num(5).await.unwrap();
+        }
+
+        let store = FileStore::open(&dir).unwrap();
+        assert!(store.is_persistent());
+        assert_eq!(store.next_sender_seq_num().await.unwrap(), 3);
+        assert_eq!(store.next_target_seq_num().await.unwrap(), 5);
+        assert_eq!(
+            store.get_messages(2, 2).await.unwrap(),
+            vec![(2, "8=FIX.4.4\u{1}35=D".to_string())]
+        );
+
+        store.reset().await.unwrap();
+        drop(store);
+        let store = FileStore::open(&dir).unwrap();
+        assert_eq!(store.next_sender_seq_num().await.unwrap(), 1);
+        assert!(store.get_messages(1, 0).await.unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_file_store_flush() {
+        let dir = temp_dir();
+        let store = FileStore::open(&dir).unwrap();
+        let saved = || fs::read_to_string(dir.join("seqnums")).unwrap();
+
+        // the target is written by flush, the sender before the message is sent
+        store.set_next_target_seq_num(7).await.unwrap();
+        assert!(saved().ends_with(" 1 1"));
+        store.flush().await.unwrap();
+        assert!(saved().ends_with(" 1 7"));
+        store.save_message(1, "first").await.unwrap();
+        assert!(saved().ends_with(" 2 7"));
+
+        // and on drop
+        store.set_next_target_seq_num(8).await.unwrap();
+        drop(store);
+        assert!(saved().ends_with(" 2 8"));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_file_store_broken_seqnums() {
+        let dir = temp_dir();
+        {
+            let store = FileStore::open(&dir).unwrap();
+            store.save_message(1, "first").await.unwrap();
+            store.save_message(2, "second").await.unwrap();
+        }
+        // emptied by a crash
+        fs::write(dir.join("seqnums"), "").unwrap();
+
+        let store = FileStore::open(&dir).unwrap();
+        assert_eq!(store.next_sender_seq_num().await.unwrap(), 3);
+        assert_eq!(store.get_messages(1, 0).await.unwrap().len(), 2);
+        assert!(!dir.join("seqnums.tmp").exists());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_file_store_resets_on_new_day() {
+        let dir = temp_dir();
+        fs::create_dir_all(&dir).unwrap();
+        let yesterday = Utc::now().date_naive() - Duration::days(1);
+        fs::write(
+            dir.join("seqnums"),
+            format!("{} 10 20", yesterday.format("%Y%m%d")),
+        )
+        .unwrap();
+        fs::write(dir.join("messages"), "9,old\n").unwrap();
+
+        let store = FileStore::open(&dir).unwrap();
+        assert_eq!(store.next_sender_seq_num().await.unwrap(), 1);
+        assert_eq!(store.next_target_seq_num().await.unwrap(), 1);
+        assert!(store.get_messages(1, 0).await.unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,188 @@
+//! Streams of the market data events.
+
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+
+use async_channel::{bounded, Receiver, Sender};
+use futures::Stream;
+
+use crate::types::Error;
+
+/// What to do when the buffer of the stream is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Drops the oldest event silently.
+    #[default]
+    DropOldest,
+    /// Drops the oldest event, and yields `Error::Lagged` with the number of the dropped events
+    /// before the next event.
+    Lagged,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct StreamOptions {
+    /// Number of the events buffered for the stream.
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            overflow: OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+/// Stream of the market data events of a symbol, in the order of the messages.
+///
+/// The stream ends when the client is dropped.
+pub struct MarketDataStream<T> {
+    receiver: Pin<Box<Receiver<T>>>,
+    lagged: Arc<AtomicU64>,
+    overflow: OverflowPolicy,
+}
+
+impl<T> Stream for MarketDataStream<T> {
+    type Item = Result<T, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.overflow == OverflowPolicy::Lagged {
+            let lagged = self.lagged.swap(0, Ordering::Relaxed);
+            if lagged > 0 {
+                return Poll::Ready(Some(Err(Error::Lagged(lagged))));
+            }
+        }
+        self.receiver.as_mut().poll_next(cx).map(|v| v.map(Ok))
+    }
+}
+
+struct StreamSender<T> {
+    sender: Sender<T>,
+    lagged: Arc<AtomicU64>,
+}
+
+impl<T> StreamSender<T> {
+    /// Returns `false` if the stream is dropped.
+    fn send(&self, event: T) -> bool {
+        match self.sender.force_send(event) {
+            Ok(Some(_)) => {
+                self.lagged.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Ok(None) => true,
+            Err(_) => false,
+        }
+    }
+}
+
+/// Streams of the symbols.
+pub(crate) struct Subscribers<T> {
+    streams: Mutex<HashMap<u32, Vec<StreamSender<T>>>>,
+}
+
+impl<T> Default for Subscribers<T> {
+    fn default() -> Self {
+        Self {
+            streams: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: Clone> Subscribers<T> {
+    pub fn stream(&self, symbol_id: u32, options: StreamOptions) -> MarketDataStream<T> {
+        let (sender, receiver) = bounded(options.capacity.max(1));
+        let lagged = Arc::new(AtomicU64::new(0));
+        self.streams
+            .lock()
+            .unwrap()
+            .entry(symbol_id)
+            .or_default()
+            .push(StreamSender {
+                sender,
+                lagged: lagged.clone(),
+            });
+        MarketDataStream {
+            receiver: Box::pin(receiver),
+            lagged,
+            overflow: options.overflow,
+        }
+    }
+
+    pub fn publish(&self, symbol_id: u32, event: T) {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some(senders) = streams.get_mut(&symbol_id) {
+            senders.retain(|sender| sender.send(event.clone()));
+            if senders.is_empty() {
+                streams.remove(&symbol_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OverflowPolicy, StreamOptions, Subscribers};
+    use crate::types::Error;
+    use futures::{executor::block_on, StreamExt};
+
+    #[test]
+    fn test_drop_oldest() {
+        let subscribers = Subscribers::default();
+        let mut stream = subscribers.stream(
+            1,
+            StreamOptions {
+                capacity: 2,
+                overflow: OverflowPolicy::DropOldest,
+            },
+        );
+        for event in 0..5 {
+            subscribers.publish(1, event);
+        }
+        subscribers.publish(2, 100);
+        drop(subscribers);
+
+        let events = block_on(stream.by_ref().map(|v| v.unwrap()).collect::<Vec<_>>());
+        assert_eq!(events, vec![3, 4]);
+    }
+
+    #[test]
+    fn test_lagged() {
+        let subscribers = Subscribers::default();
+        let mut stream = subscribers.stream(
+            1,
+            StreamOptions {
+                capacity: 2,
+                overflow: OverflowPolicy::Lagged,
+            },
+        );
+        for event in 0..5 {
+            subscribers.publish(1, event);
+        }
+
+        assert!(matches!(
+            block_on(stream.next()),
+            Some(Err(Error::Lagged(3)))
+        ));
+        assert_eq!(block_on(stream.next()).unwrap().unwrap(), 3);
+        subscribers.publish(1, 5);
+        assert_eq!(block_on(stream.next()).unwrap().unwrap(), 4);
+        assert_eq!(block_on(stream.next()).unwrap().unwrap(), 5);
+    }
+
+    #[test]
+    fn test_dropped_stream_is_removed() {
+        let subscribers = Subscribers::default();
+        drop(subscribers.stream(1, StreamOptions::default()));
+        subscribers.publish(1, 0);
+        assert!(subscribers.streams.lock().unwrap().is_empty());
+    }
+}
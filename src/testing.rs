@@ -0,0 +1,1408 @@
+//! Mock cTrader FIX server for the integration tests.
+//!
+//! The server accepts the QUOTE and TRADE sessions on one local port and answers the requests
+//! the way cTrader does. It is enabled with the `testing` feature.
+//!
+//! ```no_run
+//! # async fn run() -> std::io::Result<()> {
+//! use cfix::{testing::MockServer, MarketClient};
+//!
+//! let server = MockServer::start().await?;
+//! server.add_symbol(1, "EURUSD", 5, 1.1, 1.2).await;
+//!
+//! let mut client = MarketClient::with_config(server.config());
+//! client.connect().await.unwrap();
+//! client.subscribe_spot(1).await.unwrap();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+
+use async_lock::Mutex;
+use chrono::Utc;
+use futures::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+
+use crate::{
+    framing::Framer,
+    messages::{raw_message, ResponseMessage},
+    runtime::{self, StreamHandle, TcpListener, TcpStream},
+    types::{Config, Field, DELIMITER},
+};
+
+const TIME_FORMAT: &str = "%Y%m%d-%H:%M:%S%.3f";
+const DEPTH_SIZE: f64 = 1_000_000.0;
+
+type Fields = Vec<(u32, String)>;
+
+fn field(tag: impl Into<u32>, value: impl ToString) -> (u32, String) {
+    (tag.into(), value.to_string())
+}
+
+fn value(req: &ResponseMessage, field: Field) -> String {
+    req.get_field_value(field).unwrap_or_default()
+}
+
+fn parse<T: std::str::FromStr>(req: &ResponseMessage, field: Field) -> Option<T> {
+    req.get_field_str(field).and_then(|v| v.parse().ok())
+}
+
+fn now() -> String {
+    Utc::now().naive_utc().format(TIME_FORMAT).to_string()
+}
+
+#[derive(Debug, Clone)]
+struct MockSymbol {
+    name: String,
+    digits: u32,
+    bid: f64,
+    ask: f64,
+    bid_entry: String,
+    ask_entry: String,
+}
+
+impl MockSymbol {
+    fn entries(&self, depth: bool) -> Fields {
+        let mut fields = vec![field(Field::NoMDEntries, 2)];
+        for (entry_type, price, entry_id) in [
+            ("0", self.bid, &self.bid_entry),
+            ("1", self.ask, &self.ask_entry),
+        ] {
+            fields.push(field(Field::MDEntryType, entry_type));
+            fields.push(field(Field::MDEntryPx, price));
+            if depth {
+                fields.push(field(Field::MDEntrySize, DEPTH_SIZE));
+                fields.push(field(Field::MDEntryID, entry_id));
+            }
+        }
+        fields
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MockOrder {
+    order_id: u64,
+    pos_id: u64,
+    cl_ord_id: String,
+    symbol: u32,
+    side: String,
+    ord_type: String,
+    qty: f64,
+    price: Option<f64>,
+    stop_px: Option<f64>,
+    time_in_force: String,
+    expire_time: Option<String>,
+}
+
+impl MockOrder {
+    fn report(&self, exec_type: &str, ord_status: &str, fill_px: Option<f64>) -> Fields {
+        let mut fields = vec![
+            field(Field::OrderID, self.order_id),
+            field(Field::ClOrdId, &self.cl_ord_id),
+            field(Field::ExecID, format!("{}-{}", self.order_id, exec_type)),
+            field(Field::ExecType, exec_type),
+            field(Field::OrdStatus, ord_status),
+            field(Field::Symbol, self.symbol),
+            field(Field::Side, &self.side),
+            field(Field::TransactTime, now()),
+            field(Field::OrderQty, self.qty),
+            field(Field::OrdType, &self.ord_type),
+            field(Field::TimeInForce, &self.time_in_force),
+            field(Field::PosMaintRptID, self.pos_id),
+        ];
+        if let Some(price) = self.price {
+            fields.push(field(Field::Price, price));
+        }
+        if let Some(stop_px) = self.stop_px {
+            fields.push(field(Field::StopPx, stop_px));
+        }
+        if let Some(expire_time) = &self.expire_time {
+            fields.push(field(Field::ExpireTime, expire_time));
+        }
+        match fill_px {
+            Some(px) => fields.extend([
+                field(Field::AvgPx, px),
+                field(Field::CumQty, self.qty),
+                field(Field::LeavesQty, 0),
+                field(Field::LastPx, px),
+                field(Field::LastQty, self.qty),
+            ]),
+            None => fields.extend([field(Field::CumQty, 0), field(Field::LeavesQty, self.qty)]),
+        }
+        fields
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MockPosition {
+    pos_id: u64,
+    symbol: u32,
+    long_qty: f64,
+    short_qty: f64,
+    price: f64,
+}
+
+struct Session {
+    writer: WriteHalf<TcpStream>,
+    handle: StreamHandle,
+    logged_on: bool,
+    sender_comp_id: String,
+    sub_id: String,
+    // symbol id to MDReqID
+    spot: HashMap<u32, String>,
+    depth: HashMap<u32, String>,
+    // depth symbols subscribed with MDUpdateType FullRefresh
+    full_refresh: HashSet<u32>,
+}
+
+#[derive(Default)]
+struct ServerState {
+    symbols: BTreeMap<u32, MockSymbol>,
+    orders: Vec<MockOrder>,
+    positions: Vec<MockPosition>,
+    sessions: HashMap<u64, Session>,
+    // the sequence numbers are kept by SenderSubID over the connections.
+    next_seqs: HashMap<String, u32>,
+    received: Vec<(String, String)>,
+    // the message types received but not answered.
+    ignored: HashSet<String>,
+    last_id: u64,
+}
+
+impl ServerState {
+    fn new_id(&mut self) -> u64 {
+        self.last_id += 1;
+        self.last_id
+    }
+
+    async fn send(&mut self, conn: u64, msg_type: &str, fields: Fields) {
+        let Some(session) = self.sessions.get_mut(&conn) else {
+            return;
+        };
+        let seq = self.next_seqs.entry(session.sub_id.clone()).or_insert(1);
+        let mut body = vec![
+            format!("35={}", msg_type),
+            format!("34={}", seq),
+            "49=CSERVER".to_string(),
+            format!("50={}", session.sub_id),
+            format!("52={}", now()),
+            format!("56={}", session.sender_comp_id),
+            format!("57={}", session.sub_id),
+        ];
+        *seq += 1;
+        body.extend(
+            fields
+                .into_iter()
+                .map(|(tag, value)| format!("{}={}", tag, value)),
+        );
+        let message = raw_message(&format!("{}{}", body.join(DELIMITER), DELIMITER), DELIMITER);
+        if let Err(err) = session.writer.write_all(message.as_bytes()).await {
+            log::debug!("[MockServer] Failed to send the message - {:?}", err);
+        }
+    }
+
+    async fn business_reject(
+        &mut self,
+        conn: u64,
+        req: &ResponseMessage,
+        ref_id: String,
+        text: &str,
+    ) {
+        let fields = vec![
+            field(Field::RefSeqNum, value(req, Field::MsgSeqNum)),
+            field(Field::RefMsgType, req.get_message_type()),
+            field(Field::BusinessRejectRefID, ref_id),
+            field(Field::BusinessRejectReason, 0),
+            field(Field::Text, text),
+        ];
+        self.send(conn, "j", fields).await;
+    }
+
+    /// Handles the message and returns `false` if the connection should be closed.
+    async fn handle(&mut self, conn: u64, req: ResponseMessage) -> bool {
+        let msg_type = req.get_message_type().to_string();
+        self.received
+            .push((msg_type.clone(), req.get_message().replace(DELIMITER, "|")));
+
+        if msg_type == "A" {
+            self.on_logon(conn, &req).await;
+            return true;
+        }
+        if !self.sessions.get(&conn).is_some_and(|s| s.logged_on) {
+            log::warn!("[MockServer] Received MsgType({}) before logon", msg_type);
+            return false;
+        }
+        if self.ignored.contains(&msg_type) {
+            return true;
+        }
+
+        match msg_type.as_str() {
+            "0" | "4" => {}
+            "1" => {
+                let fields = vec![field(Field::TestReqID, value(&req, Field::TestReqID))];
+                self.send(conn, "0", fields).await;
+            }
+            "2" => {
+                // the sent messages are not kept, so the range is skipped by SequenceReset-Reset.
+                let sub_id = self.sessions[&conn].sub_id.clone();
+                let new_seq = self.next_seqs.get(&sub_id).copied().unwrap_or(1) + 1;
+                self.send(conn, "4", vec![field(Field::NewSeqNo, new_seq)])
+                    .await;
+            }
+            "5" => {
+                self.send(conn, "5", Vec::new()).await;
+                return false;
+            }
+            "x" => self.on_security_list_request(conn, &req).await,
+            "V" => self.on_market_data_request(conn, &req).await,
+            "D" => self.on_new_order_single(conn, &req).await,
+            "AN" => self.on_positions_request(conn, &req).await,
+            "AF" => self.on_mass_status_request(conn, &req).await,
+            "H" => self.on_order_status_request(conn, &req).await,
+            _ => {
+                let ref_id = value(&req, Field::MsgSeqNum);
+                self.business_reject(conn, &req, ref_id, "Unsupported message type")
+                    .await;
+            }
+        }
+        true
+    }
+
+    async fn on_logon(&mut self, conn: u64, req: &ResponseMessage) {
+        let sub_id = value(req, Field::SenderSubID);
+        let reset = req.get_field_str(Field::ResetSeqNumFlag) == Some("Y");
+        if reset {
+            self.next_seqs.insert(sub_id.clone(), 1);
+        }
+        let Some(session) = self.sessions.get_mut(&conn) else {
+            return;
+        };
+        session.logged_on = true;
+        session.sender_comp_id = value(req, Field::SenderCompID);
+        session.sub_id = sub_id;
+
+        let mut fields = vec![
+            field(Field::EncryptMethod, 0),
+            field(Field::HeartBtInt, value(req, Field::HeartBtInt)),
+        ];
+        if reset {
+            fields.push(field(Field::ResetSeqNumFlag, "Y"));
+        }
+        self.send(conn, "A", fields).await;
+    }
+
+    async fn on_security_list_request(&mut self, conn: u64, req: &ResponseMessage) {
+        let mut fields = vec![
+            field(Field::SecurityReqID, value(req, Field::SecurityReqID)),
+            field(Field::SecurityResponseID, self.new_id()),
+            field(Field::SecurityRequestResult, 0),
+            field(Field::NoRelatedSym, self.symbols.len()),
+        ];
+        for (id, symbol) in self.symbols.iter() {
+            fields.extend([
+                field(Field::Symbol, id),
+                field(Field::SymbolName, &symbol.name),
+                field(Field::SymbolDigits, symbol.digits),
+            ]);
+        }
+        self.send(conn, "y", fields).await;
+    }
+
+    async fn on_market_data_request(&mut self, conn: u64, req: &ResponseMessage) {
+        let md_req_id = value(req, Field::MDReqID);
+        let symbol_ids = req
+            .get_repeating_groups(Field::NoRelatedSym, Field::Symbol, Some(Field::Symbol))
+            .iter()
+            .filter_map(|group| group.get(&Field::Symbol)?.parse::<u32>().ok())
+            .collect::<Vec<_>>();
+        let depth = req.get_field_str(Field::MarketDepth) == Some("0");
+        let subscribe = req.get_field_str(Field::SubscriptionRequestType) == Some("1");
+        let full_refresh = req.get_field_str(Field::MDUpdateType) == Some("0");
+
+        for symbol_id in symbol_ids {
+            let Some(session) = self.sessions.get_mut(&conn) else {
+                return;
+            };
+            if depth && subscribe && full_refresh {
+                session.full_refresh.insert(symbol_id);
+            } else if depth {
+                session.full_refresh.remove(&symbol_id);
+            }
+            let subscriptions = if depth {
+                &mut session.depth
+            } else {
+                &mut session.spot
+            };
+            if !subscribe {
+                subscriptions.remove(&symbol_id);
+                continue;
+            }
+
+            let Some(symbol) = self.symbols.get(&symbol_id).cloned() else {
+                let fields = vec![
+                    field(Field::MDReqID, &md_req_id),
+                    field(Field::Symbol, symbol_id),
+                    // MDReqRejReason : unknown symbol
+                    field(281u32, 0),
+                    field(Field::Text, format!("Symbol not found: {}", symbol_id)),
+                ];
+                self.send(conn, "Y", fields).await;
+                continue;
+            };
+            subscriptions.insert(symbol_id, md_req_id.clone());
+
+            let mut fields = vec![
+                field(Field::MDReqID, &md_req_id),
+                field(Field::Symbol, symbol_id),
+            ];
+            fields.extend(symbol.entries(depth));
+            self.send(conn, "W", fields).await;
+        }
+    }
+
+    async fn set_price(&mut self, symbol_id: u32, bid: f64, ask: f64) {
+        let (bid_entry, ask_entry) = (self.new_id().to_string(), self.new_id().to_string());
+        let Some(symbol) = self.symbols.get_mut(&symbol_id) else {
+            return;
+        };
+        let old_entries = [
+            std::mem::replace(&mut symbol.bid_entry, bid_entry),
+            std::mem::replace(&mut symbol.ask_entry, ask_entry),
+        ];
+        symbol.bid = bid;
+        symbol.ask = ask;
+        let symbol = symbol.clone();
+
+        let subscribers = self
+            .sessions
+            .iter()
+            .map(|(conn, session)| {
+                (
+                    *conn,
+                    session.spot.get(&symbol_id).cloned(),
+                    session.depth.get(&symbol_id).cloned(),
+                    session.full_refresh.contains(&symbol_id),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        for (conn, spot, depth, full_refresh) in subscribers {
+            if let Some(md_req_id) = spot {
+                let mut fields = vec![
+                    field(Field::MDReqID, md_req_id),
+                    field(Field::Symbol, symbol_id),
+                ];
+                fields.extend(symbol.entries(false));
+                self.send(conn, "W", fields).await;
+            }
+            if let (Some(md_req_id), true) = (&depth, full_refresh) {
+                let mut fields = vec![
+                    field(Field::MDReqID, md_req_id),
+                    field(Field::Symbol, symbol_id),
+                ];
+                fields.extend(symbol.entries(true));
+                self.send(conn, "W", fields).await;
+            } else if let Some(md_req_id) = depth {
+                let mut fields = vec![
+                    field(Field::MDReqID, md_req_id),
+                    field(Field::NoMDEntries, 4),
+                ];
+                for entry_id in old_entries.iter() {
+                    fields.extend([
+                        field(Field::MDUpdateAction, 2),
+                        field(Field::MDEntryID, entry_id),
+                        field(Field::Symbol, symbol_id),
+                    ]);
+                }
+                for (entry_type, price, entry_id) in [
+                    ("0", symbol.bid, &symbol.bid_entry),
+                    ("1", symbol.ask, &symbol.ask_entry),
+                ] {
+                    fields.extend([
+                        field(Field::MDUpdateAction, 0),
+                        field(Field::MDEntryType, entry_type),
+                        field(Field::MDEntryID, entry_id),
+                        field(Field::Symbol, symbol_id),
+                        field(Field::MDEntryPx, price),
+                        field(Field::MDEntrySize, DEPTH_SIZE),
+                    ]);
+                }
+                self.send(conn, "X", fields).await;
+            }
+        }
+    }
+
+    async fn on_new_order_single(&mut self, conn: u64, req: &ResponseMessage) {
+        let cl_ord_id = value(req, Field::ClOrdId);
+        let symbol_id = parse::<u32>(req, Field::Symbol).unwrap_or(0);
+        let qty = parse::<f64>(req, Field::OrderQty).unwrap_or(0.0);
+
+        let Some(symbol) = self.symbols.get(&symbol_id).cloned() else {
+            self.business_reject(conn, req, cl_ord_id, "Symbol not found")
+                .await;
+            return;
+        };
+        if qty <= 0.0 {
+            self.business_reject(conn, req, cl_ord_id, "Invalid volume")
+                .await;
+            return;
+        }
+
+        let ord_type = value(req, Field::OrdType);
+        let market = ord_type == "1";
+        let order = MockOrder {
+            order_id: self.new_id(),
+            pos_id: self.new_id(),
+            cl_ord_id,
+            symbol: symbol_id,
+            side: value(req, Field::Side),
+            ord_type,
+            qty,
+            price: parse(req, Field::Price),
+            stop_px: parse(req, Field::StopPx),
+            time_in_force: req
+                .get_field_value(Field::TimeInForce)
+                .unwrap_or(if market { "3" } else { "1" }.into()),
+            expire_time: req.get_field_value(Field::ExpireTime),
+        };
+        self.send(conn, "8", order.report("0", "0", None)).await;
+
+        if market {
+            let buy = order.side == "1";
+            let price = if buy { symbol.ask } else { symbol.bid };
+            self.positions.push(MockPosition {
+                pos_id: order.pos_id,
+                symbol: symbol_id,
+                long_qty: if buy { qty } else { 0.0 },
+                short_qty: if buy { 0.0 } else { qty },
+                price,
+            });
+            self.send(conn, "8", order.report("F", "2", Some(price)))
+                .await;
+        } else {
+            self.orders.push(order);
+        }
+    }
+
+    async fn on_positions_request(&mut self, conn: u64, req: &ResponseMessage) {
+        let pos_req_id = value(req, Field::PosReqID);
+        if self.positions.is_empty() {
+            let fields = vec![
+                field(Field::PosReqID, pos_req_id),
+                field(Field::TotalNumPosReports, 0),
+                // no positions found
+                field(Field::PosReqResult, 2),
+            ];
+            self.send(conn, "AP", fields).await;
+            return;
+        }
+
+        let positions = self.positions.clone();
+        for position in positions.iter() {
+            let fields = vec![
+                field(Field::PosReqID, &pos_req_id),
+                field(Field::PosMaintRptID, position.pos_id),
+                field(Field::TotalNumPosReports, positions.len()),
+                field(Field::PosReqResult, 0),
+                field(Field::Symbol, position.symbol),
+                field(Field::NoPositions, 1),
+                field(Field::LongQty, position.long_qty),
+                field(Field::ShortQty, position.short_qty),
+                field(Field::SettlPrice, position.price),
+            ];
+            self.send(conn, "AP", fields).await;
+        }
+    }
+
+    async fn on_order_status_request(&mut self, conn: u64, req: &ResponseMessage) {
+        let cl_ord_id = value(req, Field::ClOrdId);
+        match self
+            .orders
+            .iter()
+            .find(|order| order.cl_ord_id == cl_ord_id)
+        {
+            Some(order) => {
+                let fields = order.report("I", "0", None);
+                self.send(conn, "8", fields).await;
+            }
+            None => {
+                self.business_reject(conn, req, cl_ord_id, "Order not found")
+                    .await
+            }
+        }
+    }
+
+    async fn on_mass_status_request(&mut self, conn: u64, req: &ResponseMessage) {
+        let mass_status_req_id = value(req, Field::MassStatusReqID);
+        if self.orders.is_empty() {
+            self.business_reject(conn, req, mass_status_req_id, "No orders")
+                .await;
+            return;
+        }
+
+        let orders = self.orders.clone();
+        for order in orders.iter() {
+            let mut fields = order.report("I", "0", None);
+            fields.extend([
+                field(Field::MassStatusReqID, &mass_status_req_id),
+                field(Field::TotNumReports, orders.len()),
+            ]);
+            self.send(conn, "8", fields).await;
+        }
+    }
+}
+
+/// Mock cTrader FIX server listening on localhost.
+///
+/// * Logon, Logout, Heartbeat, TestRequest and ResendRequest (answered by SequenceReset).
+/// * MarketDataRequest of spot and depth, answered by W/Y and followed by W/X on [`MockServer::set_price`].
+/// * SecurityListRequest, RequestForPositions, OrderStatusRequest and OrderMassStatusRequest.
+/// * NewOrderSingle, answered by the execution reports or BusinessMessageReject for an unknown
+///   symbol or an invalid volume. The market orders are filled at once and open a position.
+#[derive(Clone)]
+pub struct MockServer {
+    port: u16,
+    state: Arc<Mutex<ServerState>>,
+}
+
+impl MockServer {
+    /// Binds a free port of `127.0.0.1` and starts to accept the connections.
+    pub async fn start() -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.port()?;
+        let state = Arc::new(Mutex::new(ServerState::default()));
+
+        let state_clone = state.clone();
+        runtime::spawn(async move {
+            let mut conn = 0;
+            while let Ok((stream, handle)) = listener.accept().await {
+                conn += 1;
+                let (reader, writer) = stream.split();
+                state_clone.lock().await.sessions.insert(
+                    conn,
+                    Session {
+                        writer,
+                        handle,
+                        logged_on: false,
+                        sender_comp_id: String::new(),
+                        sub_id: String::new(),
+                        spot: HashMap::new(),
+                        depth: HashMap::new(),
+                        full_refresh: HashSet::new(),
+                    },
+                );
+                runtime::spawn(serve(state_clone.clone(), conn, reader));
+            }
+        });
+
+        Ok(Self { port, state })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Returns the client configuration to connect to this server.
+    pub fn config(&self) -> Config {
+        Config::new(
+            "127.0.0.1".into(),
+            "user".into(),
+            "password".into(),
+            "sender".into(),
+            30,
+        )
+        .with_port(self.port)
+    }
+
+    pub async fn add_symbol(&self, id: u32, name: &str, digits: u32, bid: f64, ask: f64) {
+        let mut state = self.state.lock().await;
+        let (bid_entry, ask_entry) = (state.new_id().to_string(), state.new_id().to_string());
+        state.symbols.insert(
+            id,
+            MockSymbol {
+                name: name.into(),
+                digits,
+                bid,
+                ask,
+                bid_entry,
+                ask_entry,
+            },
+        );
+    }
+
+    /// Updates the price and sends it to the subscribers.
+    pub async fn set_price(&self, symbol_id: u32, bid: f64, ask: f64) {
+        self.state.lock().await.set_price(symbol_id, bid, ask).await;
+    }
+
+    /// Stops or resumes answering the messages of `msg_type`. They are still recorded.
+    pub async fn set_ignored(&self, msg_type: &str, ignored: bool) {
+        let mut state = self.state.lock().await;
+        if ignored {
+            state.ignored.insert(msg_type.to_string());
+        } else {
+            state.ignored.remove(msg_type);
+        }
+    }
+
+    /// Returns the received messages of `msg_type` with the `|` delimiter.
+    pub async fn received(&self, msg_type: &str) -> Vec<String> {
+        self.state
+            .lock()
+            .await
+            .received
+            .iter()
+            .filter(|(t, _)| t == msg_type)
+            .map(|(_, message)| message.clone())
+            .collect()
+    }
+
+    /// Number of the logged on sessions.
+    pub async fn session_count(&self) -> usize {
+        self.state
+            .lock()
+            .await
+            .sessions
+            .values()
+            .filter(|session| session.logged_on)
+            .count()
+    }
+
+    /// Drops all the connections without Logout.
+    pub async fn disconnect_all(&self) {
+        for session in self.state.lock().await.sessions.values() {
+            session.handle.shutdown().ok();
+        }
+    }
+}
+
+async fn serve(state: Arc<Mutex<ServerState>>, conn: u64, mut reader: ReadHalf<TcpStream>) {
+    let mut framer = Framer::new();
+    let mut buffer = vec![0u8; 4096];
+
+    'connection: loop {
+        let n = match reader.read(&mut buffer).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        framer.extend(&buffer[..n]);
+        while let Some(res) = framer.next_message() {
+            match res {
+                Ok(req) => {
+                    if !state.lock().await.handle(conn, req).await {
+                        break 'connection;
+                    }
+                }
+                Err(invalid) => {
+                    log::warn!("[MockServer] Invalid message - {}", invalid.err);
+                }
+            }
+        }
+    }
+
+    if let Some(session) = state.lock().await.sessions.remove(&conn) {
+        session.handle.shutdown().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MockServer;
+    use crate::{
+        bar::{GapPolicy, Timeframe},
+        fixapi::FixApi,
+        messages::HeartbeatReq,
+        order_manager::OrderManager,
+        position_book::PositionBook,
+        recorder::{Rotation, TickEvent, TickReader, TickRecorder},
+        runtime,
+        store::{FileStore, MemoryStore, SessionStore},
+        stream::StreamOptions,
+        types::{
+            DepthEvent, DepthPrice, Error, ExecutionType, IncrementalRefresh, MDUpdateType,
+            MarketDataHandler, MarketType, NewOrder, OrderStatus, OrderType, ReconnectPolicy, Side,
+            SpotPrice, SubID, SubscriptionOptions, TimeInForce,
+        },
+        MarketClient, TradeClient,
+    };
+    use async_trait::async_trait;
+    use chrono::NaiveDate;
+    use futures::StreamExt;
+    use std::{
+        collections::HashMap,
+        future::Future,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    async fn wait_until<F, Fut>(mut condition: F) -> bool
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        for _ in 0..300 {
+            if condition().await {
+                return true;
+            }
+            runtime::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    async fn start_server() -> MockServer {
+        let server = MockServer::start().await.unwrap();
+        server.add_symbol(1, "EURUSD", 5, 1.1, 1.2).await;
+        server.add_symbol(2, "GBPUSD", 5, 1.3, 1.4).await;
+        server
+    }
+
+    #[derive(Default)]
+    struct Rejections(Mutex<Vec<u32>>);
+
+    #[async_trait]
+    impl MarketDataHandler for Rejections {
+        async fn on_price_of(&self, _: u32, _: SpotPrice) {}
+        async fn on_market_depth_full_refresh(&self, _: u32, _: HashMap<String, DepthPrice>) {}
+        async fn on_market_depth_incremental_refresh(&self, _: Vec<IncrementalRefresh>) {}
+        async fn on_rejected_spot_subscription(&self, symbol_id: u32, _: String) {
+            self.0.lock().unwrap().push(symbol_id);
+        }
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_market_client() {
+        let server = start_server().await;
+        let rejections = Arc::new(Rejections::default());
+        let mut client = MarketClient::with_config(server.config());
+        client.register_market_handler_arc(rejections.clone());
+        client.connect().await.unwrap();
+
+        client.subscribe_spot(1).await.unwrap();
+        assert!(
+            wait_until(|| async { client.price_of(1).await.is_ok_and(|p| p.bid == 1.1) }).await
+        );
+        server.set_price(1, 1.15, 1.25).await;
+        assert!(
+            wait_until(|| async { client.price_of(1).await.is_ok_and(|p| p.ask == 1.25) }).await
+        );
+
+        client.subscribe_depth(2).await.unwrap();
+        assert!(
+            wait_until(|| async { client.depth_data(2).await.is_ok_and(|d| d.len() == 2) }).await
+        );
+        server.set_price(2, 1.35, 1.45).await;
+        assert!(
+            wait_until(|| async {
+                client
+                    .depth_data(2)
+                    .await
+                    .is_ok_and(|d| d.len() == 2 && d.values().any(|p| p.price == 1.45))
+            })
+            .await
+        );
+
+        client.subscribe_spot(99).await.unwrap();
+        assert!(wait_until(|| async { rejections.0.lock().unwrap().contains(&99) }).await);
+        assert!(client.spot_subscription_list().await.contains(&1));
+
+        client.disconnect().await.unwrap();
+        assert!(wait_until(|| async { server.received("5").await.len() == 1 }).await);
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_subscribe_many() {
+        let server = start_server().await;
+        let rejections = Arc::new(Rejections::default());
+        let mut client = MarketClient::with_config(server.config());
+        client.register_market_handler_arc(rejections.clone());
+        client.connect().await.unwrap();
+
+        client.subscribe_spot_many(&[1, 2, 99]).await.unwrap();
+        client.subscribe_depth_many(&[1, 2]).await.unwrap();
+        assert!(
+            wait_until(|| async {
+                client.spot_subscription_list().await.len() == 2
+                    && client.depth_subscription_list().await.len() == 2
+            })
+            .await
+        );
+        assert!(wait_until(|| async { *rejections.0.lock().unwrap() == vec![99] }).await);
+        let requests = server.received("V").await;
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].contains("|146=3|55=1|55=2|55=99|"));
+
+        assert!(matches!(
+            client.subscribe_spot_many(&[3, 2]).await,
+            Err(Error::SubscribedAlready(2, _))
+        ));
+        assert_eq!(server.received("V").await.len(), 2);
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_subscribe_and_wait() {
+        let server = start_server().await;
+        let mut client = MarketClient::with_config(server.config());
+        client.connect().await.unwrap();
+
+        client.subscribe_spot_and_wait(1).await.unwrap();
+        assert_eq!(client.price_of(1).await.unwrap().bid, 1.1);
+        client.subscribe_depth_and_wait(2).await.unwrap();
+        assert_eq!(client.order_book(2).await.unwrap().entries().len(), 2);
+
+        match client.subscribe_depth_and_wait(99).await {
+            Err(Error::SubscriptionError(99, err_msg, MarketType::Depth)) => {
+                assert_eq!(err_msg, "Symbol not found: 99")
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(matches!(
+            client.subscribe_spot_and_wait(1).await,
+            Err(Error::SubscribedAlready(1, MarketType::Spot))
+        ));
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_subscribe_retry_after_timeout() {
+        let server = start_server().await;
+        let mut client = MarketClient::with_config(server.config());
+        client.set_timeout(300);
+        client.connect().await.unwrap();
+
+        server.set_ignored("V", true).await;
+        assert!(matches!(
+            client.subscribe_spot_and_wait(1).await,
+            Err(Error::TimeoutError)
+        ));
+        // the unanswered request is cancelled.
+        assert!(wait_until(|| async { server.received("V").await.len() == 2 }).await);
+        assert!(server.received("V").await[1].contains("|263=2|"));
+
+        server.set_ignored("V", false).await;
+        client.subscribe_spot_and_wait(1).await.unwrap();
+        assert_eq!(client.price_of(1).await.unwrap().bid, 1.1);
+        client.unsubscribe_spot(1).await.unwrap();
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_market_data_streams() {
+        let server = start_server().await;
+        let mut client = MarketClient::with_config(server.config());
+        client.connect().await.unwrap();
+
+        let mut spot = client.spot_stream(1, StreamOptions::default());
+        let mut depth = client.depth_stream(2, StreamOptions::default());
+        client.subscribe_spot(1).await.unwrap();
+        client.subscribe_depth(2).await.unwrap();
+        assert!(
+            wait_until(|| async {
+                client.spot_subscription_list().await.contains(&1)
+                    && client.depth_subscription_list().await.contains(&2)
+            })
+            .await
+        );
+        for i in 1..=5 {
+            server.set_price(1, 1.0 + i as f64, 2.0 + i as f64).await;
+            server.set_price(2, 3.0 + i as f64, 4.0 + i as f64).await;
+        }
+
+        let mut bids = Vec::new();
+        for _ in 0..6 {
+            bids.push(spot.next().await.unwrap().unwrap().bid);
+        }
+        assert_eq!(bids, vec![1.1, 2.0, 3.0, 4.0, 5.0, 6.0]);
+
+        assert!(matches!(
+            depth.next().await,
+            Some(Ok(DepthEvent::FullRefresh(entries))) if entries.len() == 2
+        ));
+        for _ in 0..5 {
+            match depth.next().await {
+                Some(Ok(DepthEvent::IncrementalRefresh(refresh))) => assert_eq!(refresh.len(), 4),
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+
+        // the last price: bid 8.0, ask 9.0
+        assert!(
+            wait_until(|| async {
+                client
+                    .order_book(2)
+                    .await
+                    .map(|book| book.mid() == Some(8.5) && book.spread() == Some(1.0))
+                    .unwrap_or(false)
+            })
+            .await
+        );
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_full_refresh_depth() {
+        let server = start_server().await;
+        let mut client = MarketClient::with_config(server.config());
+        client.connect().await.unwrap();
+
+        let options = SubscriptionOptions {
+            update_type: Some(MDUpdateType::FullRefresh),
+            ..SubscriptionOptions::depth()
+        };
+        let mut depth = client.depth_stream(2, StreamOptions::default());
+        client
+            .subscribe_depth_with_options(&[2], &options)
+            .await
+            .unwrap();
+        assert!(wait_until(|| async { client.depth_subscription_list().await.contains(&2) }).await);
+        assert!(server.received("V").await[0].contains("|265=0|"));
+        server.set_price(2, 3.0, 4.0).await;
+        server.set_price(2, 5.0, 6.0).await;
+
+        for _ in 0..3 {
+            match depth.next().await {
+                Some(Ok(DepthEvent::FullRefresh(entries))) => assert_eq!(entries.len(), 2),
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+        assert!(
+            wait_until(|| async {
+                client
+                    .order_book(2)
+                    .await
+                    .is_ok_and(|book| book.entries().len() == 2 && book.mid() == Some(5.5))
+            })
+            .await
+        );
+
+        let options = SubscriptionOptions {
+            market_depth: 0,
+            ..SubscriptionOptions::spot()
+        };
+        assert!(matches!(
+            client.subscribe_spot_with_options(&[1], &options).await,
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_recorder() {
+        let dir = std::env::temp_dir().join(format!("cfix-recorder-{}", uuid::Uuid::new_v4()));
+        let recorder = Arc::new(TickRecorder::open(&dir, "ticks", Rotation::default()).unwrap());
+        let server = start_server().await;
+        let mut client = MarketClient::with_config(server.config());
+        client.set_recorder(recorder.clone());
+        client.connect().await.unwrap();
+
+        client.subscribe_spot_and_wait(1).await.unwrap();
+        client.subscribe_depth_and_wait(2).await.unwrap();
+        server.set_price(1, 1.15, 1.25).await;
+        server.set_price(2, 1.35, 1.45).await;
+        assert!(
+            wait_until(|| async {
+                client
+                    .order_book(2)
+                    .await
+                    .is_ok_and(|book| book.best_ask().is_some_and(|ask| ask.price == 1.45))
+            })
+            .await
+        );
+        recorder.flush().unwrap();
+
+        let ticks = TickReader::open_dir(&dir, "ticks")
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(ticks.len(), 4);
+        assert!(ticks.iter().all(|tick| tick.sending_time.is_some()));
+        assert_eq!(
+            ticks[2].event,
+            TickEvent::Spot(SpotPrice {
+                bid: 1.15,
+                ask: 1.25
+            })
+        );
+        assert!(matches!(
+            &ticks[3].event,
+            TickEvent::Depth(DepthEvent::IncrementalRefresh(refresh)) if refresh.len() == 4
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_bars() {
+        let server = start_server().await;
+        let mut client = MarketClient::with_config(server.config());
+        client.connect().await.unwrap();
+
+        assert!(client.aggregate_bars(1, Timeframe::S1, GapPolicy::Skip));
+        assert!(!client.aggregate_bars(1, Timeframe::S1, GapPolicy::Fill));
+        let mut bars = client.bar_stream(1, StreamOptions::default());
+        client.subscribe_spot_and_wait(1).await.unwrap();
+        server.set_price(1, 1.15, 1.25).await;
+
+        // completed by the clock without the later price
+        let bar = runtime::timeout(Duration::from_secs(5), bars.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(bar.symbol_id, 1);
+        assert_eq!(bar.timeframe, Timeframe::S1);
+        assert!(bar.tick_count >= 1);
+        let (bid, ask, mid) = (bar.bid.unwrap(), bar.ask.unwrap(), bar.mid.unwrap());
+        assert!(bid.open == 1.1 || bid.open == 1.15);
+        assert!(mid.high > bid.high && mid.high < ask.high);
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_trade_client() {
+        let server = start_server().await;
+        let mut client = TradeClient::with_config(server.config());
+        client.connect().await.unwrap();
+
+        let symbols = client.fetch_security_list().await.unwrap();
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols[0].name, "EURUSD");
+
+        assert!(client.fetch_positions().await.unwrap().is_empty());
+        assert!(client
+            .fetch_all_order_status(None)
+            .await
+            .unwrap()
+            .reports
+            .is_empty());
+
+        let report = client
+            .new_market_order(1, Side::BUY, 1000.0, None, None)
+            .await
+            .unwrap();
+        assert_eq!(report.exec_type, ExecutionType::New);
+
+        let positions = client.fetch_positions().await.unwrap();
+        assert_eq!(positions.len(), 1);
+        assert_eq!(positions[0].long_qty, 1000.0);
+        assert_eq!(positions[0].settle_price, 1.2);
+
+        client
+            .new_limit_order(2, Side::SELL, 1.5, 2000.0, Some("limit".into()), None, None)
+            .await
+            .unwrap();
+        let status = client.fetch_all_order_status(None).await.unwrap();
+        assert_eq!(status.reports.len(), 1);
+        assert_eq!(status.reports[0].exec_type, ExecutionType::OrderStatus);
+        assert_eq!(status.reports[0].order_report.cl_ord_id, "limit");
+        assert!(status.reports[0].order_report.mass_status_req_id.is_some());
+
+        let report = client
+            .fetch_order_status("limit".into(), Side::SELL)
+            .await
+            .unwrap();
+        assert_eq!(report.exec_type, ExecutionType::OrderStatus);
+        assert_eq!(report.order_report.price, Some(1.5));
+        assert!(matches!(
+            client.fetch_order_status("unknown".into(), Side::BUY).await,
+            Err(Error::RequestRejected(text)) if text == "Order not found"
+        ));
+
+        match client
+            .new_market_order(99, Side::BUY, 1000.0, None, None)
+            .await
+        {
+            Err(Error::OrderFailed(text)) => assert_eq!(text, "Symbol not found"),
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        client.disconnect().await.unwrap();
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_submit_order() {
+        let server = start_server().await;
+        let mut client = TradeClient::with_config(server.config());
+        client.connect().await.unwrap();
+
+        let expire_time = NaiveDate::from_ymd_opt(2030, 1, 2)
+            .unwrap()
+            .and_hms_opt(3, 4, 5)
+            .unwrap();
+        let report = client
+            .submit_order(
+                NewOrder::stop_limit(1, Side::BUY, 1000.0, 1.3, 1.31)
+                    .with_expire_time(expire_time)
+                    .with_cl_ord_id("stop-limit"),
+            )
+            .await
+            .unwrap();
+        let order = report.order_report;
+        assert_eq!(order.order_type, OrderType::StopLimit);
+        assert_eq!((order.stop_px, order.price), (Some(1.3), Some(1.31)));
+        assert_eq!(order.time_in_force, Some(TimeInForce::GoodTillDate));
+        assert_eq!(order.expire_time, Some(expire_time));
+
+        let report = client
+            .submit_order(
+                NewOrder::market_range(2, Side::SELL, 1000.0, 1.25)
+                    .with_time_in_force(TimeInForce::ImmediateOrCancel),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            report.order_report.time_in_force,
+            Some(TimeInForce::ImmediateOrCancel)
+        );
+        let requests = server.received("D").await;
+        assert!(requests[1].contains("|40=1|") && requests[1].contains("|44=1.25|"));
+
+        // not sent
+        assert!(matches!(
+            client
+                .submit_order(
+                    NewOrder::stop(1, Side::BUY, 1000.0, 1.3)
+                        .with_time_in_force(TimeInForce::ImmediateOrCancel)
+                )
+                .await,
+            Err(Error::InvalidArgument(_))
+        ));
+        for lot in [0.0, -1000.0, f64::NAN] {
+            assert!(matches!(
+                client
+                    .adjust_position_size("1".into(), 1, lot, Side::SELL, None)
+                    .await,
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+        assert_eq!(server.received("D").await.len(), 2);
+
+        client.disconnect().await.unwrap();
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_order_manager() {
+        let server = start_server().await;
+        let mut client = TradeClient::with_config(server.config());
+        client.connect().await.unwrap();
+        client
+            .new_limit_order(1, Side::BUY, 1.0, 1000.0, Some("limit".into()), None, None)
+            .await
+            .unwrap();
+        client.disconnect().await.unwrap();
+
+        // seeded at the logon
+        let manager = Arc::new(OrderManager::new());
+        let mut client = TradeClient::with_config(server.config());
+        client.set_order_manager(manager.clone());
+        client.connect().await.unwrap();
+        assert!(wait_until(|| async { manager.order_by_cl_ord_id("limit").is_some() }).await);
+
+        client
+            .new_market_order(2, Side::SELL, 2000.0, Some("market".into()), None)
+            .await
+            .unwrap();
+        assert!(
+            wait_until(|| async {
+                manager
+                    .order_by_cl_ord_id("market")
+                    .is_some_and(|order| order.order_status == OrderStatus::Filled)
+            })
+            .await
+        );
+        let open_orders = manager.open_orders();
+        assert_eq!(open_orders.len(), 1);
+        assert_eq!(open_orders[0].cl_ord_id, "limit");
+        assert_eq!(manager.orders_for_symbol(2).len(), 1);
+
+        client.disconnect().await.unwrap();
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_position_book() {
+        let server = start_server().await;
+        let mut market = MarketClient::with_config(server.config());
+        market.connect().await.unwrap();
+        market.subscribe_spot_and_wait(1).await.unwrap();
+
+        let book = Arc::new(PositionBook::new());
+        let mut client = TradeClient::with_config(server.config());
+        client.set_position_book(book.clone());
+        client.connect().await.unwrap();
+        // seeded with no positions
+        assert!(wait_until(|| async { server.received("AN").await.len() == 1 }).await);
+        runtime::sleep(Duration::from_millis(100)).await;
+
+        client
+            .new_market_order(1, Side::BUY, 1000.0, None, None)
+            .await
+            .unwrap();
+        assert!(wait_until(|| async { book.positions().len() == 1 }).await);
+        assert_eq!(book.positions()[0].entry_price, 1.2);
+
+        server.set_price(1, 1.3, 1.4).await;
+        assert!(
+            wait_until(|| async { market.price_of(1).await.is_ok_and(|p| p.bid == 1.3) }).await
+        );
+        let pnl = book.unrealized_pnl(&market).await.unwrap();
+        assert!((pnl[0].pnl - 100.0).abs() < 1e-6);
+        let by_symbol = book.unrealized_pnl_by_symbol(&market).await.unwrap();
+        assert!((by_symbol[&1] - 100.0).abs() < 1e-6);
+
+        client.disconnect().await.unwrap();
+        market.disconnect().await.unwrap();
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_seed_with_fills() {
+        let server = start_server().await;
+        let manager = Arc::new(OrderManager::new());
+        let book = Arc::new(PositionBook::new());
+        let mut client = TradeClient::with_config(server.config());
+        client.set_order_manager(manager.clone());
+        client.set_position_book(book.clone());
+        client.connect().await.unwrap();
+
+        // filled while seeding, and kept whether the snapshots have it or not
+        client
+            .new_market_order(1, Side::BUY, 1000.0, Some("market".into()), None)
+            .await
+            .unwrap();
+        assert!(wait_until(|| async { server.received("AN").await.len() == 1 }).await);
+        assert!(wait_until(|| async { book.positions().len() == 1 }).await);
+
+        let positions = book.positions();
+        assert_eq!(positions[0].volume, 1000.0);
+        assert_eq!(
+            manager.order_by_cl_ord_id("market").unwrap().order_status,
+            OrderStatus::Filled
+        );
+
+        client.disconnect().await.unwrap();
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_resubscribe_after_reconnect() {
+        let server = start_server().await;
+        let mut client = MarketClient::with_config(server.config());
+        client.set_reconnect_policy(Some(ReconnectPolicy {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(100),
+            jitter: 0.0,
+            ..Default::default()
+        }));
+        client.connect().await.unwrap();
+        client.subscribe_spot(1).await.unwrap();
+        assert!(wait_until(|| async { client.price_of(1).await.is_ok() }).await);
+
+        server.disconnect_all().await;
+        assert!(wait_until(|| async { server.received("V").await.len() == 2 }).await);
+        assert!(wait_until(|| async { server.session_count().await == 1 }).await);
+
+        server.set_price(1, 1.15, 1.25).await;
+        assert!(
+            wait_until(|| async { client.price_of(1).await.is_ok_and(|p| p.bid == 1.15) }).await
+        );
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_logon_seq_too_low() {
+        let server = start_server().await;
+        let dir = std::env::temp_dir().join(format!("cfix-store-{}", uuid::Uuid::new_v4()));
+        // the store is ahead of the server, which starts from 1
+        let store = FileStore::open(&dir).unwrap();
+        store.set_next_target_seq_num(10).await.unwrap();
+
+        let mut client = MarketClient::with_config(server.config());
+        client.set_session_store(store);
+        assert!(matches!(
+            client.connect().await,
+            Err(Error::SeqNumTooLow {
+                expected: 10,
+                received: 1
+            })
+        ));
+        assert!(wait_until(|| async { server.received("5").await.len() == 1 }).await);
+        assert!(server.received("5").await[0].contains("58=MsgSeqNum too low"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    /// Takes a while to save, so the concurrent senders interleave.
+    #[derive(Default)]
+    struct SlowStore(MemoryStore);
+
+    #[async_trait]
+    impl SessionStore for SlowStore {
+        async fn next_sender_seq_num(&self) -> Result<u32, Error> {
+            self.0.next_sender_seq_num().await
+        }
+        async fn next_target_seq_num(&self) -> Result<u32, Error> {
+            self.0.next_target_seq_num().await
+        }
+        async fn set_next_sender_seq_num(&self, seq: u32) -> Result<(), Error> {
+            self.0.set_next_sender_seq_num(seq).await
+        }
+        async fn advance_next_sender_seq_num(&self, seq: u32) -> Result<(), Error> {
+            runtime::sleep(Duration::from_millis(fastrand::u64(0..5))).await;
+            self.0.advance_next_sender_seq_num(seq).await
+        }
+        async fn set_next_target_seq_num(&self, seq: u32) -> Result<(), Error> {
+            self.0.set_next_target_seq_num(seq).await
+        }
+        async fn save_message(&self, seq: u32, message: &str) -> Result<(), Error> {
+            self.0.save_message(seq, message).await
+        }
+        async fn get_messages(&self, begin: u32, end: u32) -> Result<Vec<(u32, String)>, Error> {
+            self.0.get_messages(begin, end).await
+        }
+        async fn reset(&self) -> Result<(), Error> {
+            self.0.reset().await
+        }
+    }
+
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    #[cfg_attr(not(feature = "tokio"), async_std::test)]
+    async fn test_concurrent_senders_in_order() {
+        let server = start_server().await;
+        let mut api = FixApi::new(SubID::TRADE, server.config());
+        api.set_session_store(SlowStore::default());
+        api.connect().await.unwrap();
+        api.logon(false).await.unwrap();
+
+        futures::future::join_all(
+            (0..20).map(|_| async { api.send_message(HeartbeatReq::new(None)).await }),
+        )
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+        assert!(wait_until(|| async { server.received("0").await.len() == 20 }).await);
+
+        let seqs = server
+            .received("0")
+            .await
+            .iter()
+            .map(|msg| {
+                msg.split('|')
+                    .find_map(|field| field.strip_prefix("34="))
+                    .unwrap()
+                    .parse::<u32>()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(seqs, (2..22).collect::<Vec<_>>());
+        api.disconnect().await.unwrap();
+    }
+}
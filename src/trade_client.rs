@@ -1,21 +1,24 @@
-use async_std::{
-    channel::{bounded, Receiver, Sender},
-    sync::RwLock,
-    task,
-};
+use async_channel::{bounded, Receiver, Sender};
+use async_lock::RwLock;
 use chrono::NaiveDateTime;
 use uuid::Uuid;
 
 use crate::{
     fixapi::FixApi,
     messages::{
-        NewOrderSingleReq, OrderCancelReplaceReq, OrderCancelReq, OrderMassStatusReq, PositionsReq,
-        ResponseMessage, SecurityListReq,
+        NewOrderSingleReq, OrderCancelReplaceReq, OrderCancelReq, OrderMassStatusReq,
+        OrderStatusReq, PositionsReq, ResponseMessage, SecurityListReq,
     },
+    order_manager::OrderManager,
     parse_func::{self, parse_execution_report},
+    position_book::PositionBook,
+    replay::{ReplaySource, ReplaySpeed},
+    runtime,
+    session::seq_num,
+    store::SessionStore,
     types::{
-        ConnectionHandler, Error, ExecutionReport, Field, OrderType, PositionReport, Side,
-        SymbolInformation, TradeDataHandler,
+        Config, ConnectionHandler, Error, ExecutionReport, Field, NewOrder, OrderMassStatus,
+        PositionReport, ReconnectPolicy, Side, SubID, SymbolInformation, TradeDataHandler,
     },
 };
 
@@ -28,6 +31,11 @@ use std::{
     time::{Duration, Instant},
 };
 
+/// Maximum milliseconds to wait for the signal before scanning the queue again.
+///
+/// The signal wakes one of the waiting requests, so the others rescan by themselves.
+const RESCAN_INTERVAL: u64 = 50;
+
 #[derive(Debug)]
 struct TimeoutItem<T> {
     item: T,
@@ -45,10 +53,37 @@ impl<T> TimeoutItem<T> {
     }
 }
 
+/// Execution reports received while the order manager and the position book are seeded, with the
+/// MsgSeqNum. `None` when not seeding.
+type PendingReports = Arc<std::sync::Mutex<Option<Vec<(u32, ExecutionReport)>>>>;
+
+/// MsgSeqNum of the last message of a response.
+fn last_seq(responses: &[ResponseMessage]) -> u32 {
+    responses.last().and_then(seq_num).unwrap_or(0)
+}
+
+fn apply_report(
+    order_manager: Option<&OrderManager>,
+    position_book: Option<&PositionBook>,
+    report: &ExecutionReport,
+) {
+    if let Some(manager) = order_manager {
+        if let Err(err) = manager.apply(report) {
+            log::warn!("Failed to apply the execution report - {}", err);
+        }
+    }
+    if let Some(book) = position_book {
+        book.apply(report);
+    }
+}
+
 pub struct TradeClient {
     internal: FixApi,
 
     trade_data_handler: Option<Arc<dyn TradeDataHandler + Send + Sync>>,
+    order_manager: Option<Arc<OrderManager>>,
+    position_book: Option<Arc<PositionBook>>,
+    pending_reports: PendingReports,
 
     queue: Arc<RwLock<VecDeque<TimeoutItem<ResponseMessage>>>>,
 
@@ -67,17 +102,24 @@ impl TradeClient {
         sender_comp_id: String,
         heartbeat_interval: Option<u32>,
     ) -> Self {
+        Self::with_config(Config::new(
+            host,
+            login,
+            password,
+            sender_comp_id,
+            heartbeat_interval.unwrap_or(30),
+        ))
+    }
+
+    /// Creates the client from a `Config`, e.g. to use the TLS transport.
+    pub fn with_config(config: Config) -> Self {
         let (tx, rx) = bounded(1);
         Self {
-            internal: FixApi::new(
-                crate::types::SubID::TRADE,
-                host,
-                login,
-                password,
-                sender_comp_id,
-                heartbeat_interval,
-            ),
+            internal: FixApi::new(SubID::TRADE, config),
             trade_data_handler: None,
+            order_manager: None,
+            position_book: None,
+            pending_reports: Default::default(),
             queue: Arc::new(RwLock::new(VecDeque::new())),
 
             signal: tx,
@@ -123,8 +165,38 @@ impl TradeClient {
         self.internal.register_connection_handler_arc(handler);
     }
 
+    /// Sets the policy to reconnect automatically when the connection is lost. (disabled by default)
+    pub fn set_reconnect_policy(&mut self, policy: Option<ReconnectPolicy>) {
+        self.internal.set_reconnect_policy(policy);
+    }
+
+    /// Sets the store of the sequence numbers and the sent messages. (`MemoryStore` by default)
+    ///
+    /// With a persistent store such as `FileStore`, the session logs on without resetting the
+    /// sequence numbers.
+    pub fn set_session_store<S: SessionStore + Send + Sync + 'static>(&mut self, store: S) {
+        self.internal.set_session_store(store);
+    }
+
+    /// Sets the manager of the orders, applied with the execution reports before the handler.
+    ///
+    /// Set it before `connect`. The manager is seeded from `fetch_all_order_status` at every logon,
+    /// and the execution reports received while fetching are applied after the snapshot.
+    pub fn set_order_manager(&mut self, manager: Arc<OrderManager>) {
+        self.order_manager = Some(manager);
+    }
+
+    /// Sets the book of the positions, applied with the fills of the execution reports.
+    ///
+    /// Set it before `connect`. The book is seeded from `fetch_positions` at every logon, and the
+    /// fills received while fetching are applied after the snapshot.
+    pub fn set_position_book(&mut self, book: Arc<PositionBook>) {
+        self.position_book = Some(book);
+    }
+
     pub async fn connect(&mut self) -> Result<(), Error> {
         self.register_internal_handler();
+        self.register_seeding();
         self.internal.connect().await?;
         self.internal.logon(false).await
     }
@@ -133,6 +205,14 @@ impl TradeClient {
         self.internal.disconnect().await
     }
 
+    /// Replays the recorded messages of a FIX log instead of connecting to the server.
+    ///
+    /// The execution reports go to the handler as if they were received.
+    pub async fn replay(&mut self, source: &ReplaySource, speed: ReplaySpeed) {
+        self.register_internal_handler();
+        self.internal.replay(source, speed).await;
+    }
+
     pub fn is_connected(&self) -> bool {
         self.internal.is_connected()
     }
@@ -140,33 +220,40 @@ impl TradeClient {
     fn register_internal_handler(&mut self) {
         let queue = self.queue.clone();
         let handler = self.trade_data_handler.clone();
+        let order_manager = self.order_manager.clone();
+        let position_book = self.position_book.clone();
+        let pending_reports = self.pending_reports.clone();
         let signal = self.signal.clone();
         let trade_callback = move |res: ResponseMessage| {
+            let report = if res.get_message_type() == "8"
+                && res
+                    .get_field_value(Field::ExecType)
+                    .map(|v| v.as_str() != "I")
+                    .unwrap_or(true)
+            {
+                // IGNORE the error
+                parse_execution_report(res.clone()).ok()
+            } else {
+                None
+            };
+
+            // called in the order of MsgSeqNum, so the state is updated here, not in the task.
+            if let Some(report) = &report {
+                match pending_reports.lock().unwrap().as_mut() {
+                    Some(pending) => pending.push((seq_num(&res).unwrap_or(0), report.clone())),
+                    None => {
+                        apply_report(order_manager.as_deref(), position_book.as_deref(), report)
+                    }
+                }
+            }
+
             let signal = signal.clone();
             let handler = handler.clone();
             let queue = queue.clone();
             let lifetime = Duration::from_millis(5000);
-            task::spawn(async move {
-                match res.get_message_type() {
-                    "8" => {
-                        if res
-                            .get_field_value(Field::ExecType)
-                            .map(|v| v.as_str() != "I")
-                            .unwrap_or(true)
-                        {
-                            match parse_execution_report(res.clone()) {
-                                Ok(report) => {
-                                    if let Some(handler) = handler {
-                                        handler.on_execution_report(report).await;
-                                    }
-                                }
-                                Err(_err) => {
-                                    // IGNORE
-                                }
-                            }
-                        }
-                    }
-                    _ => {}
+            runtime::spawn(async move {
+                if let (Some(handler), Some(report)) = (handler, report) {
+                    handler.on_execution_report(report).await;
                 }
 
                 queue
@@ -194,6 +281,82 @@ impl TradeClient {
         self.internal.register_trade_callback(trade_callback);
     }
 
+    fn register_seeding(&mut self) {
+        let manager = self.order_manager.clone();
+        let book = self.position_book.clone();
+        if manager.is_none() && book.is_none() {
+            return;
+        }
+        let pending_reports = self.pending_reports.clone();
+        let queue = self.queue.clone();
+        let signal = self.signal.clone();
+        let receiver = self.receiver.clone();
+        let timeout = self.timeout;
+        self.internal.register_logon_callback(move |api: FixApi| {
+            // the reports from now are kept until the snapshots are received
+            *pending_reports.lock().unwrap() = Some(Vec::new());
+            // fetches with the session of the logon
+            let client = TradeClient {
+                internal: api,
+                trade_data_handler: None,
+                order_manager: None,
+                position_book: None,
+                pending_reports: Default::default(),
+                queue: queue.clone(),
+                signal: signal.clone(),
+                receiver: receiver.clone(),
+                timeout,
+            };
+            let manager = manager.clone();
+            let book = book.clone();
+            let pending_reports = pending_reports.clone();
+            runtime::spawn(async move {
+                let orders = match manager {
+                    Some(_) => client
+                        .fetch_order_mass_status_with_seq(None)
+                        .await
+                        .map_err(|err| log::error!("Failed to seed the orders - {:?}", err))
+                        .ok(),
+                    None => None,
+                };
+                let positions = match book {
+                    Some(_) => client
+                        .fetch_positions_with_seq()
+                        .await
+                        .map_err(|err| log::error!("Failed to seed the positions - {:?}", err))
+                        .ok(),
+                    None => None,
+                };
+
+                // the reports up to the last message of a snapshot are included in it, except
+                // the orders closed by them since the mass status has only the open orders.
+                let mut pending_reports = pending_reports.lock().unwrap();
+                let mut orders_seq = 0;
+                if let (Some(manager), Some((status, seq))) = (&manager, orders) {
+                    for err in status.failures.iter() {
+                        log::error!("Keep the open orders not in the mass status - {:?}", err);
+                    }
+                    manager.seed(&status);
+                    orders_seq = seq;
+                }
+                let mut positions_seq = 0;
+                if let (Some(book), Some((positions, seq))) = (&book, positions) {
+                    book.seed(&positions);
+                    positions_seq = seq;
+                }
+                for (seq, report) in pending_reports.take().unwrap_or_default() {
+                    apply_report(
+                        manager.as_deref().filter(|_| {
+                            seq > orders_seq || !report.order_report.order_status.is_open()
+                        }),
+                        book.as_deref().filter(|_| seq > positions_seq),
+                        &report,
+                    );
+                }
+            });
+        });
+    }
+
     fn create_unique_id(&self) -> String {
         Uuid::new_v4().to_string()
     }
@@ -202,65 +365,67 @@ impl TradeClient {
         if !self.is_connected() {
             return Err(Error::NotConnected);
         }
-        async_std::future::timeout(Duration::from_millis(dur), receiver.recv())
+        runtime::timeout(Duration::from_millis(dur), receiver.recv())
             .await
-            .map_err(|_| Error::TimeoutError)?
+            .ok_or(Error::TimeoutError)?
             .map_err(|e| e.into())
     }
 
     async fn fetch_response(
         &self,
         arg: Vec<(&str, Field, String)>,
+    ) -> Result<ResponseMessage, Error> {
+        self.fetch_response_by(|res| {
+            arg.iter()
+                .any(|(msg_type, field, value)| res.matching_field_value(msg_type, *field, value))
+        })
+        .await
+    }
+
+    /// Waits for the first response matching the predicate.
+    async fn fetch_response_by<F: Fn(&ResponseMessage) -> bool>(
+        &self,
+        matches: F,
     ) -> Result<ResponseMessage, Error> {
         // setup for timeout
         let now = Instant::now();
-        let mut remain = self.timeout;
 
         loop {
-            let _ = self.wait_notifier(self.receiver.clone(), remain).await?;
-            // match self.wait_notifier(receiver, remain).await {
+            // the response can be queued before the signal, while the other request is waiting.
             let mut res = None;
             let q = self.queue.read().await;
             for v in q.iter().rev() {
-                let mut b = false;
                 let consumed = v.consumed.load(Ordering::Relaxed);
                 if consumed {
                     continue;
                 }
 
-                for (msg_type, field, value) in arg.iter() {
-                    if v.item.matching_field_value(msg_type, *field, value) {
-                        b = true;
-                        res = Some(v.item.clone());
-                        v.consumed.store(true, Ordering::Relaxed);
-                        break;
-                    }
-                }
-                if b {
+                if matches(&v.item) {
+                    res = Some(v.item.clone());
+                    v.consumed.store(true, Ordering::Relaxed);
                     break;
                 }
             }
+            drop(q);
 
-            match res {
-                Some(res) => {
-                    return Ok(res);
-                }
-                None => {
-                    // check remaining time.
-                    let past = (Instant::now() - now).as_millis() as u64;
-                    if past < self.timeout {
-                        // continue.
-                        remain = self.timeout - past;
-
-                        // check if there is more waiting receiver.
-                        if self.receiver.receiver_count() > 1 {
-                            self.signal.try_send(()).ok();
-                        }
-                        continue;
-                    } else {
-                        return Err(Error::TimeoutError);
-                    }
-                }
+            if let Some(res) = res {
+                return Ok(res);
+            }
+            // check remaining time.
+            let past = (Instant::now() - now).as_millis() as u64;
+            if past >= self.timeout {
+                return Err(Error::TimeoutError);
+            }
+            // the other waiting requests scan the queue again after the interval.
+            match self
+                .wait_notifier(
+                    self.receiver.clone(),
+                    (self.timeout - past).min(RESCAN_INTERVAL),
+                )
+                .await
+            {
+                Ok(()) | Err(Error::TimeoutError) => {}
+                Err(err) => return Err(err),
             }
         }
     }
@@ -294,6 +459,11 @@ impl TradeClient {
     }
 
     pub async fn fetch_positions(&self) -> Result<Vec<PositionReport>, Error> {
+        Ok(self.fetch_positions_with_seq().await?.0)
+    }
+
+    /// `fetch_positions` with the MsgSeqNum of the last response.
+    async fn fetch_positions_with_seq(&self) -> Result<(Vec<PositionReport>, u32), Error> {
         self.check_connection()?;
         let pos_req_id = self.create_unique_id();
         let req = PositionsReq::new(pos_req_id.clone(), None);
@@ -310,21 +480,22 @@ impl TradeClient {
                     if res.get_message_type() == "AP"
                         && res
                             .get_field_value(Field::PosReqResult)
-                            .map_or(false, |v| v.as_str() == "0")
+                            .is_some_and(|v| v.as_str() == "0")
                     {
                         let no_pos = res
                             .get_field_value(Field::TotalNumPosReports)
-                            .unwrap_or("0".into())
-                            .parse::<usize>()
-                            .unwrap();
+                            .and_then(|v| v.parse::<usize>().ok())
+                            .unwrap_or(0);
                         result.push(res);
                         if no_pos <= result.len() {
-                            return parse_func::parse_positions(result);
+                            let seq = last_seq(&result);
+                            return Ok((parse_func::parse_positions(result)?, seq));
                         } else {
                             continue;
                         }
                     } else {
-                        return parse_func::parse_positions(vec![res]);
+                        let seq = seq_num(&res).unwrap_or(0);
+                        return Ok((parse_func::parse_positions(vec![res])?, seq));
                     }
                 }
                 Err(err) => {
@@ -337,7 +508,15 @@ impl TradeClient {
     pub async fn fetch_all_order_status(
         &self,
         issue_data: Option<NaiveDateTime>,
-    ) -> Result<Vec<ExecutionReport>, Error> {
+    ) -> Result<OrderMassStatus, Error> {
+        Ok(self.fetch_order_mass_status_with_seq(issue_data).await?.0)
+    }
+
+    /// `fetch_all_order_status` with the MsgSeqNum of the last response.
+    async fn fetch_order_mass_status_with_seq(
+        &self,
+        issue_data: Option<NaiveDateTime>,
+    ) -> Result<(OrderMassStatus, u32), Error> {
         self.check_connection()?;
         let mass_status_req_id = self.create_unique_id();
         // FIXME if mass_status_req_id is not 7, then return 'j' but response does not include the mass_status_req_id
@@ -356,18 +535,18 @@ impl TradeClient {
             {
                 Ok(res) => {
                     return match res.get_message_type() {
-                        "j" => Ok(Vec::new()),
+                        "j" => Ok((OrderMassStatus::default(), seq_num(&res).unwrap_or(0))),
                         "8" => {
                             let no_report = res
                                 .get_field_value(Field::TotNumReports)
-                                .unwrap_or("0".into())
-                                .parse::<usize>()
-                                .unwrap();
+                                .and_then(|v| v.parse::<usize>().ok())
+                                .unwrap_or(0);
 
                             result.push(res);
 
                             if no_report <= result.len() {
-                                parse_func::parse_order_mass_status(result)
+                                let seq = last_seq(&result);
+                                Ok((parse_func::parse_order_mass_status(result), seq))
                             } else {
                                 continue;
                             }
@@ -382,6 +561,35 @@ impl TradeClient {
         }
     }
 
+    /// Fetches the status of an order by the ClOrdID.
+    ///
+    /// Returns the execution report with `ExecutionType::OrderStatus`, or `Error::RequestRejected`
+    /// if the server rejects the request, e.g. for an unknown order.
+    pub async fn fetch_order_status(
+        &self,
+        cl_ord_id: String,
+        side: Side,
+    ) -> Result<ExecutionReport, Error> {
+        self.check_connection()?;
+        let req = OrderStatusReq::new(cl_ord_id.clone(), Some(side));
+        self.internal.send_message(req).await?;
+
+        // the other execution reports of the order are not the response.
+        let res = self
+            .fetch_response_by(|res| {
+                (res.matching_field_value("8", Field::ClOrdId, &cl_ord_id)
+                    && res.get_field_str(Field::ExecType) == Some("I"))
+                    || res.matching_field_value("j", Field::BusinessRejectRefID, &cl_ord_id)
+            })
+            .await?;
+        match res.get_message_type() {
+            "j" => Err(Error::RequestRejected(
+                res.get_field_value(Field::Text).unwrap_or("Unknown".into()),
+            )),
+            _ => parse_func::parse_execution_report(res),
+        }
+    }
+
     async fn new_order(&self, req: NewOrderSingleReq) -> Result<ExecutionReport, Error> {
         self.check_connection()?;
         let cl_ord_id = req.cl_ord_id.clone();
@@ -405,6 +613,29 @@ impl TradeClient {
         }
     }
 
+    /// Submits the order, after checking it by `NewOrder::validate`.
+    ///
+    /// Returns `Error::InvalidArgument` without sending the order if it is invalid for the order
+    /// type.
+    pub async fn submit_order(&self, order: NewOrder) -> Result<ExecutionReport, Error> {
+        order.validate()?;
+        let req = NewOrderSingleReq::new(
+            order.cl_ord_id.unwrap_or_else(|| self.create_unique_id()),
+            order.symbol,
+            order.side,
+            None,
+            order.order_qty,
+            order.order_type,
+            order.price,
+            order.stop_px,
+            order.expire_time,
+            order.time_in_force,
+            order.position_id,
+            order.designation,
+        );
+        self.new_order(req).await
+    }
+
     pub async fn new_market_order(
         &self,
         symbol: u32,
@@ -413,22 +644,15 @@ impl TradeClient {
         cl_ord_id: Option<String>,
         custom_ord_label: Option<String>,
     ) -> Result<ExecutionReport, Error> {
-        let req = NewOrderSingleReq::new(
-            cl_ord_id.unwrap_or(self.create_unique_id()),
-            symbol,
-            side,
-            None,
-            order_qty,
-            OrderType::Market,
-            None,
-            None,
-            None,
-            None,
-            custom_ord_label,
-        );
-        self.new_order(req).await
+        self.submit_order(NewOrder {
+            cl_ord_id,
+            designation: custom_ord_label,
+            ..NewOrder::market(symbol, side, order_qty)
+        })
+        .await
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn new_limit_order(
         &self,
         symbol: u32,
@@ -439,23 +663,16 @@ impl TradeClient {
         expire_time: Option<NaiveDateTime>,
         custom_ord_label: Option<String>,
     ) -> Result<ExecutionReport, Error> {
-        let req = NewOrderSingleReq::new(
-            cl_ord_id.unwrap_or(self.create_unique_id()),
-            symbol,
-            side,
-            None,
-            order_qty,
-            OrderType::Limit,
-            Some(price),
-            None,
+        self.submit_order(NewOrder {
+            cl_ord_id,
             expire_time,
-            None,
-            custom_ord_label,
-        );
-
-        self.new_order(req).await
+            designation: custom_ord_label,
+            ..NewOrder::limit(symbol, side, order_qty, price)
+        })
+        .await
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn new_stop_order(
         &self,
         symbol: u32,
@@ -466,22 +683,15 @@ impl TradeClient {
         expire_time: Option<NaiveDateTime>,
         custom_ord_label: Option<String>,
     ) -> Result<ExecutionReport, Error> {
-        let req = NewOrderSingleReq::new(
-            cl_ord_id.unwrap_or(self.create_unique_id()),
-            symbol,
-            side,
-            None,
-            order_qty,
-            OrderType::Stop,
-            None,
-            Some(stop_px),
+        self.submit_order(NewOrder {
+            cl_ord_id,
             expire_time,
-            None,
-            custom_ord_label,
-        );
-
-        self.new_order(req).await
+            designation: custom_ord_label,
+            ..NewOrder::stop(symbol, side, order_qty, stop_px)
+        })
+        .await
     }
+
     pub async fn close_position(
         &self,
         pos_report: PositionReport,
@@ -519,21 +729,12 @@ impl TradeClient {
         side: Side,
         custom_ord_label: Option<String>,
     ) -> Result<ExecutionReport, Error> {
-        let req = NewOrderSingleReq::new(
-            self.create_unique_id(),
-            symbol_id,
-            side,
-            None,
-            lot,
-            OrderType::Market,
-            None,
-            None,
-            None,
-            Some(pos_id),
-            custom_ord_label,
-        );
-
-        self.new_order(req).await
+        self.submit_order(NewOrder {
+            position_id: Some(pos_id),
+            designation: custom_ord_label,
+            ..NewOrder::market(symbol_id, side, lot)
+        })
+        .await
     }
 
     /// Replace order request
@@ -542,7 +743,7 @@ impl TradeClient {
     ///
     /// * `orig_cl_ord_id` - A unique identifier for the order, which is going to be canceled, allocated by the client.
     /// * `order_id` - Unique ID of an order, returned by the server.
-    /// ...
+    /// * ...
     ///
     ///  Either `orig_cl_ord_id` or `order_id` must be passed to this function. If both are `None`, the function will return an error.
     pub async fn replace_order(
@@ -579,10 +780,9 @@ impl TradeClient {
         self.internal.send_message(req).await?;
         match self
             .fetch_response(vec![
-                if org_cl_ord_id.is_some() {
-                    ("8", Field::ClOrdId, org_cl_ord_id.unwrap())
-                } else {
-                    ("8", Field::OrderID, order_id.unwrap())
+                match org_cl_ord_id {
+                    Some(org_cl_ord_id) => ("8", Field::ClOrdId, org_cl_ord_id),
+                    None => ("8", Field::OrderID, order_id.unwrap()),
                 },
                 ("j", Field::BusinessRejectRefID, cl_ord_id.clone()),
             ])
@@ -595,8 +795,7 @@ impl TradeClient {
                         Err(Error::OrderFailed(
                             res.get_field_value(Field::Text)
                                 .unwrap_or("Unknown error".into()),
-                        )
-                        .into())
+                        ))
                     }
                     _ => {
                         // "8" Success
@@ -653,16 +852,14 @@ impl TradeClient {
                         Err(Error::OrderFailed(
                             res.get_field_value(Field::Text)
                                 .unwrap_or("Unknown error".into()),
-                        )
-                        .into())
+                        ))
                     }
                     "9" => {
                         // cancel rejected
                         Err(Error::OrderCancelRejected(
                             res.get_field_value(Field::Text)
                                 .unwrap_or("Unknown error".into()),
-                        )
-                        .into())
+                        ))
                     }
                     _ => {
                         // "8" Success
@@ -1,11 +1,11 @@
 use chrono::NaiveDateTime;
-use serde::Deserialize;
-use std::{collections::HashMap, str::FromStr, sync::Arc};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
-use num_enum::{IntoPrimitive, TryFromPrimitive};
+use num_enum::{FromPrimitive, IntoPrimitive, TryFromPrimitive};
 
-use crate::messages::ResponseMessage;
+use crate::{bar::Bar, messages::ResponseMessage};
 
 pub const DELIMITER: &str = "\u{1}";
 
@@ -14,6 +14,10 @@ pub trait ConnectionHandler {
     async fn on_connect(&self);
     async fn on_logon(&self);
     async fn on_disconnect(&self);
+
+    /// Called when the received message is malformed. The message is dropped.
+    #[allow(unused_variables)]
+    async fn on_invalid_message(&self, message: String, err: ParseError) {}
 }
 
 #[allow(unused_variables)]
@@ -47,6 +51,10 @@ pub trait MarketDataHandler {
     /// Called when a depth subscription request has been rejected by the server.
     /// This function has a default empty implementation and can be overridden by the struct implementing this trait.
     async fn on_rejected_depth_subscription(&self, symbol_id: u32, err_msg: String) {}
+
+    /// Called when a bar of `MarketClient::aggregate_bars` is completed.
+    /// This function has a default empty implementation and can be overridden by the struct implementing this trait.
+    async fn on_bar(&self, bar: Bar) {}
 }
 
 #[derive(Clone)]
@@ -102,7 +110,7 @@ pub struct PositionReport {
     pub guaranteed_sl: Option<bool>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ExecutionType {
     OrderStatus,
     New,
@@ -124,19 +132,27 @@ impl FromStr for ExecutionType {
             "C" => Ok(ExecutionType::Expired),
             "F" => Ok(ExecutionType::Trade),
             "I" => Ok(ExecutionType::OrderStatus),
-            _ => Err(ParseError(s.into())),
+            _ => Err(ParseError::InvalidValue(s.into())),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ExecutionReport {
     /// 150.
     pub exec_type: ExecutionType,
     pub order_report: OrderReport,
 }
 
-#[derive(Debug)]
+/// Execution reports of the order mass status.
+#[derive(Debug, Default)]
+pub struct OrderMassStatus {
+    pub reports: Vec<ExecutionReport>,
+    /// Errors of the execution reports that failed to parse.
+    pub failures: Vec<Error>,
+}
+
+#[derive(Debug, Clone)]
 pub struct OrderReport {
     /// Instrument identificators are provided by Spotware. 55
     pub symbol: u32,
@@ -147,8 +163,14 @@ pub struct OrderReport {
     /// Unique identifier for the order, allocated by the client. 11
     pub cl_ord_id: String,
 
-    /// Position ID. 72
-    pub pos_main_rept_id: String,
+    /// Unique identifier of the execution report. 17
+    pub exec_id: Option<String>,
+
+    /// ID of the position of the order. 721
+    ///
+    /// A position is opened with the ID by the first fill, and the later orders with the ID
+    /// modify or close the same position.
+    pub pos_main_rept_id: Option<String>,
 
     /// Client custom order label. 494
     pub designation: Option<String>,
@@ -205,13 +227,11 @@ pub struct OrderReport {
     pub leaves_qty: f64,
     /// The bought/sold amount of the order which has been filled on this (last) fill. 32
     pub last_qty: Option<f64>,
+    /// The price of this (last) fill. 31
+    pub last_px: Option<f64>,
 
-    // FIXME new type?
     /// 59
-    /// 1 = Good Till Cancel (GTC);
-    /// 3 = Immediate Or Cancel (IOC);
-    /// 6 = Good Till Date (GTD).
-    pub time_in_force: String,
+    pub time_in_force: Option<TimeInForce>,
 
     /// Time the transaction represented by this ExecutionReport occurred message (in UTC). 60
     pub transact_time: NaiveDateTime,
@@ -221,21 +241,50 @@ pub struct OrderReport {
 
     /// Where possible, message to explain execution report. 58
     pub text: Option<String>,
-    // /// 103
-    // pub ord__rej_reason: Option<u32>,
-    // /// MassStatusReqID. 584
-    // pub masss_status_req_id: Option<String>,
+    /// Reason of the rejected order. 103
+    pub ord_rej_reason: Option<OrdRejReason>,
+    /// MassStatusReqID of the OrderMassStatusRequest, for the reports of the order mass status. 584
+    pub mass_status_req_id: Option<String>,
 }
 
+/// OrdRejReason(103). The unknown values are parsed as `Other` with the raw code.
 #[repr(u32)]
-#[derive(Debug, TryFromPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
+pub enum OrdRejReason {
+    BrokerOption = 0,
+    UnknownSymbol = 1,
+    ExchangeClosed = 2,
+    OrderExceedsLimit = 3,
+    TooLateToEnter = 4,
+    UnknownOrder = 5,
+    DuplicateOrder = 6,
+    StaleOrder = 8,
+    IncorrectQuantity = 13,
+    #[num_enum(catch_all)]
+    Other(u32),
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
 pub enum TimeInForce {
     GoodTillCancel = 1,
     ImmediateOrCancel = 3,
     GoodTillDate = 6,
 }
 
-#[derive(Debug)]
+impl FromStr for TimeInForce {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1" => Ok(Self::GoodTillCancel),
+            "3" => Ok(Self::ImmediateOrCancel),
+            "6" => Ok(Self::GoodTillDate),
+            _ => Err(ParseError::InvalidValue(s.into())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OrderStatus {
     New,
     ParitallyFilled,
@@ -245,8 +294,27 @@ pub enum OrderStatus {
     Expired,
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct ParseError(String);
+/// Error while parsing the FIX message or the value of the field.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    #[error("Invalid value : {0}")]
+    InvalidValue(String),
+    #[error("Malformed field : {0}")]
+    MalformedField(String),
+    #[error("Missing field : {0}")]
+    MissingField(Field),
+    #[error("BodyLength mismatch : expected {expected}, actual {actual}")]
+    BodyLengthMismatch { expected: usize, actual: usize },
+    #[error("CheckSum mismatch : expected {expected}, actual {actual}")]
+    CheckSumMismatch { expected: u32, actual: u32 },
+}
+
+impl OrderStatus {
+    /// New or partially filled.
+    pub fn is_open(&self) -> bool {
+        matches!(self, Self::New | Self::ParitallyFilled)
+    }
+}
 
 impl FromStr for OrderStatus {
     type Err = ParseError;
@@ -258,14 +326,14 @@ impl FromStr for OrderStatus {
             "8" => Ok(Self::Rejected),
             "4" => Ok(Self::Cancelled),
             "C" => Ok(Self::Expired),
-            _ => Err(ParseError(s.into())),
+            _ => Err(ParseError::InvalidValue(s.into())),
         }
     }
 }
 
 // == Market type definition
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum MarketType {
     Spot,
     Depth,
@@ -281,12 +349,21 @@ impl std::fmt::Display for MarketType {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PriceType {
     Bid,
     Ask,
 }
 
+impl From<PriceType> for char {
+    fn from(value: PriceType) -> Self {
+        match value {
+            PriceType::Bid => '0',
+            PriceType::Ask => '1',
+        }
+    }
+}
+
 impl FromStr for PriceType {
     type Err = ();
 
@@ -299,20 +376,63 @@ impl FromStr for PriceType {
     }
 }
 
-#[derive(Debug, Clone)]
+/// MDUpdateType(265) of the depth subscription.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntoPrimitive)]
+#[repr(u32)]
+pub enum MDUpdateType {
+    /// Only the full refreshes are sent.
+    FullRefresh = 0,
+    IncrementalRefresh = 1,
+}
+
+/// Options of the MarketDataRequest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscriptionOptions {
+    /// MarketDepth(264). 1 for the spot prices, and 0 for the full book of the depth.
+    pub market_depth: u32,
+    /// Not sent if `None`, and the server decides.
+    pub update_type: Option<MDUpdateType>,
+    pub entry_types: Vec<PriceType>,
+}
+
+impl SubscriptionOptions {
+    /// Bid and ask of the top of the book.
+    pub fn spot() -> Self {
+        Self {
+            market_depth: 1,
+            update_type: None,
+            entry_types: vec![PriceType::Bid, PriceType::Ask],
+        }
+    }
+
+    /// Bid and ask of the full book.
+    pub fn depth() -> Self {
+        Self {
+            market_depth: 0,
+            update_type: None,
+            entry_types: vec![PriceType::Bid, PriceType::Ask],
+        }
+    }
+
+    pub(crate) fn entry_type_chars(&self) -> Vec<char> {
+        self.entry_types.iter().map(|v| char::from(*v)).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SpotPrice {
     pub bid: f64,
     pub ask: f64,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DepthPrice {
     pub price_type: PriceType,
     pub price: f64,
     pub size: f64,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum IncrementalRefresh {
     New {
         symbol_id: u32,
@@ -325,6 +445,13 @@ pub enum IncrementalRefresh {
     },
 }
 
+/// Event of the depth stream.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DepthEvent {
+    FullRefresh(HashMap<String, DepthPrice>),
+    IncrementalRefresh(Vec<IncrementalRefresh>),
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     // connection errors
@@ -332,12 +459,22 @@ pub enum Error {
     NotConnected,
     #[error("logged out")]
     LoggedOut,
+    #[error("MsgSeqNum too low, expecting {expected} but received {received}")]
+    SeqNumTooLow { expected: u32, received: u32 },
 
     #[error("Field not found : {0}")]
     FieldNotFoundError(Field),
+    #[error("Failed to parse {field}({value}) in the message of {msg_type}")]
+    ParseError {
+        field: Field,
+        value: String,
+        msg_type: String,
+    },
 
     #[error("Missing argument error")]
     MissingArgumentError,
+    #[error("Invalid argument : {0}")]
+    InvalidArgument(String),
 
     // #[error("Request failed")]
     // RequestFailed,
@@ -345,6 +482,13 @@ pub enum Error {
     OrderFailed(String), // for "j"
     #[error("Order cancel rejected : {0}")]
     OrderCancelRejected(String),
+    #[error("Illegal transition of order({order_id}) from {from:?} to {to:?} by {exec_type:?}")]
+    IllegalOrderTransition {
+        order_id: String,
+        from: Option<OrderStatus>,
+        to: OrderStatus,
+        exec_type: ExecutionType,
+    },
 
     // subscription errors for market client
     #[error("Failed to {2} subscription {0}: {1}")]
@@ -355,10 +499,18 @@ pub enum Error {
     RequestingSubscription(u32, MarketType),
     #[error("Not susbscribed {1} for symbol({0})")]
     NotSubscribed(u32, MarketType),
+    #[error("Stream lagged behind, {0} events dropped")]
+    Lagged(u64),
 
     #[error("Timeout error")]
     TimeoutError,
 
+    #[error("TLS error : {0}")]
+    TlsError(String),
+
+    #[error("Invalid message : {0}")]
+    InvalidMessage(#[from] ParseError),
+
     // internal errors
     #[error("Request rejected - {0}")]
     RequestRejected(String),
@@ -369,35 +521,140 @@ pub enum Error {
 
     // reponse send error
     #[error(transparent)]
-    SendError(#[from] async_std::channel::SendError<ResponseMessage>),
+    SendError(#[from] async_channel::SendError<ResponseMessage>),
 
     // #[error(transparent)]
-    // TriggerError(#[from] async_std::channel::SendError<String>),
+    // TriggerError(#[from] async_channel::SendError<String>),
     #[error(transparent)]
-    RecvError(#[from] async_std::channel::RecvError),
+    RecvError(#[from] async_channel::RecvError),
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
 }
 
 //
 // only for internal
-pub type MarketCallback = Arc<dyn Fn(InternalMDResult) -> () + Send + Sync>;
-pub type TradeCallback = Arc<dyn Fn(ResponseMessage) -> () + Send + Sync>;
+pub type MarketCallback = Arc<dyn Fn(InternalMDResult) + Send + Sync>;
+pub type TradeCallback = Arc<dyn Fn(ResponseMessage) + Send + Sync>;
 //
 
 pub enum InternalMDResult {
     MD {
+        seq: u32,
+        /// SendingTime(52) of the message.
+        sending_time: Option<NaiveDateTime>,
         msg_type: char,
         symbol_id: u32,
         data: Vec<HashMap<Field, String>>,
     },
     MDReject {
+        seq: u32,
         symbol_id: u32,
         md_req_id: String,
         err_msg: String,
     },
 }
 
+impl InternalMDResult {
+    /// MsgSeqNum of the message.
+    pub fn seq(&self) -> u32 {
+        match self {
+            InternalMDResult::MD { seq, .. } | InternalMDResult::MDReject { seq, .. } => *seq,
+        }
+    }
+
+    pub fn sending_time(&self) -> Option<NaiveDateTime> {
+        match self {
+            InternalMDResult::MD { sending_time, .. } => *sending_time,
+            InternalMDResult::MDReject { .. } => None,
+        }
+    }
+}
+
+/// Transport used to carry the FIX session.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub enum Transport {
+    /// Plain TCP connection (ports 5201/5202).
+    #[default]
+    Plain,
+    /// TLS connection (ports 5211/5212).
+    Tls(TlsConfig),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TlsConfig {
+    /// Name used for SNI and certificate verification. The host of `Config` is used if `None`.
+    #[serde(default)]
+    pub server_name: Option<String>,
+    /// Additional PEM encoded root certificates to trust.
+    #[serde(default)]
+    pub root_certs: Vec<String>,
+    /// Whether to trust the bundled Mozilla root certificates.
+    #[serde(default = "default_webpki_roots")]
+    pub webpki_roots: bool,
+}
+
+fn default_webpki_roots() -> bool {
+    true
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            server_name: None,
+            root_certs: Vec::new(),
+            webpki_roots: default_webpki_roots(),
+        }
+    }
+}
+
+impl TlsConfig {
+    /// Adds a PEM encoded root certificate to trust.
+    pub fn add_root_cert(mut self, pem: impl Into<String>) -> Self {
+        self.root_certs.push(pem.into());
+        self
+    }
+}
+
+/// Reconnection policy with exponential backoff and jitter.
+///
+/// The delay of the n-th attempt is `initial_delay * multiplier^n`, capped by `max_delay`, and
+/// randomized by `+/- jitter` of itself.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    /// Ratio between 0.0 and 1.0.
+    pub jitter: f64,
+    /// Gives up after the number of attempts. Retries forever if `None`.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Returns the delay before the given attempt. (starting from 0)
+    pub fn delay_for(&self, attempt: u32) -> Duration {
+        let base = self.initial_delay.as_secs_f64() * self.multiplier.powi(attempt as i32);
+        let base = base.min(self.max_delay.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = 1.0 + jitter * (fastrand::f64() * 2.0 - 1.0);
+        Duration::from_secs_f64((base * factor).max(0.0))
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     pub host: String,
@@ -405,6 +662,11 @@ pub struct Config {
     pub password: String,
     pub sender_comp_id: String,
     pub heart_beat: u32,
+    #[serde(default)]
+    pub transport: Transport,
+    /// Overrides the default port of the session.
+    #[serde(default)]
+    pub port: Option<u16>,
 }
 
 impl Config {
@@ -421,6 +683,31 @@ impl Config {
             password,
             sender_comp_id,
             heart_beat,
+            transport: Transport::Plain,
+            port: None,
+        }
+    }
+
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.transport = Transport::Tls(tls);
+        self
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// Returns the port to connect for the given session.
+    pub fn port_for(&self, sub_id: SubID) -> u16 {
+        if let Some(port) = self.port {
+            return port;
+        }
+        match (&self.transport, sub_id) {
+            (Transport::Plain, SubID::QUOTE) => 5201,
+            (Transport::Plain, SubID::TRADE) => 5202,
+            (Transport::Tls(_), SubID::QUOTE) => 5211,
+            (Transport::Tls(_), SubID::TRADE) => 5212,
         }
     }
 }
@@ -435,7 +722,9 @@ pub enum Field {
     ClOrdId = 11,
     CumQty = 14,
     EndSeqNo = 16,
-    OrdQty = 32,
+    ExecID = 17,
+    LastPx = 31,
+    LastQty = 32,
     MsgSeqNum = 34,
     MsgType = 35,
     NewSeqNo = 36,
@@ -444,6 +733,7 @@ pub enum Field {
     OrdStatus = 39,
     OrdType = 40,
     OrigClOrdID = 41,
+    PossDupFlag = 43,
     Price = 44,
     RefSeqNum = 45,
     SenderCompID = 49,
@@ -461,6 +751,7 @@ pub enum Field {
     OrdRejReason = 103,
     HeartBtInt = 108,
     TestReqID = 112,
+    OrigSendingTime = 122,
     GapFillFlag = 123,
     ExpireTime = 126,
     ResetSeqNumFlag = 141,
@@ -550,21 +841,17 @@ impl FromStr for SubID {
 }
 
 #[repr(u32)]
-#[derive(Debug, PartialEq, TryFromPrimitive, Clone, Copy)]
+#[derive(Debug, PartialEq, TryFromPrimitive, Clone, Copy, Default)]
 pub enum Side {
+    #[default]
     BUY = 1,
     SELL = 2,
 }
 
-impl Default for Side {
-    fn default() -> Self {
-        Side::BUY
-    }
-}
-
 #[repr(u32)]
-#[derive(Debug, PartialEq, TryFromPrimitive, Clone, Copy)]
+#[derive(Debug, PartialEq, TryFromPrimitive, Clone, Copy, Default)]
 pub enum OrderType {
+    #[default]
     Market = 1,
     Limit = 2,
     Stop = 3,
@@ -579,13 +866,274 @@ impl FromStr for OrderType {
             "2" => Ok(Self::Limit),
             "3" => Ok(Self::Stop),
             "4" => Ok(Self::StopLimit),
-            _ => Err(ParseError(s.into())),
+            _ => Err(ParseError::InvalidValue(s.into())),
         }
     }
 }
 
-impl Default for OrderType {
-    fn default() -> Self {
-        OrderType::Market
+/// Order to submit by `TradeClient::submit_order`.
+///
+/// The fields are checked against the order type by `validate` before the order is sent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewOrder {
+    pub symbol: u32,
+    pub side: Side,
+    pub order_qty: f64,
+    pub order_type: OrderType,
+    /// Limit price of the limit and stop-limit orders, or the worst price of the market range
+    /// order. 44
+    pub price: Option<f64>,
+    /// Trigger price of the stop and stop-limit orders. 99
+    pub stop_px: Option<f64>,
+    /// 59, decided by the server if `None`.
+    pub time_in_force: Option<TimeInForce>,
+    /// Expiry of the pending order. (UTC) 126
+    pub expire_time: Option<NaiveDateTime>,
+    /// Allocated by the client if `None`.
+    pub cl_ord_id: Option<String>,
+    /// Position to change by the order. 721
+    pub position_id: Option<String>,
+    /// Custom label of the order. 494
+    pub designation: Option<String>,
+}
+
+impl NewOrder {
+    fn new(symbol: u32, side: Side, order_qty: f64, order_type: OrderType) -> Self {
+        Self {
+            symbol,
+            side,
+            order_qty,
+            order_type,
+            price: None,
+            stop_px: None,
+            time_in_force: None,
+            expire_time: None,
+            cl_ord_id: None,
+            position_id: None,
+            designation: None,
+        }
+    }
+
+    pub fn market(symbol: u32, side: Side, order_qty: f64) -> Self {
+        Self::new(symbol, side, order_qty, OrderType::Market)
+    }
+
+    /// Market order filled only at `price` or better.
+    pub fn market_range(symbol: u32, side: Side, order_qty: f64, price: f64) -> Self {
+        Self {
+            price: Some(price),
+            ..Self::market(symbol, side, order_qty)
+        }
+    }
+
+    pub fn limit(symbol: u32, side: Side, order_qty: f64, price: f64) -> Self {
+        Self {
+            price: Some(price),
+            ..Self::new(symbol, side, order_qty, OrderType::Limit)
+        }
+    }
+
+    pub fn stop(symbol: u32, side: Side, order_qty: f64, stop_px: f64) -> Self {
+        Self {
+            stop_px: Some(stop_px),
+            ..Self::new(symbol, side, order_qty, OrderType::Stop)
+        }
+    }
+
+    /// Limit order at `price` placed when the price reaches `stop_px`.
+    pub fn stop_limit(symbol: u32, side: Side, order_qty: f64, stop_px: f64, price: f64) -> Self {
+        Self {
+            price: Some(price),
+            stop_px: Some(stop_px),
+            ..Self::new(symbol, side, order_qty, OrderType::StopLimit)
+        }
+    }
+
+    pub fn with_time_in_force(mut self, time_in_force: TimeInForce) -> Self {
+        self.time_in_force = Some(time_in_force);
+        self
+    }
+
+    /// Good till date, expiring at the time.
+    pub fn with_expire_time(mut self, expire_time: NaiveDateTime) -> Self {
+        self.time_in_force = Some(TimeInForce::GoodTillDate);
+        self.expire_time = Some(expire_time);
+        self
+    }
+
+    pub fn with_cl_ord_id(mut self, cl_ord_id: impl Into<String>) -> Self {
+        self.cl_ord_id = Some(cl_ord_id.into());
+        self
+    }
+
+    pub fn with_position_id(mut self, position_id: impl Into<String>) -> Self {
+        self.position_id = Some(position_id.into());
+        self
+    }
+
+    pub fn with_designation(mut self, designation: impl Into<String>) -> Self {
+        self.designation = Some(designation.into());
+        self
+    }
+
+    /// Checks the prices and the time in force required or allowed by the order type.
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |msg: String| Err(Error::InvalidArgument(msg));
+        if !(self.order_qty.is_finite() && self.order_qty > 0.0) {
+            return invalid(format!("order quantity {}", self.order_qty));
+        }
+        for (name, value) in [("price", self.price), ("stop price", self.stop_px)] {
+            if let Some(value) = value.filter(|v| !(v.is_finite() && *v > 0.0)) {
+                return invalid(format!("{} {}", name, value));
+            }
+        }
+
+        let order_type = self.order_type;
+        let (price, stop_px) = match order_type {
+            // the price of the market order is optional for the market range
+            OrderType::Market => (self.price.is_some(), false),
+            OrderType::Limit => (true, false),
+            OrderType::Stop => (false, true),
+            OrderType::StopLimit => (true, true),
+        };
+        if price && self.price.is_none() {
+            return invalid(format!("price is required for {:?} order", order_type));
+        }
+        if !price && self.price.is_some() {
+            return invalid(format!("price is not allowed for {:?} order", order_type));
+        }
+        if stop_px != self.stop_px.is_some() {
+            let required = if stop_px { "required" } else { "not allowed" };
+            return invalid(format!(
+                "stop price is {} for {:?} order",
+                required, order_type
+            ));
+        }
+
+        match (order_type, self.time_in_force, self.expire_time) {
+            (OrderType::Market, _, Some(_)) => {
+                invalid("expire time is not allowed for Market order".into())
+            }
+            (OrderType::Market, None | Some(TimeInForce::ImmediateOrCancel), _) => Ok(()),
+            (OrderType::Market, Some(tif), _) => {
+                invalid(format!("{:?} is not allowed for Market order", tif))
+            }
+            (OrderType::Stop | OrderType::StopLimit, Some(TimeInForce::ImmediateOrCancel), _) => {
+                invalid(format!(
+                    "ImmediateOrCancel is not allowed for {:?} order",
+                    order_type
+                ))
+            }
+            (_, Some(TimeInForce::GoodTillDate), None) => {
+                invalid("expire time is required for GoodTillDate".into())
+            }
+            (_, Some(tif @ TimeInForce::GoodTillCancel), Some(_))
+            | (_, Some(tif @ TimeInForce::ImmediateOrCancel), Some(_)) => {
+                invalid(format!("expire time is not allowed for {:?}", tif))
+            }
+            (_, None, Some(_)) => invalid("expire time requires GoodTillDate".into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, NewOrder, OrderType, ReconnectPolicy, Side, TimeInForce};
+    use chrono::NaiveDate;
+    use std::time::Duration;
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            jitter: 0.0,
+            max_attempts: None,
+        };
+        assert_eq!(policy.delay_for(0), Duration::from_millis(100));
+        assert_eq!(policy.delay_for(1), Duration::from_millis(200));
+        assert_eq!(policy.delay_for(3), Duration::from_millis(800));
+        // capped
+        assert_eq!(policy.delay_for(4), Duration::from_secs(1));
+        assert_eq!(policy.delay_for(30), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_reconnect_jitter() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            jitter: 0.5,
+            ..ReconnectPolicy::default()
+        };
+        for _ in 0..100 {
+            let delay = policy.delay_for(0);
+            assert!(delay >= Duration::from_millis(500));
+            assert!(delay <= Duration::from_millis(1500));
+        }
+    }
+
+    #[test]
+    fn test_validate_new_order() {
+        let expire = NaiveDate::from_ymd_opt(2030, 1, 2)
+            .unwrap()
+            .and_hms_opt(3, 4, 5)
+            .unwrap();
+        let valid = [
+            NewOrder::market(1, Side::BUY, 1000.0),
+            NewOrder::market_range(1, Side::BUY, 1000.0, 1.2)
+                .with_time_in_force(TimeInForce::ImmediateOrCancel),
+            NewOrder::limit(1, Side::SELL, 1000.0, 1.2)
+                .with_time_in_force(TimeInForce::ImmediateOrCancel),
+            NewOrder::stop(1, Side::BUY, 1000.0, 1.3)
+                .with_time_in_force(TimeInForce::GoodTillCancel),
+            NewOrder::stop_limit(1, Side::BUY, 1000.0, 1.3, 1.31).with_expire_time(expire),
+        ];
+        for order in valid.iter() {
+            assert!(order.validate().is_ok(), "{:?}", order);
+        }
+
+        let invalid = [
+            NewOrder::market(1, Side::BUY, 0.0),
+            NewOrder::limit(1, Side::BUY, 1000.0, f64::NAN),
+            NewOrder::market(1, Side::BUY, 1000.0).with_time_in_force(TimeInForce::GoodTillCancel),
+            NewOrder::market(1, Side::BUY, 1000.0).with_expire_time(expire),
+            NewOrder {
+                price: None,
+                ..NewOrder::limit(1, Side::BUY, 1000.0, 1.2)
+            },
+            NewOrder {
+                price: Some(1.2),
+                ..NewOrder::stop(1, Side::BUY, 1000.0, 1.3)
+            },
+            NewOrder {
+                stop_px: None,
+                ..NewOrder::stop_limit(1, Side::BUY, 1000.0, 1.3, 1.31)
+            },
+            NewOrder::stop_limit(1, Side::BUY, 1000.0, 1.3, 1.31)
+                .with_time_in_force(TimeInForce::ImmediateOrCancel),
+            NewOrder::limit(1, Side::BUY, 1000.0, 1.2)
+                .with_time_in_force(TimeInForce::GoodTillDate),
+            NewOrder {
+                time_in_force: Some(TimeInForce::GoodTillCancel),
+                ..NewOrder::limit(1, Side::BUY, 1000.0, 1.2).with_expire_time(expire)
+            },
+            NewOrder {
+                time_in_force: None,
+                ..NewOrder::stop(1, Side::BUY, 1000.0, 1.3).with_expire_time(expire)
+            },
+        ];
+        for order in invalid.iter() {
+            assert!(
+                matches!(order.validate(), Err(Error::InvalidArgument(_))),
+                "{:?}",
+                order
+            );
+        }
+        assert_eq!(
+            NewOrder::stop_limit(1, Side::SELL, 1000.0, 1.1, 1.09).order_type,
+            OrderType::StopLimit
+        );
     }
 }